@@ -0,0 +1,117 @@
+//! End-to-end latency of announcing a swap and receiving its confirmation
+//! over the in-memory transport.
+//!
+//! Run with `cargo bench --bench announce_latency`. Besides criterion's own
+//! report the median and 99th percentile of all iterations are printed.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use libp2p::{
+    core::{transport::MemoryTransport, upgrade},
+    identity,
+    mplex::MplexConfig,
+    multihash::Sha2_256,
+    secio::SecioConfig,
+    swarm::{Swarm, SwarmEvent},
+    PeerId, Transport,
+};
+use libp2p_tests::{
+    announce::{
+        behaviour::{Announce, BehaviourOutEvent, DialInformation},
+        SwapDigest,
+    },
+    swap_id::SwapId,
+};
+use std::time::{Duration, Instant};
+
+fn new_swarm() -> (PeerId, Swarm<Announce>) {
+    let id_keys = identity::Keypair::generate_ed25519();
+    let peer_id = id_keys.public().into_peer_id();
+    let transport = MemoryTransport
+        .upgrade(upgrade::Version::V1)
+        .authenticate(SecioConfig::new(id_keys))
+        .multiplex(MplexConfig::new());
+    let swarm = Swarm::new(transport, Announce::default(), peer_id.clone());
+    (peer_id, swarm)
+}
+
+/// Alice's swarm and how to reach Bob, who confirms every announce in the
+/// background.
+fn setup() -> (Swarm<Announce>, DialInformation) {
+    let (_, alice) = new_swarm();
+    let (bob_peer_id, mut bob) = new_swarm();
+
+    Swarm::listen_on(&mut bob, "/memory/0".parse().unwrap()).unwrap();
+    let bob_addr = async_std::task::block_on(async {
+        loop {
+            if let SwarmEvent::NewListenAddr(addr) = bob.next_event().await {
+                return addr;
+            }
+        }
+    });
+    async_std::task::spawn(async move {
+        loop {
+            if let BehaviourOutEvent::ReceivedAnnouncement { io, .. } = bob.next().await {
+                async_std::task::spawn(io.confirm(SwapId::random()));
+            }
+        }
+    });
+
+    (alice, DialInformation {
+        peer_id: bob_peer_id,
+        address_hint: Some(bob_addr),
+    })
+}
+
+/// Announces a swap not announced before and waits for its confirmation.
+fn announce(alice: &mut Swarm<Announce>, dial_info: &DialInformation, round: u64) -> Duration {
+    let swap_digest = SwapDigest::new(Sha2_256::digest(&round.to_be_bytes()));
+
+    let start = Instant::now();
+    alice.start_announce_protocol(swap_digest, dial_info.clone()).unwrap();
+    async_std::task::block_on(async {
+        loop {
+            if let BehaviourOutEvent::ReceivedConfirmation { .. } = alice.next().await {
+                return;
+            }
+        }
+    });
+
+    start.elapsed()
+}
+
+fn percentile(sorted: &[Duration], percent: usize) -> Duration {
+    sorted[(sorted.len() - 1) * percent / 100]
+}
+
+fn announce_latency(c: &mut Criterion) {
+    // The connection is kept open between announces, so only the first pays
+    // for dialing.
+    let (mut alice, dial_info) = setup();
+    let mut round = 0;
+    announce(&mut alice, &dial_info, round);
+
+    let mut latencies = Vec::new();
+    c.bench_function("announce/confirm over memory", |b| {
+        b.iter_custom(|iterations| {
+            let mut total = Duration::default();
+            for _ in 0..iterations {
+                round += 1;
+                let latency = announce(&mut alice, &dial_info, round);
+                latencies.push(latency);
+                total += latency;
+            }
+            total
+        })
+    });
+
+    latencies.sort();
+    println!(
+        "announce/confirm over memory: median {:?}, p99 {:?} of {} announces",
+        percentile(&latencies, 50),
+        percentile(&latencies, 99),
+        latencies.len()
+    );
+}
+
+criterion_group!(benches, announce_latency);
+criterion_main!(benches);
@@ -0,0 +1,26 @@
+//! Feeds arbitrary bytes to the inbound announce upgrade. Whatever the peer
+//! sends, the upgrade has to fail with a `protocol::Error` rather than panic.
+//!
+//! Run with `cargo +nightly fuzz run inbound_frame`, the corpus is seeded
+//! with a valid V1 frame.
+
+#![no_main]
+
+use futures::{executor::block_on, io::Cursor};
+use libfuzzer_sys::fuzz_target;
+use libp2p::core::upgrade::InboundUpgrade;
+use libp2p_tests::announce::protocol::{InboundConfig, ProtocolVersion};
+
+fuzz_target!(|data: &[u8]| {
+    let versions = [
+        ProtocolVersion::V1,
+        ProtocolVersion::V2,
+        ProtocolVersion::Cbor,
+        ProtocolVersion::Pipelined,
+    ];
+
+    for version in &versions {
+        let socket = Cursor::new(data.to_vec());
+        let _ = block_on(InboundConfig::default().upgrade_inbound(socket, (*version).into()));
+    }
+});
@@ -1,11 +1,21 @@
 pub mod behaviour;
+mod deadlines;
 pub mod handler;
+pub mod metrics;
 pub mod protocol;
 
-use libp2p::multihash::{self, Multihash};
-use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
-use std::fmt;
+use libp2p::multihash::{self, Multihash, Sha2_256};
+use serde::{
+    de::{self, Error},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use std::{cmp::Ordering, convert::TryFrom, fmt, str::FromStr};
 
+/// The digest identifying a swap.
+///
+/// Digests are ordered by the bytes of their multihash, so by the code of
+/// the hash function first and only then by the hash itself. Use
+/// `cmp_by_digest` to order them by the hash alone.
 #[derive(Clone, Debug, PartialOrd, Ord, PartialEq, Eq, Hash)]
 pub struct SwapDigest(Multihash);
 
@@ -13,6 +23,107 @@ impl SwapDigest {
     pub fn new(multihash: Multihash) -> Self {
         Self(multihash)
     }
+
+    /// The SHA2-256 digest of `input`.
+    pub fn sha2_256(input: &[u8]) -> Self {
+        Self(Sha2_256::digest(input))
+    }
+
+    /// The SHA2-256 digest of `value` serialized as compact JSON, with the
+    /// keys of maps sorted. Two parties computing the digest of the same
+    /// swap parameters this way agree on it, whatever order their maps
+    /// iterate in.
+    pub fn of<T: Serialize>(value: &T) -> Result<Self, serde_json::Error> {
+        // Objects in a `serde_json::Value` keep their keys sorted.
+        let canonical = serde_json::to_value(value)?;
+        let bytes = serde_json::to_vec(&canonical)?;
+
+        Ok(Self::sha2_256(&bytes))
+    }
+
+    /// Parses a digest from the hex encoding of a multihash, as produced by
+    /// the `Display` impl.
+    pub fn from_hex(hex: &str) -> Result<Self, ParseError> {
+        let bytes = hex::decode(hex)?;
+
+        Self::from_bytes(&bytes)
+    }
+
+    /// Parses a digest from the bytes of a multihash.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ParseError> {
+        let multihash = multihash::Multihash::from_bytes(bytes.to_vec())?;
+
+        Ok(SwapDigest::new(multihash))
+    }
+
+    /// The multihash the digest wraps, i.e. the hash with its algorithm.
+    pub fn as_multihash(&self) -> &Multihash {
+        &self.0
+    }
+
+    /// The hash function used to compute this digest.
+    pub fn algorithm(&self) -> multihash::Code {
+        self.0.algorithm()
+    }
+
+    /// The hash itself, without the multihash prefix.
+    pub fn digest_bytes(&self) -> &[u8] {
+        self.0.digest()
+    }
+
+    /// Compares the hashes of the digests, ignoring which hash function
+    /// computed them. Digests of different algorithms over the same bytes
+    /// compare equal.
+    pub fn cmp_by_digest(&self, other: &SwapDigest) -> Ordering {
+        self.digest_bytes().cmp(other.digest_bytes())
+    }
+
+    /// The first 8 characters of the `Display` form, enough to tell digests
+    /// apart in logs.
+    pub fn short(&self) -> String {
+        let mut hex = self.to_string();
+        hex.truncate(8);
+        hex
+    }
+}
+
+impl From<Multihash> for SwapDigest {
+    fn from(multihash: Multihash) -> Self {
+        SwapDigest::new(multihash)
+    }
+}
+
+/// Parses the hex encoding, like `from_hex`.
+impl FromStr for SwapDigest {
+    type Err = ParseError;
+
+    fn from_str(hex: &str) -> Result<Self, Self::Err> {
+        SwapDigest::from_hex(hex)
+    }
+}
+
+impl TryFrom<&[u8]> for SwapDigest {
+    type Error = ParseError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        SwapDigest::from_bytes(bytes)
+    }
+}
+
+/// The bytes of the multihash, prefix included.
+impl AsRef<[u8]> for SwapDigest {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_bytes()
+    }
+}
+
+/// Error returned when a `SwapDigest` cannot be parsed.
+#[derive(Debug, thiserror::Error)]
+pub enum ParseError {
+    #[error("swap digest is not valid hex")]
+    Hex(#[from] hex::FromHexError),
+    #[error("swap digest is not a valid multihash")]
+    Multihash(#[from] multihash::DecodeOwnedError),
 }
 
 impl fmt::Display for SwapDigest {
@@ -26,6 +137,12 @@ impl Serialize for SwapDigest {
         where
             S: Serializer,
     {
+        // Binary formats get the multihash as is, text formats the hex
+        // encoding of it.
+        if !serializer.is_human_readable() {
+            return serializer.serialize_bytes(self.0.as_bytes());
+        }
+
         let hex = hex::encode(self.0.as_bytes());
 
         serializer.serialize_str(&hex)
@@ -37,123 +154,2185 @@ impl<'de> Deserialize<'de> for SwapDigest {
         where
             D: Deserializer<'de>,
     {
+        if !deserializer.is_human_readable() {
+            return deserializer.deserialize_bytes(BytesVisitor);
+        }
+
         let hex = String::deserialize(deserializer)?;
-        let bytes = hex::decode(hex).map_err(D::Error::custom)?;
-        let multihash = multihash::Multihash::from_bytes(bytes).map_err(D::Error::custom)?;
 
-        Ok(SwapDigest::new(multihash))
+        SwapDigest::from_hex(&hex).map_err(D::Error::custom)
+    }
+}
+
+struct BytesVisitor;
+
+impl<'de> de::Visitor<'de> for BytesVisitor {
+    type Value = SwapDigest;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the bytes of a multihash")
+    }
+
+    fn visit_bytes<E>(self, bytes: &[u8]) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        SwapDigest::from_bytes(bytes).map_err(E::custom)
+    }
+
+    /// Binary formats that can tell strings from bytes may still hold the
+    /// hex encoding from before digests were serialized as bytes there.
+    fn visit_str<E>(self, hex: &str) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        SwapDigest::from_hex(hex).map_err(E::custom)
     }
 }
 
 #[cfg(test)]
 mod tests {
 
-    use crate::announce::{ SwapDigest, protocol::OutboundConfig, behaviour::Announce, behaviour::BehaviourOutEvent};
-    use async_std;
-    use futures::{pin_mut, prelude::*};
+    use crate::{
+        announce::{
+            behaviour::{
+                into_event_stream, Announce, AnnounceConfig, AnnounceOutcome, BehaviourOutEvent,
+                DialInformation, StartAnnounceError,
+            },
+            protocol::{self, Confirmation, Direction, ProtocolVersion, RejectReason},
+            ParseError, SwapDigest,
+        },
+        swap_id::SwapId,
+        testing,
+    };
+    use futures::{
+        future::{self, Either},
+        prelude::*,
+    };
     use libp2p::{
-        core::{muxing::StreamMuxer, upgrade},
-        identity,
-        mplex::MplexConfig,
-        multihash::{Sha2_256, Multihash},
-        secio::SecioConfig,
+        core::{muxing::StreamMuxer, transport::ListenerEvent},
+        multihash::{self, Code, Sha2_256},
         swarm::{Swarm, SwarmEvent},
-        tcp::TcpConfig,
-        PeerId, Transport,
+        Multiaddr, PeerId, Transport,
     };
-
-    use std::{fmt, io};
-    use crate::announce::behaviour::DialInformation;
-
-    fn transport() -> (
-        PeerId,
-        impl Transport<
-            Output = (
-                PeerId,
-                impl StreamMuxer<
-                    Substream = impl Send,
-                    OutboundSubstream = impl Send,
-                    Error = impl Into<io::Error>,
-                >,
-            ),
-            Listener = impl Send,
-            ListenerUpgrade = impl Send,
-            Dial = impl Send,
-            Error = impl fmt::Debug,
-        > + Clone,
-    ) {
-        let id_keys = identity::Keypair::generate_ed25519();
-        let peer_id = id_keys.public().into_peer_id();
-        let transport = TcpConfig::new()
-            .nodelay(true)
-            .upgrade(upgrade::Version::V1)
-            .authenticate(SecioConfig::new(id_keys))
-            .multiplex(MplexConfig::new());
-        (peer_id, transport)
-    }
+    use serde::{Deserialize, Serialize};
+    use std::{
+        cmp::Ordering,
+        convert::TryFrom,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+    use wasm_timer::Delay;
 
     fn random_swap_digest() -> SwapDigest {
         SwapDigest(Sha2_256::digest(b"hello world"))
     }
 
+    fn new_swarm() -> (Swarm<Announce>, PeerId) {
+        new_swarm_with(AnnounceConfig::default())
+    }
+
+    fn new_swarm_with(config: AnnounceConfig) -> (Swarm<Announce>, PeerId) {
+        let (peer_id, swarm) = testing::build_announce_swarm_with(config);
+        (swarm, peer_id)
+    }
+
+    fn listen<TConfirmation: Confirmation>(swarm: &mut Swarm<Announce<TConfirmation>>) -> Multiaddr {
+        Swarm::listen_on(swarm, "/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
+
+        async_std::task::block_on(async {
+            loop {
+                if let SwarmEvent::NewListenAddr(addr) = swarm.next_event().await {
+                    return addr;
+                }
+            }
+        })
+    }
+
+    /// An address nobody is listening on, dialing it is refused.
+    fn unused_address() -> Multiaddr {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        format!("/ip4/127.0.0.1/tcp/{}", port).parse().unwrap()
+    }
+
+    /// Accepts connections with the transport, but never looks at the
+    /// substreams opened on them, so their negotiation stalls.
+    fn listen_without_negotiating<T, M>(transport: T) -> Multiaddr
+    where
+        T: Transport<Output = (PeerId, M)>,
+        T::Listener: Send + Unpin + 'static,
+        T::ListenerUpgrade: Send,
+        T::Error: Send,
+        M: StreamMuxer + Send + Sync,
+    {
+        let mut listener = transport.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
+        let address = match async_std::task::block_on(listener.next()) {
+            Some(Ok(ListenerEvent::NewAddress(address))) => address,
+            _ => panic!("listener did not report its address"),
+        };
+
+        async_std::task::spawn(async move {
+            while let Some(Ok(event)) = listener.next().await {
+                if let ListenerEvent::Upgrade { upgrade, .. } = event {
+                    if let Ok((_, muxer)) = upgrade.await {
+                        // Sends the confirmation of the muxer's protocol.
+                        let _ = future::poll_fn(|cx| muxer.flush_all(cx)).await;
+                        future::pending::<()>().await;
+                    }
+                }
+            }
+        });
+
+        address
+    }
+
+    /// Polls both swarms until one of them emits a behaviour event, Alice's
+    /// events are returned on the left and Bob's on the right.
+    async fn next_behaviour_event(
+        alice: &mut Swarm<Announce>,
+        bob: &mut Swarm<Announce>,
+    ) -> Either<BehaviourOutEvent, BehaviourOutEvent> {
+        match future::select(alice.next().boxed(), bob.next().boxed()).await {
+            Either::Left((event, _)) => Either::Left(event),
+            Either::Right((event, _)) => Either::Right(event),
+        }
+    }
+
+    /// Runs the swarm in the background, confirming every announce it
+    /// receives.
+    fn spawn_confirming(mut swarm: Swarm<Announce>) {
+        async_std::task::spawn(async move {
+            loop {
+                if let BehaviourOutEvent::ReceivedAnnouncement { io, .. } = swarm.next().await {
+                    async_std::task::spawn(io.confirm(SwapId::random()));
+                }
+            }
+        });
+    }
+
+    /// Upper bound on the number of swarm events we are willing to process
+    /// before declaring the protocol stuck.
+    const MAX_SWARM_EVENTS: usize = 100;
+
+    #[test]
+    fn swap_digest_roundtrips_through_hex() {
+        let digest = random_swap_digest();
+
+        let parsed = SwapDigest::from_hex(&digest.to_string()).unwrap();
+
+        assert_eq!(parsed, digest);
+    }
+
+    #[test]
+    fn same_swap_parameters_have_the_same_digest() {
+        #[derive(Serialize)]
+        struct Params {
+            amount: u64,
+            fees: std::collections::HashMap<String, u64>,
+        }
+        let params = || Params {
+            amount: 100,
+            fees: (0..16).map(|i| (format!("ledger-{}", i), i)).collect(),
+        };
+
+        let first = SwapDigest::of(&params()).unwrap();
+        let second = SwapDigest::of(&params()).unwrap();
+
+        assert_eq!(first, second);
+        assert_eq!(first.algorithm(), Code::Sha2_256);
+        assert_ne!(first, SwapDigest::of(&Params { amount: 101, ..params() }).unwrap());
+    }
+
+    #[test]
+    fn swap_digest_is_parsed_from_str() {
+        let digest = random_swap_digest();
+
+        assert_eq!(digest.to_string().parse::<SwapDigest>().unwrap(), digest);
+
+        let error = "abcd".parse::<SwapDigest>().unwrap_err();
+        assert!(matches!(error, ParseError::Multihash(_)));
+        assert_eq!(error.to_string(), "swap digest is not a valid multihash");
+    }
+
+    #[test]
+    fn swap_digest_roundtrips_through_json_as_hex() {
+        let digest = random_swap_digest();
+
+        let json = serde_json::to_string(&digest).unwrap();
+        assert_eq!(json, format!("\"{}\"", digest));
+
+        assert_eq!(serde_json::from_str::<SwapDigest>(&json).unwrap(), digest);
+    }
+
+    #[test]
+    fn swap_digest_roundtrips_through_bincode_as_bytes() {
+        let digest = random_swap_digest();
+
+        let bytes = bincode::serialize(&digest).unwrap();
+        // A length prefix, then the multihash itself.
+        assert_eq!(bytes.len(), 8 + digest.as_ref().len());
+
+        assert_eq!(bincode::deserialize::<SwapDigest>(&bytes).unwrap(), digest);
+    }
+
+    #[test]
+    fn hex_swap_digest_is_still_read_from_cbor() {
+        let digest = random_swap_digest();
+
+        let legacy = serde_cbor::to_vec(&digest.to_string()).unwrap();
+
+        assert_eq!(serde_cbor::from_slice::<SwapDigest>(&legacy).unwrap(), digest);
+    }
+
+    #[test]
+    fn malformed_swap_digest_is_rejected() {
+        assert!(matches!(SwapDigest::from_hex("not hex"), Err(ParseError::Hex(_))));
+        assert!(matches!(SwapDigest::from_bytes(&[0x12, 0x20, 0x01]), Err(ParseError::Multihash(_))));
+    }
+
+    #[test]
+    fn swap_digest_exposes_its_multihash() {
+        let digest = random_swap_digest();
+
+        assert_eq!(digest.algorithm(), Code::Sha2_256);
+        assert_eq!(digest.digest_bytes().len(), 32);
+        assert!(digest.as_multihash().as_bytes().ends_with(digest.digest_bytes()));
+    }
+
+    #[test]
+    fn short_swap_digest_is_a_prefix_of_the_full_one() {
+        let digest = random_swap_digest();
+
+        assert_eq!(digest.short().len(), 8);
+        assert!(digest.to_string().starts_with(&digest.short()));
+    }
+
+    #[test]
+    fn swap_digests_order_by_algorithm_before_digest() {
+        let sha2 = SwapDigest::new(multihash::wrap(Code::Sha2_256, &[0xff; 32]));
+        let sha3 = SwapDigest::new(multihash::wrap(Code::Sha3_256, &[0xff; 32]));
+        assert_eq!(sha2.cmp(&sha3), Ordering::Less);
+        assert_eq!(sha2.cmp_by_digest(&sha3), Ordering::Equal);
+
+        let lower_sha3 = SwapDigest::new(multihash::wrap(Code::Sha3_256, &[0x00; 32]));
+        assert_eq!(sha2.cmp(&lower_sha3), Ordering::Less);
+        assert_eq!(sha2.cmp_by_digest(&lower_sha3), Ordering::Greater);
+    }
+
+    #[test]
+    fn swap_digest_converts_from_and_to_bytes() {
+        let multihash = Sha2_256::digest(b"hello world");
+        let digest = SwapDigest::from(multihash.clone());
+
+        assert_eq!(SwapDigest::try_from(multihash.as_bytes()).unwrap(), digest);
+        assert_eq!(digest.as_ref(), multihash.as_bytes());
+    }
+
+    #[test]
+    fn truncated_bytes_are_not_a_swap_digest() {
+        let multihash = Sha2_256::digest(b"hello world");
+        let truncated = &multihash.as_bytes()[..16];
+
+        assert!(matches!(SwapDigest::try_from(truncated), Err(ParseError::Multihash(_))));
+    }
+
     #[test]
     fn send_announce_receive_confirmation() {
-        let (mut alice_swarm, alice_peer_id) = {
-            let (peer_id, transport) = transport();
-            let protocol = Announce::default();
-            let swarm = Swarm::new(transport, protocol, peer_id.clone());
-            (swarm, peer_id)
+        let (mut alice_swarm, _alice_peer_id) = new_swarm();
+        let (mut bob_swarm, bob_peer_id) = new_swarm();
+
+        let bob_addr = listen(&mut bob_swarm);
+
+        let send_swap_digest = random_swap_digest();
+        let send_swap_id = SwapId::default();
+
+        let dial_info = DialInformation {
+            peer_id: bob_peer_id.clone(),
+            address_hint: Some(bob_addr),
+        };
+
+        alice_swarm.start_announce_protocol(send_swap_digest.clone(), dial_info).unwrap();
+
+        async_std::task::block_on(async move {
+            let mut bob_received_announcement = false;
+
+            for _ in 0..MAX_SWARM_EVENTS {
+                match future::select(alice_swarm.next_event().boxed(), bob_swarm.next_event().boxed()).await {
+                    Either::Left((
+                        SwarmEvent::Behaviour(BehaviourOutEvent::ReceivedConfirmation {
+                            peer,
+                            digest,
+                            swap_id,
+                            ..
+                        }),
+                        _,
+                    )) => {
+                        assert!(bob_received_announcement);
+                        assert_eq!(peer, bob_peer_id);
+                        assert_eq!(swap_id, send_swap_id);
+                        assert_eq!(digest, send_swap_digest);
+                        return;
+                    }
+                    Either::Right((
+                        SwarmEvent::Behaviour(BehaviourOutEvent::ReceivedAnnouncement { io, .. }),
+                        _,
+                    )) => {
+                        assert_eq!(io.swap_digest, send_swap_digest);
+                        bob_received_announcement = true;
+                        async_std::task::spawn(io.confirm(send_swap_id));
+                    }
+                    _ => {}
+                }
+            }
+
+            panic!("announce protocol did not complete within {} swarm events", MAX_SWARM_EVENTS);
+        })
+    }
+
+    #[test]
+    fn bob_is_told_the_confirmation_was_sent() {
+        let (mut alice_swarm, _) = new_swarm();
+        let (mut bob_swarm, bob_peer_id) = new_swarm();
+
+        let swap_digest = random_swap_digest();
+        let dial_info = DialInformation {
+            peer_id: bob_peer_id,
+            address_hint: Some(listen(&mut bob_swarm)),
         };
 
-        let (mut bob_swarm, bob_peer_id) = {
-            let (peer_id, transport) = transport();
-            let protocol = Announce::default();
-            let swarm = Swarm::new(transport, protocol, peer_id.clone());
-            (swarm, peer_id)
+        alice_swarm.start_announce_protocol(swap_digest.clone(), dial_info).unwrap();
+
+        async_std::task::block_on(async move {
+            let sent_swap_id = SwapId::random();
+
+            loop {
+                match next_behaviour_event(&mut alice_swarm, &mut bob_swarm).await {
+                    Either::Right(BehaviourOutEvent::ReceivedAnnouncement { io, .. }) => {
+                        async_std::task::spawn(io.confirm(sent_swap_id));
+                    }
+                    Either::Right(BehaviourOutEvent::ConfirmationSent { digest, swap_id, .. }) => {
+                        assert_eq!(digest, swap_digest);
+                        assert_eq!(swap_id, sent_swap_id);
+                        return;
+                    }
+                    Either::Right(event) => panic!("unexpected event {:?}", event),
+                    Either::Left(_) => {}
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn rejected_announce_is_reported_before_the_timeout() {
+        let (mut alice_swarm, _) = new_swarm();
+        let (mut bob_swarm, bob_peer_id) = new_swarm();
+
+        let swap_digest = random_swap_digest();
+        let dial_info = DialInformation {
+            peer_id: bob_peer_id,
+            address_hint: Some(listen(&mut bob_swarm)),
         };
 
-        Swarm::listen_on(&mut bob_swarm, "/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
+        let started = std::time::Instant::now();
+        alice_swarm.start_announce_protocol(swap_digest.clone(), dial_info).unwrap();
+
+        async_std::task::block_on(async move {
+            loop {
+                match next_behaviour_event(&mut alice_swarm, &mut bob_swarm).await {
+                    Either::Right(BehaviourOutEvent::ReceivedAnnouncement { io, .. }) => {
+                        async_std::task::spawn(io.reject(RejectReason::UnknownDigest));
+                    }
+                    Either::Left(BehaviourOutEvent::AnnounceRejected { digest, reason, .. }) => {
+                        assert_eq!(digest, swap_digest);
+                        assert_eq!(reason, RejectReason::UnknownDigest);
+                        assert!(started.elapsed() < protocol::DEFAULT_TIMEOUT);
+                        return;
+                    }
+                    Either::Left(BehaviourOutEvent::AnnounceFailed { .. }) => panic!("announce failed instead of being rejected"),
+                    _ => {}
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn announce_failing_the_digest_filter_is_rejected() {
+        let (mut alice_swarm, _) = new_swarm();
+        let (mut bob_swarm, bob_peer_id) = new_swarm();
+
+        let unknown = SwapDigest::new(Sha2_256::digest(b"unknown"));
+        let known = random_swap_digest();
+        {
+            let unknown = unknown.clone();
+            bob_swarm.set_digest_filter(move |digest| *digest != unknown);
+        }
+
+        let address = listen(&mut bob_swarm);
+        for digest in [unknown.clone(), known.clone()] {
+            let dial_info = DialInformation {
+                peer_id: bob_peer_id.clone(),
+                address_hint: Some(address.clone()),
+            };
+            alice_swarm.start_announce_protocol(digest, dial_info).unwrap();
+        }
+
+        async_std::task::block_on(async move {
+            let mut rejected = false;
+            let mut confirmed = false;
+            while !(rejected && confirmed) {
+                match next_behaviour_event(&mut alice_swarm, &mut bob_swarm).await {
+                    Either::Right(BehaviourOutEvent::ReceivedAnnouncement { io, .. }) => {
+                        assert_eq!(io.swap_digest, known);
+                        async_std::task::spawn(io.confirm(SwapId::random()));
+                    }
+                    Either::Left(BehaviourOutEvent::AnnounceRejected { digest, reason, .. }) => {
+                        assert_eq!(digest, unknown);
+                        assert_eq!(reason, RejectReason::UnknownDigest);
+                        rejected = true;
+                    }
+                    Either::Left(BehaviourOutEvent::ReceivedConfirmation { digest, .. }) => {
+                        assert_eq!(digest, known);
+                        confirmed = true;
+                    }
+                    Either::Left(BehaviourOutEvent::AnnounceFailed { .. }) => panic!("announce failed instead of being rejected"),
+                    _ => {}
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn announce_dropped_unanswered_fails_promptly() {
+        let (mut alice_swarm, _) = new_swarm();
+        let (mut bob_swarm, bob_peer_id) = new_swarm();
+
+        let address = listen(&mut bob_swarm);
+        let dial_info = DialInformation {
+            peer_id: bob_peer_id,
+            address_hint: Some(address),
+        };
+        alice_swarm.start_announce_protocol(random_swap_digest(), dial_info).unwrap();
 
-        let bob_addr: libp2p::core::Multiaddr = async_std::task::block_on(async {
+        let started = std::time::Instant::now();
+        async_std::task::block_on(async move {
             loop {
-                let bob_swarm_fut = bob_swarm.next_event();
-                pin_mut!(bob_swarm_fut);
-                match bob_swarm_fut.await {
-                    SwarmEvent::NewListenAddr(addr) => return addr,
+                match next_behaviour_event(&mut alice_swarm, &mut bob_swarm).await {
+                    Either::Right(BehaviourOutEvent::ReceivedAnnouncement { io, .. }) => io.drop_unanswered(),
+                    Either::Left(BehaviourOutEvent::AnnounceFailed { .. }) => break,
+                    Either::Left(BehaviourOutEvent::ReceivedConfirmation { .. }) => panic!("nothing was confirmed"),
                     _ => {}
                 }
             }
         });
 
-        //alice_swarm.add_peer(bob_peer_id.clone(), bob_addr.clone());
-        Swarm::dial_addr(&mut alice_swarm, bob_addr.clone());
+        // Well before the 10 seconds the announce would otherwise time out after.
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn failed_inbound_announce_leaves_the_connection_usable() {
+        let (mut alice_swarm, _) = new_swarm_with(AnnounceConfig::default().max_retries(0));
+        let (mut bob_swarm, bob_peer_id) = new_swarm();
 
-        let send_swap_digest = random_swap_digest();
-        let outbound_config = OutboundConfig::new(send_swap_digest.clone());
-        
         let dial_info = DialInformation {
             peer_id: bob_peer_id.clone(),
-            address_hint: Some(bob_addr.clone()),
+            address_hint: Some(listen(&mut bob_swarm)),
+        };
+        // Bob only knows SHA2-256 digests, the inbound upgrade fails.
+        let sha3 = SwapDigest::new(multihash::wrap(Code::Sha3_256, &[0xff; 32]));
+        alice_swarm.start_announce_protocol(sha3, dial_info.clone()).unwrap();
+
+        async_std::task::block_on(async move {
+            let mut connected = 0;
+            let mut announced = false;
+            loop {
+                match next_behaviour_event(&mut alice_swarm, &mut bob_swarm).await {
+                    Either::Left(BehaviourOutEvent::PeerConnected { .. }) => connected += 1,
+                    Either::Right(BehaviourOutEvent::InboundFailed { .. }) if !announced => {
+                        announced = true;
+                        alice_swarm
+                            .start_announce_protocol(random_swap_digest(), dial_info.clone())
+                            .unwrap();
+                    }
+                    Either::Right(BehaviourOutEvent::ReceivedAnnouncement { io, .. }) => {
+                        async_std::task::spawn(io.confirm(SwapId::random()));
+                    }
+                    Either::Left(BehaviourOutEvent::ReceivedConfirmation { digest, .. }) => {
+                        assert_eq!(digest, random_swap_digest());
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+
+            assert_eq!(connected, 1);
+            assert!(alice_swarm.is_connected(&bob_peer_id));
+        })
+    }
+
+    #[test]
+    fn bob_receives_announcement_within_bounded_polls() {
+        let (mut alice_swarm, alice_peer_id) = new_swarm();
+        let (mut bob_swarm, bob_peer_id) = new_swarm();
+
+        let bob_addr = listen(&mut bob_swarm);
+
+        let send_swap_digest = random_swap_digest();
+        let dial_info = DialInformation {
+            peer_id: bob_peer_id,
+            address_hint: Some(bob_addr),
+        };
+
+        alice_swarm.start_announce_protocol(send_swap_digest.clone(), dial_info).unwrap();
+
+        async_std::task::block_on(async move {
+            for _ in 0..MAX_SWARM_EVENTS {
+                if let Either::Right((
+                    SwarmEvent::Behaviour(BehaviourOutEvent::ReceivedAnnouncement { peer, io, .. }),
+                    _,
+                )) = future::select(alice_swarm.next_event().boxed(), bob_swarm.next_event().boxed()).await
+                {
+                    assert_eq!(peer, alice_peer_id);
+                    assert_eq!(io.swap_digest, send_swap_digest);
+                    return;
+                }
+            }
+
+            panic!("bob did not receive the announcement within {} swarm events", MAX_SWARM_EVENTS);
+        })
+    }
+
+    #[test]
+    fn announce_confirm_cycle_is_counted() {
+        let (mut alice_swarm, _) = new_swarm();
+        let (mut bob_swarm, bob_peer_id) = new_swarm();
+
+        let bob_addr = listen(&mut bob_swarm);
+
+        let dial_info = DialInformation {
+            peer_id: bob_peer_id,
+            address_hint: Some(bob_addr),
         };
 
-        alice_swarm.start_announce_protocol(send_swap_digest.clone(), dial_info);
+        alice_swarm.start_announce_protocol(random_swap_digest(), dial_info).unwrap();
 
         async_std::task::block_on(async move {
             loop {
-                let bob_swarm_fut = bob_swarm.next_event();
-                pin_mut!(bob_swarm_fut);
-                match bob_swarm_fut.await {
-                    SwarmEvent::Behaviour(behavior_event) => {
-                        // never enters this block causing the test to hang
-                        if let BehaviourOutEvent::ReceivedAnnouncement { peer, io } = behavior_event {
-                            assert_eq!(io.swap_digest, send_swap_digest);
-                            // assert_eq!(peer, peer)
-                            return;
-                        }
+                match next_behaviour_event(&mut alice_swarm, &mut bob_swarm).await {
+                    Either::Left(BehaviourOutEvent::ReceivedConfirmation { .. }) => break,
+                    Either::Right(BehaviourOutEvent::ReceivedAnnouncement { io, .. }) => {
+                        async_std::task::spawn(io.confirm(SwapId::default()));
                     }
                     _ => {}
                 }
             }
+
+            let alice_metrics = alice_swarm.metrics();
+            assert_eq!(alice_metrics.announces_sent(), 1);
+            assert_eq!(alice_metrics.confirmations_received(), 1);
+            assert_eq!(alice_metrics.timeouts(), 0);
+            assert_eq!(bob_swarm.metrics().announcements_received(), 1);
         })
     }
+
+    #[test]
+    fn repeated_announce_is_confirmed_once() {
+        let (mut alice_swarm, _) = new_swarm();
+        let (mut bob_swarm, bob_peer_id) = new_swarm();
+
+        let bob_addr = listen(&mut bob_swarm);
+
+        for _ in 0..2 {
+            let dial_info = DialInformation {
+                peer_id: bob_peer_id.clone(),
+                address_hint: Some(bob_addr.clone()),
+            };
+            alice_swarm.start_announce_protocol(random_swap_digest(), dial_info).unwrap();
+        }
+
+        async_std::task::block_on(async move {
+            let mut announcements = 0;
+            let mut confirmed = false;
+            let mut confirmation_sent = false;
+
+            while !(confirmed && confirmation_sent) {
+                match next_behaviour_event(&mut alice_swarm, &mut bob_swarm).await {
+                    Either::Left(BehaviourOutEvent::ReceivedConfirmation { .. }) => {
+                        assert!(!confirmed, "confirmed twice");
+                        confirmed = true;
+                    }
+                    Either::Right(BehaviourOutEvent::ReceivedAnnouncement { io, .. }) => {
+                        announcements += 1;
+                        async_std::task::spawn(io.confirm(SwapId::default()));
+                    }
+                    Either::Right(BehaviourOutEvent::ConfirmationSent { .. }) => confirmation_sent = true,
+                    _ => {}
+                }
+            }
+
+            assert_eq!(announcements, 1);
+            assert_eq!(alice_swarm.metrics().announces_sent(), 1);
+            assert_eq!(alice_swarm.pending_announcements().count(), 0);
+        })
+    }
+
+    #[test]
+    fn announce_to_many_is_confirmed_by_each_peer() {
+        let (mut alice_swarm, _) = new_swarm();
+        let (mut bob_swarm, bob_peer_id) = new_swarm();
+        let (mut carol_swarm, carol_peer_id) = new_swarm();
+
+        let peers = vec![
+            DialInformation {
+                peer_id: bob_peer_id.clone(),
+                address_hint: Some(listen(&mut bob_swarm)),
+            },
+            DialInformation {
+                peer_id: carol_peer_id.clone(),
+                address_hint: Some(listen(&mut carol_swarm)),
+            },
+        ];
+        spawn_confirming(bob_swarm);
+        spawn_confirming(carol_swarm);
+
+        let mut handle = alice_swarm.announce_to_many(random_swap_digest(), peers).unwrap();
+
+        async_std::task::block_on(async move {
+            let mut confirmed_by = Vec::new();
+            while confirmed_by.len() < 2 {
+                if let BehaviourOutEvent::ReceivedConfirmation { peer, .. } = alice_swarm.next().await {
+                    confirmed_by.push(peer);
+                }
+            }
+
+            let mut outcomes = Vec::new();
+            while let Some((peer, outcome)) = handle.next().await {
+                assert!(matches!(outcome, AnnounceOutcome::Confirmed(_)));
+                outcomes.push(peer);
+            }
+
+            for peers in [&mut confirmed_by, &mut outcomes] {
+                peers.sort_by_key(|peer| peer.to_base58());
+                let mut expected = vec![bob_peer_id.clone(), carol_peer_id.clone()];
+                expected.sort_by_key(|peer| peer.to_base58());
+                assert_eq!(*peers, expected);
+            }
+        })
+    }
+
+    #[test]
+    fn announce_handle_resolves_to_the_confirmation() {
+        let (mut alice_swarm, _) = new_swarm();
+        let (mut bob_swarm, bob_peer_id) = new_swarm();
+
+        let swap_digest = random_swap_digest();
+        let dial_info = DialInformation {
+            peer_id: bob_peer_id,
+            address_hint: Some(listen(&mut bob_swarm)),
+        };
+        spawn_confirming(bob_swarm);
+
+        let handle = alice_swarm.start_announce_protocol(swap_digest.clone(), dial_info).unwrap();
+
+        async_std::task::block_on(async move {
+            // Alice's swarm has to be polled for the handle to make progress.
+            let swarm = async {
+                loop {
+                    alice_swarm.next().await;
+                }
+            };
+
+            let confirmed = match future::select(handle, swarm.boxed()).await {
+                Either::Left((confirmed, _)) => confirmed.unwrap(),
+                Either::Right(_) => unreachable!("the swarm never stops"),
+            };
+
+            assert_eq!(confirmed.swap_digest(), &swap_digest);
+        })
+    }
+
+    #[test]
+    fn cancelled_announce_is_not_confirmed() {
+        let (mut alice_swarm, _) = new_swarm();
+        let (mut bob_swarm, bob_peer_id) = new_swarm();
+
+        let bob_addr = listen(&mut bob_swarm);
+
+        let swap_digest = random_swap_digest();
+        let later_digest = SwapDigest::new(Sha2_256::digest(b"announced later"));
+        let dial_info = DialInformation {
+            peer_id: bob_peer_id,
+            address_hint: Some(bob_addr),
+        };
+
+        alice_swarm.start_announce_protocol(swap_digest.clone(), dial_info.clone()).unwrap();
+
+        async_std::task::block_on(async move {
+            let mut cancelled = false;
+
+            loop {
+                match next_behaviour_event(&mut alice_swarm, &mut bob_swarm).await {
+                    Either::Left(BehaviourOutEvent::ReceivedConfirmation { digest, .. }) => {
+                        assert_eq!(digest, later_digest, "received a confirmation for a cancelled announce");
+                        break;
+                    }
+                    Either::Right(BehaviourOutEvent::ReceivedAnnouncement { io, .. }) => {
+                        if io.swap_digest == swap_digest {
+                            assert!(alice_swarm.cancel_announce(&swap_digest));
+                            assert!(!alice_swarm.cancel_announce(&swap_digest));
+                            cancelled = true;
+                        }
+                        async_std::task::spawn(io.confirm(SwapId::default()));
+                    }
+                    // The confirmation of the cancelled announce reaches
+                    // Alice before that of one she only starts now.
+                    Either::Right(BehaviourOutEvent::ConfirmationSent { digest, .. }) if digest == swap_digest => {
+                        alice_swarm
+                            .start_announce_protocol(later_digest.clone(), dial_info.clone())
+                            .unwrap();
+                    }
+                    _ => {}
+                }
+            }
+
+            assert!(cancelled);
+            assert_eq!(alice_swarm.pending_announcements().count(), 0);
+        })
+    }
+
+    #[test]
+    fn unconfirmed_announce_fails_after_configured_timeout() {
+        let (mut alice_swarm, _) = new_swarm_with(AnnounceConfig::default().timeout(Duration::from_millis(100)));
+        let (mut bob_swarm, bob_peer_id) = new_swarm();
+
+        let bob_addr = listen(&mut bob_swarm);
+
+        let swap_digest = random_swap_digest();
+        let dial_info = DialInformation {
+            peer_id: bob_peer_id.clone(),
+            address_hint: Some(bob_addr),
+        };
+
+        alice_swarm.start_announce_protocol(swap_digest.clone(), dial_info).unwrap();
+
+        async_std::task::block_on(async move {
+            // Bob holds on to the reply substream without ever confirming.
+            let mut _reply = None;
+
+            loop {
+                match next_behaviour_event(&mut alice_swarm, &mut bob_swarm).await {
+                    Either::Left(BehaviourOutEvent::AnnounceFailed { peer, digest, .. }) => {
+                        assert_eq!(peer, bob_peer_id);
+                        assert_eq!(digest, swap_digest);
+                        return;
+                    }
+                    Either::Right(BehaviourOutEvent::ReceivedAnnouncement { io, .. }) => _reply = Some(io),
+                    Either::Left(BehaviourOutEvent::PeerConnected { .. }) => {}
+                    Either::Left(BehaviourOutEvent::AnnounceInFlight { .. }) => {}
+                    Either::Left(BehaviourOutEvent::AnnounceQueued { .. }) => {}
+                    Either::Left(event) => panic!("unexpected event {:?}", event),
+                    _ => {}
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn unreachable_peer_is_reported_as_dial_failure() {
+        let (mut alice_swarm, _) = new_swarm();
+        let (_, bob_peer_id) = new_swarm();
+
+        let dial_info = DialInformation {
+            peer_id: bob_peer_id.clone(),
+            address_hint: Some(unused_address()),
+        };
+
+        alice_swarm.start_announce_protocol(random_swap_digest(), dial_info).unwrap();
+
+        async_std::task::block_on(async move {
+            loop {
+                match alice_swarm.next().await {
+                    BehaviourOutEvent::AnnounceQueued { .. } => {}
+                    BehaviourOutEvent::DialFailed { peer, .. } => {
+                        assert_eq!(peer, bob_peer_id);
+                        return;
+                    }
+                    event => panic!("unexpected event {:?}", event),
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn announce_is_queued_before_it_is_confirmed() {
+        let (mut alice_swarm, _) = new_swarm();
+        let (mut bob_swarm, bob_peer_id) = new_swarm();
+        let dial_info = DialInformation {
+            peer_id: bob_peer_id.clone(),
+            address_hint: Some(listen(&mut bob_swarm)),
+        };
+        spawn_confirming(bob_swarm);
+
+        let swap_digest = random_swap_digest();
+        alice_swarm.start_announce_protocol(swap_digest.clone(), dial_info).unwrap();
+
+        async_std::task::block_on(async move {
+            match alice_swarm.next().await {
+                BehaviourOutEvent::AnnounceQueued { peer, digest } => {
+                    assert_eq!(peer, bob_peer_id);
+                    assert_eq!(digest, swap_digest);
+                }
+                event => panic!("unexpected event {:?}", event),
+            }
+
+            loop {
+                match alice_swarm.next().await {
+                    BehaviourOutEvent::ReceivedConfirmation { digest, .. } => {
+                        assert_eq!(digest, swap_digest);
+                        return;
+                    }
+                    BehaviourOutEvent::PeerConnected { .. } => {}
+                    BehaviourOutEvent::AnnounceInFlight { .. } => {}
+                    event => panic!("unexpected event {:?}", event),
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn announces_beyond_the_outbound_cap_wait_for_earlier_ones() {
+        let (mut alice_swarm, _) = new_swarm_with(AnnounceConfig::default().max_concurrent_outbound(2));
+        let (mut bob_swarm, bob_peer_id) = new_swarm();
+        let address = listen(&mut bob_swarm);
+
+        // Bob takes his time to confirm, so announces pile up unless capped.
+        async_std::task::spawn(async move {
+            loop {
+                if let BehaviourOutEvent::ReceivedAnnouncement { io, .. } = bob_swarm.next().await {
+                    async_std::task::spawn(async move {
+                        Delay::new(Duration::from_millis(100)).await.unwrap();
+                        io.confirm(SwapId::random()).await
+                    });
+                }
+            }
+        });
+
+        for i in 0..5u8 {
+            let dial_info = DialInformation {
+                peer_id: bob_peer_id.clone(),
+                address_hint: Some(address.clone()),
+            };
+            alice_swarm
+                .start_announce_protocol(SwapDigest::new(Sha2_256::digest(&[i])), dial_info)
+                .unwrap();
+        }
+
+        async_std::task::block_on(async move {
+            let mut in_flight = 0;
+            let mut most_in_flight = 0;
+            let mut confirmed = 0;
+            while confirmed < 5 {
+                match alice_swarm.next().await {
+                    BehaviourOutEvent::AnnounceInFlight { .. } => {
+                        in_flight += 1;
+                        most_in_flight = most_in_flight.max(in_flight);
+                    }
+                    BehaviourOutEvent::ReceivedConfirmation { .. } => {
+                        in_flight -= 1;
+                        confirmed += 1;
+                    }
+                    BehaviourOutEvent::AnnounceQueued { .. } => {}
+                    BehaviourOutEvent::PeerConnected { .. } => {}
+                    event => panic!("unexpected event {:?}", event),
+                }
+            }
+
+            assert_eq!(most_in_flight, 2);
+        })
+    }
+
+    #[test]
+    fn announce_is_in_flight_once_its_substream_is_negotiated() {
+        let (mut alice_swarm, _) = new_swarm();
+        let (mut bob_swarm, bob_peer_id) = new_swarm();
+        let dial_info = DialInformation {
+            peer_id: bob_peer_id.clone(),
+            address_hint: Some(listen(&mut bob_swarm)),
+        };
+        spawn_confirming(bob_swarm);
+
+        let swap_digest = random_swap_digest();
+        alice_swarm.start_announce_protocol(swap_digest.clone(), dial_info).unwrap();
+
+        async_std::task::block_on(async move {
+            let mut queued = false;
+            let mut in_flight = false;
+            loop {
+                match alice_swarm.next().await {
+                    BehaviourOutEvent::AnnounceQueued { digest, .. } => {
+                        assert_eq!(digest, swap_digest);
+                        assert!(!in_flight);
+                        queued = true;
+                    }
+                    BehaviourOutEvent::AnnounceInFlight { peer, digest } => {
+                        assert_eq!(peer, bob_peer_id);
+                        assert_eq!(digest, swap_digest);
+                        assert!(queued);
+                        in_flight = true;
+                    }
+                    BehaviourOutEvent::ReceivedConfirmation { digest, .. } => {
+                        assert_eq!(digest, swap_digest);
+                        assert!(in_flight);
+                        return;
+                    }
+                    BehaviourOutEvent::PeerConnected { .. } => {}
+                    event => panic!("unexpected event {:?}", event),
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn peer_rejecting_the_protocol_is_reported_as_announce_failure() {
+        let (mut alice_swarm, _) = new_swarm();
+        let (mut bob_swarm, bob_peer_id) = new_swarm_with(AnnounceConfig::default().versions(vec![ProtocolVersion::Cbor]));
+
+        let dial_info = DialInformation {
+            peer_id: bob_peer_id.clone(),
+            address_hint: Some(listen(&mut bob_swarm)),
+        };
+
+        alice_swarm.start_announce_protocol(random_swap_digest(), dial_info).unwrap();
+
+        async_std::task::block_on(async move {
+            loop {
+                match next_behaviour_event(&mut alice_swarm, &mut bob_swarm).await {
+                    Either::Left(BehaviourOutEvent::AnnounceFailed { peer, .. }) => {
+                        assert_eq!(peer, bob_peer_id);
+                        return;
+                    }
+                    Either::Left(BehaviourOutEvent::PeerConnected { .. }) => {}
+                    Either::Left(BehaviourOutEvent::AnnounceInFlight { .. }) => {}
+                    Either::Left(BehaviourOutEvent::AnnounceQueued { .. }) => {}
+                    Either::Left(event) => panic!("unexpected event {:?}", event),
+                    Either::Right(_) => {}
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn stalled_protocol_negotiation_fails_the_announce() {
+        let (mut alice_swarm, _) = new_swarm_with(
+            AnnounceConfig::default()
+                .negotiation_timeout(Duration::from_millis(200))
+                .timeout(Duration::from_secs(3)),
+        );
+
+        let (bob_peer_id, bob_transport) = testing::transport();
+        let address = listen_without_negotiating(bob_transport);
+
+        let swap_digest = random_swap_digest();
+        let dial_info = DialInformation {
+            peer_id: bob_peer_id.clone(),
+            address_hint: Some(address),
+        };
+        let started = std::time::Instant::now();
+        alice_swarm.start_announce_protocol(swap_digest.clone(), dial_info).unwrap();
+
+        async_std::task::block_on(async move {
+            loop {
+                match alice_swarm.next().await {
+                    BehaviourOutEvent::AnnounceFailed { peer, digest, .. } => {
+                        assert_eq!(peer, bob_peer_id);
+                        assert_eq!(digest, swap_digest);
+                        // Before even the confirmation would have timed
+                        // out.
+                        assert!(started.elapsed() < Duration::from_secs(3));
+                        return;
+                    }
+                    BehaviourOutEvent::AnnounceQueued { .. } => {}
+                    BehaviourOutEvent::PeerConnected { .. } => {}
+                    event => panic!("unexpected event {:?}", event),
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn unanswered_announcement_times_out() {
+        let (mut alice_swarm, _) = new_swarm();
+        let (mut bob_swarm, bob_peer_id) =
+            new_swarm_with(AnnounceConfig::default().reply_timeout(Duration::from_millis(100)));
+
+        let swap_digest = random_swap_digest();
+        let dial_info = DialInformation {
+            peer_id: bob_peer_id,
+            address_hint: Some(listen(&mut bob_swarm)),
+        };
+
+        alice_swarm.start_announce_protocol(swap_digest.clone(), dial_info).unwrap();
+
+        async_std::task::block_on(async move {
+            // Bob holds on to the reply substream without ever confirming.
+            let mut reply = None;
+
+            loop {
+                match next_behaviour_event(&mut alice_swarm, &mut bob_swarm).await {
+                    Either::Right(BehaviourOutEvent::ReceivedAnnouncement { io, .. }) => reply = Some(io),
+                    Either::Right(BehaviourOutEvent::ReplyTimedOut { digest, .. }) => {
+                        assert_eq!(digest, swap_digest);
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+
+            let reply = reply.expect("bob received the announcement before it timed out");
+            assert!(matches!(
+                reply.confirm(SwapId::random()).await,
+                Err(protocol::Error::ReplyTimeout)
+            ));
+        })
+    }
+
+    #[test]
+    fn peers_with_different_protocol_prefixes_do_not_announce() {
+        let (mut alice_swarm, _) = new_swarm();
+        let (mut bob_swarm, bob_peer_id) =
+            new_swarm_with(AnnounceConfig::default().protocol_name("/testnet/swap/announce".to_owned()));
+
+        let dial_info = DialInformation {
+            peer_id: bob_peer_id.clone(),
+            address_hint: Some(listen(&mut bob_swarm)),
+        };
+
+        alice_swarm.start_announce_protocol(random_swap_digest(), dial_info).unwrap();
+
+        async_std::task::block_on(async move {
+            loop {
+                match next_behaviour_event(&mut alice_swarm, &mut bob_swarm).await {
+                    Either::Left(BehaviourOutEvent::AnnounceFailed { peer, .. }) => {
+                        assert_eq!(peer, bob_peer_id);
+                        return;
+                    }
+                    Either::Right(BehaviourOutEvent::ReceivedAnnouncement { .. }) => {
+                        panic!("bob received an announce made with a different prefix")
+                    }
+                    Either::Left(BehaviourOutEvent::PeerConnected { .. }) => {}
+                    Either::Left(BehaviourOutEvent::AnnounceInFlight { .. }) => {}
+                    Either::Left(BehaviourOutEvent::AnnounceQueued { .. }) => {}
+                    event => panic!("unexpected event {:?}", event),
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn pipelined_announces_are_confirmed_in_order() {
+        let config = AnnounceConfig::default().pipeline_announces(true);
+        let (mut alice_swarm, _) = new_swarm_with(config.clone());
+        let (mut bob_swarm, bob_peer_id) = new_swarm_with(config);
+        let bob_addr = listen(&mut bob_swarm);
+
+        // Announced before the connection exists, so all three are queued
+        // when it is established and go out on the same substream.
+        let swap_digests = (0u8..3)
+            .map(|i| SwapDigest::new(Sha2_256::digest(&[i])))
+            .collect::<Vec<_>>();
+        for swap_digest in &swap_digests {
+            let dial_info = DialInformation {
+                peer_id: bob_peer_id.clone(),
+                address_hint: Some(bob_addr.clone()),
+            };
+            alice_swarm.start_announce_protocol(swap_digest.clone(), dial_info).unwrap();
+        }
+
+        async_std::task::block_on(async move {
+            let mut replies = Vec::new();
+            let mut confirmed = Vec::new();
+
+            while confirmed.len() < swap_digests.len() {
+                match next_behaviour_event(&mut alice_swarm, &mut bob_swarm).await {
+                    Either::Right(BehaviourOutEvent::ReceivedAnnouncement { io, .. }) => {
+                        assert_eq!(io.version, ProtocolVersion::Pipelined);
+                        replies.push(io);
+
+                        // Confirming in reverse makes Alice rely on the
+                        // request ids to put them back in order.
+                        if replies.len() == swap_digests.len() {
+                            for reply in replies.drain(..).rev() {
+                                async_std::task::spawn(reply.confirm(SwapId::random()));
+                            }
+                        }
+                    }
+                    Either::Left(BehaviourOutEvent::ReceivedConfirmation { digest, .. }) => {
+                        confirmed.push(digest)
+                    }
+                    Either::Left(BehaviourOutEvent::PeerConnected { .. }) => {}
+                    Either::Left(BehaviourOutEvent::AnnounceInFlight { .. }) => {}
+                    Either::Left(BehaviourOutEvent::AnnounceQueued { .. }) => {}
+                    Either::Left(event) => panic!("unexpected event {:?}", event),
+                    Either::Right(_) => {}
+                }
+            }
+
+            assert_eq!(confirmed, swap_digests);
+        })
+    }
+
+    #[test]
+    fn flooding_peer_is_rate_limited() {
+        let (mut alice_swarm, _) = new_swarm();
+        let (mut bob_swarm, bob_peer_id) =
+            new_swarm_with(AnnounceConfig::default().max_inbound_per_peer_per_second(2));
+        let bob_addr = listen(&mut bob_swarm);
+
+        for i in 0u8..10 {
+            let dial_info = DialInformation {
+                peer_id: bob_peer_id.clone(),
+                address_hint: Some(bob_addr.clone()),
+            };
+            let swap_digest = SwapDigest::new(Sha2_256::digest(&[i]));
+            alice_swarm.start_announce_protocol(swap_digest, dial_info).unwrap();
+        }
+
+        async_std::task::block_on(async move {
+            // Bob holds on to the announcements he lets through.
+            let mut received = Vec::new();
+
+            loop {
+                match next_behaviour_event(&mut alice_swarm, &mut bob_swarm).await {
+                    Either::Right(BehaviourOutEvent::ReceivedAnnouncement { io, .. }) => received.push(io),
+                    Either::Right(BehaviourOutEvent::InboundRateLimited { .. }) => break,
+                    _ => {}
+                }
+            }
+
+            assert_eq!(received.len(), 2);
+        })
+    }
+
+    #[test]
+    fn announce_uses_configured_protocol_versions() {
+        let (mut alice_swarm, _) = new_swarm();
+        let (mut bob_swarm, bob_peer_id) = new_swarm_with(AnnounceConfig::default().versions(vec![ProtocolVersion::V1]));
+
+        let bob_addr = listen(&mut bob_swarm);
+
+        let dial_info = DialInformation {
+            peer_id: bob_peer_id,
+            address_hint: Some(bob_addr),
+        };
+
+        alice_swarm.start_announce_protocol(random_swap_digest(), dial_info).unwrap();
+
+        async_std::task::block_on(async move {
+            loop {
+                if let Either::Right(BehaviourOutEvent::ReceivedAnnouncement { io, .. }) =
+                    next_behaviour_event(&mut alice_swarm, &mut bob_swarm).await
+                {
+                    assert_eq!(io.version, ProtocolVersion::V1);
+                    return;
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn announcement_of_a_v1_peer_reports_v1() {
+        let (mut alice_swarm, _) = new_swarm_with(AnnounceConfig::default().versions(vec![ProtocolVersion::V1]));
+        let (mut bob_swarm, bob_peer_id) = new_swarm();
+
+        let dial_info = DialInformation {
+            peer_id: bob_peer_id,
+            address_hint: Some(listen(&mut bob_swarm)),
+        };
+        alice_swarm.start_announce_protocol(random_swap_digest(), dial_info).unwrap();
+
+        async_std::task::block_on(async move {
+            loop {
+                match next_behaviour_event(&mut alice_swarm, &mut bob_swarm).await {
+                    Either::Right(BehaviourOutEvent::ReceivedAnnouncement { io, version, .. }) => {
+                        assert_eq!(version, ProtocolVersion::V1);
+                        assert_eq!(io.version, ProtocolVersion::V1);
+                        async_std::task::spawn(io.confirm(SwapId::random()));
+                    }
+                    // Alice only speaks V1, so she could not have read a
+                    // confirmation in another format.
+                    Either::Left(BehaviourOutEvent::ReceivedConfirmation { .. }) => return,
+                    _ => {}
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn v2_announce_carries_its_ttl() {
+        let (mut alice_swarm, _) = new_swarm();
+        let (mut bob_swarm, bob_peer_id) = new_swarm();
+
+        let dial_info = DialInformation {
+            peer_id: bob_peer_id,
+            address_hint: Some(listen(&mut bob_swarm)),
+        };
+        let ttl = Duration::from_secs(90);
+        alice_swarm
+            .start_announce_protocol_with_ttl(random_swap_digest(), dial_info, ttl)
+            .unwrap();
+
+        async_std::task::block_on(async move {
+            loop {
+                if let Either::Right(BehaviourOutEvent::ReceivedAnnouncement { io, ttl: received, .. }) =
+                    next_behaviour_event(&mut alice_swarm, &mut bob_swarm).await
+                {
+                    assert_eq!(io.version, ProtocolVersion::V2);
+                    assert_eq!(received, Some(ttl));
+                    return;
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn confirmation_pushed_by_the_receiver_confirms_the_announce() {
+        let (mut alice_swarm, _) = new_swarm_with(AnnounceConfig::default().push_confirmations(true));
+        let (mut bob_swarm, bob_peer_id) = new_swarm();
+
+        let dial_info = DialInformation {
+            peer_id: bob_peer_id.clone(),
+            address_hint: Some(listen(&mut bob_swarm)),
+        };
+        let swap_digest = random_swap_digest();
+        alice_swarm.start_announce_protocol(swap_digest.clone(), dial_info).unwrap();
+
+        let swap_id = SwapId::default();
+        async_std::task::block_on(async move {
+            let (mut confirmed, mut sent) = (false, false);
+            while !(confirmed && sent) {
+                match next_behaviour_event(&mut alice_swarm, &mut bob_swarm).await {
+                    Either::Right(BehaviourOutEvent::ReceivedAnnouncement { peer, io, .. }) => {
+                        // Not replied to, Bob opens a substream of his own.
+                        let digest = io.swap_digest.clone();
+                        drop(io);
+                        assert!(bob_swarm.push_confirmation(peer, digest, swap_id));
+                    }
+                    Either::Left(BehaviourOutEvent::ReceivedConfirmation {
+                        peer,
+                        digest,
+                        swap_id: received,
+                        ..
+                    }) => {
+                        assert_eq!(peer, bob_peer_id);
+                        assert_eq!(digest, swap_digest);
+                        assert_eq!(received, swap_id);
+                        confirmed = true;
+                    }
+                    Either::Right(BehaviourOutEvent::ConfirmationSent { digest, .. }) => {
+                        assert_eq!(digest, swap_digest);
+                        sent = true;
+                    }
+                    Either::Left(BehaviourOutEvent::AnnounceFailed { .. })
+                    | Either::Right(BehaviourOutEvent::ConfirmationSendFailed { .. }) => {
+                        panic!("the pushed confirmation did not arrive")
+                    }
+                    _ => {}
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn v1_announce_has_no_ttl() {
+        let (mut alice_swarm, _) = new_swarm();
+        let (mut bob_swarm, bob_peer_id) = new_swarm_with(AnnounceConfig::default().versions(vec![ProtocolVersion::V1]));
+
+        let dial_info = DialInformation {
+            peer_id: bob_peer_id,
+            address_hint: Some(listen(&mut bob_swarm)),
+        };
+        alice_swarm
+            .start_announce_protocol_with_ttl(random_swap_digest(), dial_info, Duration::from_secs(90))
+            .unwrap();
+
+        async_std::task::block_on(async move {
+            loop {
+                if let Either::Right(BehaviourOutEvent::ReceivedAnnouncement { io, ttl, .. }) =
+                    next_behaviour_event(&mut alice_swarm, &mut bob_swarm).await
+                {
+                    assert_eq!(io.version, ProtocolVersion::V1);
+                    assert_eq!(ttl, None);
+                    return;
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn node_not_listening_announces_but_refuses_announcements() {
+        let (mut alice_swarm, alice_peer_id) = new_swarm_with(AnnounceConfig::default().listen(false));
+        let (mut bob_swarm, bob_peer_id) = new_swarm();
+
+        let dial_info = DialInformation {
+            peer_id: bob_peer_id,
+            address_hint: Some(listen(&mut bob_swarm)),
+        };
+        alice_swarm.start_announce_protocol(random_swap_digest(), dial_info).unwrap();
+
+        async_std::task::block_on(async move {
+            loop {
+                match next_behaviour_event(&mut alice_swarm, &mut bob_swarm).await {
+                    Either::Right(BehaviourOutEvent::ReceivedAnnouncement { io, .. }) => {
+                        async_std::task::spawn(io.confirm(SwapId::random()));
+                    }
+                    // Over the connection Alice made, with Alice not offering
+                    // the protocol.
+                    Either::Left(BehaviourOutEvent::ReceivedConfirmation { .. }) => {
+                        let dial_info = DialInformation {
+                            peer_id: alice_peer_id.clone(),
+                            address_hint: None,
+                        };
+                        bob_swarm.start_announce_protocol(random_swap_digest(), dial_info).unwrap();
+                    }
+                    Either::Left(BehaviourOutEvent::ReceivedAnnouncement { .. }) => {
+                        panic!("Alice accepted an announcement")
+                    }
+                    Either::Right(BehaviourOutEvent::AnnounceFailed { peer, .. }) => {
+                        assert_eq!(peer, alice_peer_id);
+                        return;
+                    }
+                    _ => {}
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn held_announce_is_resent_once_the_peer_reconnects() {
+        let (mut alice_swarm, alice_peer_id) =
+            new_swarm_with(AnnounceConfig::default().resend_on_reconnect(Duration::from_secs(5)));
+        let (mut bob_swarm, bob_peer_id) = new_swarm();
+
+        let bob_addr = listen(&mut bob_swarm);
+        let dial_info = DialInformation {
+            peer_id: bob_peer_id,
+            address_hint: Some(bob_addr.clone()),
+        };
+        alice_swarm.start_announce_protocol(random_swap_digest(), dial_info).unwrap();
+
+        async_std::task::block_on(async move {
+            let mut announcements = 0;
+            let mut disconnected = false;
+            loop {
+                match next_behaviour_event(&mut alice_swarm, &mut bob_swarm).await {
+                    // The first announcement goes down with the connection.
+                    Either::Right(BehaviourOutEvent::ReceivedAnnouncement { io, .. }) if announcements == 0 => {
+                        announcements += 1;
+                        Swarm::ban_peer_id(&mut bob_swarm, alice_peer_id.clone());
+                        Swarm::unban_peer_id(&mut bob_swarm, alice_peer_id.clone());
+                        drop(io);
+                    }
+                    Either::Right(BehaviourOutEvent::ReceivedAnnouncement { io, .. }) => {
+                        announcements += 1;
+                        async_std::task::spawn(io.confirm(SwapId::random()));
+                    }
+                    Either::Left(BehaviourOutEvent::PeerDisconnected { .. }) => {
+                        disconnected = true;
+                        Swarm::dial_addr(&mut alice_swarm, bob_addr.clone()).unwrap();
+                    }
+                    Either::Left(BehaviourOutEvent::ReceivedConfirmation { .. }) => break,
+                    Either::Left(BehaviourOutEvent::AnnounceFailed { .. }) => panic!("the announce was not held"),
+                    _ => {}
+                }
+            }
+
+            assert!(disconnected);
+            assert_eq!(announcements, 2);
+        })
+    }
+
+    #[test]
+    fn held_announce_expires_if_the_peer_does_not_reconnect() {
+        let (mut alice_swarm, alice_peer_id) =
+            new_swarm_with(AnnounceConfig::default().resend_on_reconnect(Duration::from_millis(100)));
+        let (mut bob_swarm, bob_peer_id) = new_swarm();
+
+        let dial_info = DialInformation {
+            peer_id: bob_peer_id.clone(),
+            address_hint: Some(listen(&mut bob_swarm)),
+        };
+        let swap_digest = random_swap_digest();
+        alice_swarm.start_announce_protocol(swap_digest.clone(), dial_info).unwrap();
+
+        async_std::task::block_on(async move {
+            let mut _reply = None;
+            loop {
+                match next_behaviour_event(&mut alice_swarm, &mut bob_swarm).await {
+                    Either::Right(BehaviourOutEvent::ReceivedAnnouncement { io, .. }) => {
+                        _reply = Some(io);
+                        Swarm::ban_peer_id(&mut bob_swarm, alice_peer_id.clone());
+                    }
+                    Either::Left(BehaviourOutEvent::AnnounceExpired { peer, digest }) => {
+                        assert_eq!(peer, bob_peer_id);
+                        assert_eq!(digest, swap_digest);
+                        break;
+                    }
+                    Either::Left(BehaviourOutEvent::AnnounceFailed { .. }) => panic!("the announce was not held"),
+                    _ => {}
+                }
+            }
+
+            assert_eq!(alice_swarm.pending_announcements().count(), 0);
+        })
+    }
+
+    #[test]
+    fn pipelined_announcements_are_received_in_the_order_they_were_sent() {
+        let config = AnnounceConfig::default().pipeline_announces(true);
+        let (mut alice_swarm, _) = new_swarm_with(config.clone());
+        let (mut bob_swarm, bob_peer_id) = new_swarm_with(config);
+
+        let bob_addr = listen(&mut bob_swarm);
+        let sent = (0u8..3).map(|i| SwapDigest::new(Sha2_256::digest(&[i]))).collect::<Vec<_>>();
+        for swap_digest in &sent {
+            let dial_info = DialInformation {
+                peer_id: bob_peer_id.clone(),
+                address_hint: Some(bob_addr.clone()),
+            };
+            alice_swarm.start_announce_protocol(swap_digest.clone(), dial_info).unwrap();
+        }
+
+        async_std::task::block_on(async move {
+            let mut received = Vec::new();
+            while received.len() < sent.len() {
+                if let Either::Right(BehaviourOutEvent::ReceivedAnnouncement { io, .. }) =
+                    next_behaviour_event(&mut alice_swarm, &mut bob_swarm).await
+                {
+                    received.push(io.swap_digest.clone());
+                }
+            }
+
+            assert_eq!(received, sent);
+        })
+    }
+
+    #[test]
+    fn confirmed_announces_are_no_longer_pending() {
+        let (mut alice_swarm, _) = new_swarm();
+        let (mut bob_swarm, bob_peer_id) = new_swarm();
+
+        let bob_addr = listen(&mut bob_swarm);
+
+        let first = random_swap_digest();
+        let second = SwapDigest(Sha2_256::digest(b"goodbye world"));
+
+        for swap_digest in [first.clone(), second.clone()] {
+            let dial_info = DialInformation {
+                peer_id: bob_peer_id.clone(),
+                address_hint: Some(bob_addr.clone()),
+            };
+            alice_swarm.start_announce_protocol(swap_digest, dial_info).unwrap();
+        }
+
+        let mut pending = alice_swarm
+            .pending_announcements()
+            .map(|(peer, digest)| (peer.clone(), digest.clone()))
+            .collect::<Vec<_>>();
+        pending.sort_by_key(|(_, digest)| digest.to_string());
+        let mut expected = vec![(bob_peer_id.clone(), first), (bob_peer_id, second)];
+        expected.sort_by_key(|(_, digest)| digest.to_string());
+        assert_eq!(pending, expected);
+
+        async_std::task::block_on(async move {
+            let mut confirmed = 0;
+
+            while confirmed < 2 {
+                match next_behaviour_event(&mut alice_swarm, &mut bob_swarm).await {
+                    Either::Left(BehaviourOutEvent::ReceivedConfirmation { digest, .. }) => {
+                        confirmed += 1;
+                        assert!(alice_swarm.pending_announcements().all(|(_, pending)| pending != &digest));
+                        assert_eq!(alice_swarm.pending_announcements().count(), 2 - confirmed);
+                    }
+                    Either::Right(BehaviourOutEvent::ReceivedAnnouncement { io, .. }) => {
+                        async_std::task::spawn(io.confirm(SwapId::default()));
+                    }
+                    _ => {}
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn failed_announce_is_retried_until_peer_accepts() {
+        let config = AnnounceConfig::default()
+            .max_retries(2)
+            .base_backoff(Duration::from_millis(50));
+        let (mut alice_swarm, _) = new_swarm_with(config);
+        let (mut bob_swarm, bob_peer_id) = new_swarm();
+
+        // Bob only starts listening on this address once Alice's first two
+        // dials have been refused.
+        let bob_addr = unused_address();
+
+        let dial_info = DialInformation {
+            peer_id: bob_peer_id,
+            address_hint: Some(bob_addr.clone()),
+        };
+
+        alice_swarm.start_announce_protocol(random_swap_digest(), dial_info).unwrap();
+
+        async_std::task::block_on(async move {
+            let mut refused = 0;
+
+            loop {
+                let event =
+                    match future::select(alice_swarm.next_event().boxed(), bob_swarm.next_event().boxed()).await {
+                        Either::Left((event, _)) => Either::Left(event),
+                        Either::Right((event, _)) => Either::Right(event),
+                    };
+
+                match event {
+                    Either::Left(SwarmEvent::UnreachableAddr { .. }) => {
+                        refused += 1;
+                        if refused == 2 {
+                            Swarm::listen_on(&mut bob_swarm, bob_addr.clone()).unwrap();
+                        }
+                    }
+                    Either::Left(SwarmEvent::Behaviour(BehaviourOutEvent::ReceivedConfirmation { .. })) => {
+                        assert_eq!(refused, 2);
+                        return;
+                    }
+                    Either::Left(SwarmEvent::Behaviour(BehaviourOutEvent::PeerConnected { .. })) => {}
+                    Either::Left(SwarmEvent::Behaviour(BehaviourOutEvent::AnnounceInFlight { .. })) => {}
+                    Either::Left(SwarmEvent::Behaviour(BehaviourOutEvent::AnnounceQueued { .. })) => {}
+                    Either::Left(SwarmEvent::Behaviour(event)) => panic!("unexpected event {:?}", event),
+                    Either::Right(SwarmEvent::Behaviour(BehaviourOutEvent::ReceivedAnnouncement { io, .. })) => {
+                        async_std::task::spawn(io.confirm(SwapId::default()));
+                    }
+                    _ => {}
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn confirmation_carries_the_context_of_its_announce() {
+        let (mut alice_swarm, _) = new_swarm();
+        let (mut bob_swarm, bob_peer_id) = new_swarm();
+        let bob_addr = listen(&mut bob_swarm);
+        spawn_confirming(bob_swarm);
+
+        let swap_digests = (0u8..2)
+            .map(|i| SwapDigest::new(Sha2_256::digest(&[i])))
+            .collect::<Vec<_>>();
+        for (context, swap_digest) in swap_digests.iter().enumerate() {
+            let dial_info = DialInformation {
+                peer_id: bob_peer_id.clone(),
+                address_hint: Some(bob_addr.clone()),
+            };
+            alice_swarm
+                .start_announce_protocol_with_context(swap_digest.clone(), dial_info, context as u64)
+                .unwrap();
+        }
+
+        async_std::task::block_on(async move {
+            let mut confirmed = 0;
+
+            while confirmed < swap_digests.len() {
+                match alice_swarm.next().await {
+                    BehaviourOutEvent::ReceivedConfirmation { digest, context, .. } => {
+                        let expected = swap_digests.iter().position(|d| *d == digest).unwrap();
+                        assert_eq!(context, Some(expected as u64));
+                        confirmed += 1;
+                    }
+                    BehaviourOutEvent::PeerConnected { .. } => {}
+                    BehaviourOutEvent::AnnounceInFlight { .. } => {}
+                    BehaviourOutEvent::AnnounceQueued { .. } => {}
+                    event => panic!("unexpected event {:?}", event),
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn replayed_announcement_is_suppressed() {
+        // Bob does not answer the replay, Alice gives up on it quickly.
+        let (mut alice_swarm, _) =
+            new_swarm_with(AnnounceConfig::default().timeout(Duration::from_millis(500)));
+        let (mut bob_swarm, bob_peer_id) =
+            new_swarm_with(AnnounceConfig::default().recently_completed_capacity(8));
+        let dial_info = DialInformation {
+            peer_id: bob_peer_id,
+            address_hint: Some(listen(&mut bob_swarm)),
+        };
+
+        let swap_digest = random_swap_digest();
+        alice_swarm.start_announce_protocol(swap_digest.clone(), dial_info.clone()).unwrap();
+
+        async_std::task::block_on(async move {
+            let mut announcements = 0;
+            let (mut sent, mut confirmed, mut replayed) = (false, false, false);
+
+            loop {
+                match next_behaviour_event(&mut alice_swarm, &mut bob_swarm).await {
+                    Either::Right(BehaviourOutEvent::ReceivedAnnouncement { io, .. }) => {
+                        announcements += 1;
+                        async_std::task::spawn(io.confirm(SwapId::random()));
+                    }
+                    Either::Right(BehaviourOutEvent::ConfirmationSent { .. }) => sent = true,
+                    Either::Left(BehaviourOutEvent::ReceivedConfirmation { .. }) => {
+                        assert!(!confirmed);
+                        confirmed = true;
+                    }
+                    Either::Left(BehaviourOutEvent::AnnounceFailed { digest, .. }) => {
+                        assert!(replayed);
+                        assert_eq!(digest, swap_digest);
+                        break;
+                    }
+                    Either::Left(BehaviourOutEvent::PeerConnected { .. }) => {}
+                    Either::Left(BehaviourOutEvent::AnnounceInFlight { .. }) => {}
+                    Either::Left(BehaviourOutEvent::AnnounceQueued { .. }) => {}
+                    event => panic!("unexpected event {:?}", event),
+                }
+
+                // Bob only remembers the announce once he is done with it.
+                if sent && confirmed && !replayed {
+                    replayed = true;
+                    alice_swarm
+                        .start_announce_protocol(swap_digest.clone(), dial_info.clone())
+                        .unwrap();
+                }
+            }
+
+            assert_eq!(announcements, 1);
+        })
+    }
+
+    #[test]
+    fn announcement_can_be_confirmed_later_from_another_task() {
+        let (mut alice_swarm, _) = new_swarm();
+        let (mut bob_swarm, bob_peer_id) = new_swarm();
+        let dial_info = DialInformation {
+            peer_id: bob_peer_id,
+            address_hint: Some(listen(&mut bob_swarm)),
+        };
+        async_std::task::spawn(async move {
+            loop {
+                if let BehaviourOutEvent::ReceivedAnnouncement { io, .. } = bob_swarm.next().await {
+                    // Stands in for an asynchronous lookup deciding to confirm.
+                    async_std::task::spawn(async move {
+                        Delay::new(Duration::from_millis(200)).await.unwrap();
+                        io.confirm(SwapId::random()).await
+                    });
+                }
+            }
+        });
+
+        let swap_digest = random_swap_digest();
+        alice_swarm.start_announce_protocol(swap_digest.clone(), dial_info).unwrap();
+
+        async_std::task::block_on(async move {
+            loop {
+                match alice_swarm.next().await {
+                    BehaviourOutEvent::ReceivedConfirmation { digest, .. } => {
+                        assert_eq!(digest, swap_digest);
+                        return;
+                    }
+                    BehaviourOutEvent::PeerConnected { .. } => {}
+                    BehaviourOutEvent::AnnounceInFlight { .. } => {}
+                    BehaviourOutEvent::AnnounceQueued { .. } => {}
+                    event => panic!("unexpected event {:?}", event),
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn announce_without_hint_uses_identified_addresses() {
+        let (mut alice_swarm, _) = new_swarm();
+        let (mut bob_swarm, bob_peer_id) = new_swarm();
+        let bob_addr = listen(&mut bob_swarm);
+        spawn_confirming(bob_swarm);
+
+        alice_swarm.inject_identified_addresses(bob_peer_id.clone(), vec![bob_addr]);
+        let dial_info = DialInformation {
+            peer_id: bob_peer_id,
+            address_hint: None,
+        };
+        let swap_digest = random_swap_digest();
+        alice_swarm.start_announce_protocol(swap_digest.clone(), dial_info).unwrap();
+
+        async_std::task::block_on(async move {
+            loop {
+                match alice_swarm.next().await {
+                    BehaviourOutEvent::ReceivedConfirmation { digest, .. } => {
+                        assert_eq!(digest, swap_digest);
+                        return;
+                    }
+                    BehaviourOutEvent::PeerConnected { .. } => {}
+                    BehaviourOutEvent::AnnounceInFlight { .. } => {}
+                    BehaviourOutEvent::AnnounceQueued { .. } => {}
+                    event => panic!("unexpected event {:?}", event),
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn first_confirmation_wins_the_race() {
+        let (mut alice_swarm, _) = new_swarm();
+        let (mut bob_swarm, bob_peer_id) = new_swarm();
+        let (mut carol_swarm, carol_peer_id) = new_swarm();
+        let peers = vec![
+            DialInformation {
+                peer_id: bob_peer_id.clone(),
+                address_hint: Some(listen(&mut bob_swarm)),
+            },
+            DialInformation {
+                peer_id: carol_peer_id.clone(),
+                address_hint: Some(listen(&mut carol_swarm)),
+            },
+        ];
+        spawn_confirming(bob_swarm);
+        // Carol takes her time.
+        async_std::task::spawn(async move {
+            loop {
+                if let BehaviourOutEvent::ReceivedAnnouncement { io, .. } = carol_swarm.next().await {
+                    async_std::task::spawn(async move {
+                        Delay::new(Duration::from_millis(500)).await.unwrap();
+                        io.confirm(SwapId::random()).await
+                    });
+                }
+            }
+        });
+
+        let swap_digest = random_swap_digest();
+        let mut race = alice_swarm.announce_race(swap_digest.clone(), peers).unwrap();
+
+        async_std::task::block_on(async move {
+            let (mut confirmed, mut lost) = (false, false);
+            while !(confirmed && lost) {
+                match alice_swarm.next().await {
+                    BehaviourOutEvent::ReceivedConfirmation { peer, .. } => {
+                        assert_eq!(peer, bob_peer_id);
+                        confirmed = true;
+                    }
+                    BehaviourOutEvent::RaceLost { peer, digest } => {
+                        assert_eq!(peer, carol_peer_id);
+                        assert_eq!(digest, swap_digest);
+                        lost = true;
+                    }
+                    BehaviourOutEvent::PeerConnected { .. } => {}
+                    BehaviourOutEvent::AnnounceInFlight { .. } => {}
+                    BehaviourOutEvent::AnnounceQueued { .. } => {}
+                    event => panic!("unexpected event {:?}", event),
+                }
+            }
+
+            let (winner, _) = (&mut race).now_or_never().unwrap().unwrap();
+            assert_eq!(winner, bob_peer_id);
+
+            // Carol's late confirmation is not reported.
+            let mut wait = Delay::new(Duration::from_secs(1));
+            loop {
+                match future::select(&mut wait, alice_swarm.next().boxed()).await {
+                    Either::Left(_) => break,
+                    Either::Right((BehaviourOutEvent::ReceivedConfirmation { peer, .. }, _)) => {
+                        panic!("confirmation from {} after the race was won", peer)
+                    }
+                    Either::Right(_) => {}
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn forced_reannounce_is_confirmed_again() {
+        let (mut alice_swarm, _) =
+            new_swarm_with(AnnounceConfig::default().recently_completed_capacity(8));
+        let (mut bob_swarm, bob_peer_id) = new_swarm();
+        let dial_info = DialInformation {
+            peer_id: bob_peer_id,
+            address_hint: Some(listen(&mut bob_swarm)),
+        };
+        spawn_confirming(bob_swarm);
+
+        let swap_digest = random_swap_digest();
+        alice_swarm.start_announce_protocol(swap_digest.clone(), dial_info.clone()).unwrap();
+
+        async_std::task::block_on(async move {
+            let mut confirmations = 0;
+
+            loop {
+                match alice_swarm.next().await {
+                    BehaviourOutEvent::ReceivedConfirmation { digest, .. } => {
+                        assert_eq!(digest, swap_digest);
+                        confirmations += 1;
+                        if confirmations == 2 {
+                            break;
+                        }
+                        alice_swarm
+                            .force_reannounce(swap_digest.clone(), dial_info.clone())
+                            .unwrap();
+                    }
+                    BehaviourOutEvent::PeerConnected { .. } => {}
+                    BehaviourOutEvent::AnnounceInFlight { .. } => {}
+                    BehaviourOutEvent::AnnounceQueued { .. } => {}
+                    event => panic!("unexpected event {:?}", event),
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn shutdown_resolves_once_the_announce_is_confirmed() {
+        let (mut alice_swarm, _) = new_swarm();
+        let (mut bob_swarm, bob_peer_id) = new_swarm();
+        let dial_info = DialInformation {
+            peer_id: bob_peer_id,
+            address_hint: Some(listen(&mut bob_swarm)),
+        };
+        spawn_confirming(bob_swarm);
+
+        alice_swarm.start_announce_protocol(random_swap_digest(), dial_info.clone()).unwrap();
+        let mut shutdown = alice_swarm.shutdown().boxed();
+
+        let error = alice_swarm
+            .start_announce_protocol(random_swap_digest(), dial_info)
+            .unwrap_err();
+        assert_eq!(error, StartAnnounceError::ShuttingDown);
+
+        async_std::task::block_on(async move {
+            let mut confirmed = false;
+
+            loop {
+                match future::select(shutdown, alice_swarm.next().boxed()).await {
+                    Either::Left(((), _)) => break,
+                    Either::Right((event, unfinished)) => {
+                        shutdown = unfinished;
+                        match event {
+                            BehaviourOutEvent::ReceivedConfirmation { .. } => confirmed = true,
+                            BehaviourOutEvent::PeerConnected { .. } => {}
+                            BehaviourOutEvent::AnnounceInFlight { .. } => {}
+                            BehaviourOutEvent::AnnounceQueued { .. } => {}
+                            event => panic!("unexpected event {:?}", event),
+                        }
+                    }
+                }
+            }
+
+            assert!(confirmed);
+        })
+    }
+
+    #[test]
+    fn shutdown_of_an_idle_swarm_resolves_and_refuses_new_announces() {
+        let (mut alice_swarm, _) = new_swarm();
+        let (mut bob_swarm, bob_peer_id) = new_swarm();
+        let dial_info = DialInformation {
+            peer_id: bob_peer_id,
+            address_hint: Some(listen(&mut bob_swarm)),
+        };
+
+        async_std::task::block_on(alice_swarm.shutdown());
+
+        let error = alice_swarm
+            .start_announce_protocol(random_swap_digest(), dial_info)
+            .unwrap_err();
+        assert_eq!(error, StartAnnounceError::ShuttingDown);
+    }
+
+    #[test]
+    fn handler_and_in_flight_counts_drop_to_zero() {
+        let (mut alice_swarm, _) =
+            new_swarm_with(AnnounceConfig::default().idle_timeout(Duration::from_millis(100)));
+        let (mut bob_swarm, bob_peer_id) = new_swarm();
+        let bob_addr = listen(&mut bob_swarm);
+        spawn_confirming(bob_swarm);
+
+        const ANNOUNCES: u8 = 3;
+        for i in 0..ANNOUNCES {
+            let dial_info = DialInformation {
+                peer_id: bob_peer_id.clone(),
+                address_hint: Some(bob_addr.clone()),
+            };
+            let swap_digest = SwapDigest::new(Sha2_256::digest(&[i]));
+            alice_swarm.start_announce_protocol(swap_digest, dial_info).unwrap();
+        }
+        assert_eq!(alice_swarm.in_flight_count(), usize::from(ANNOUNCES));
+        assert_eq!(alice_swarm.active_handler_count(), 0);
+
+        async_std::task::block_on(async move {
+            let mut confirmed = 0;
+
+            while confirmed < ANNOUNCES {
+                match alice_swarm.next_event().await {
+                    SwarmEvent::Behaviour(BehaviourOutEvent::ReceivedConfirmation { .. }) => {
+                        assert_eq!(alice_swarm.active_handler_count(), 1);
+                        confirmed += 1;
+                    }
+                    SwarmEvent::Behaviour(BehaviourOutEvent::PeerConnected { .. }) => {}
+                    SwarmEvent::Behaviour(BehaviourOutEvent::AnnounceInFlight { .. }) => {}
+                    SwarmEvent::Behaviour(BehaviourOutEvent::AnnounceQueued { .. }) => {}
+                    SwarmEvent::Behaviour(event) => panic!("unexpected event {:?}", event),
+                    _ => {}
+                }
+            }
+            assert_eq!(alice_swarm.in_flight_count(), 0);
+
+            // The connection is closed once it has been idle for long enough.
+            while alice_swarm.active_handler_count() > 0 {
+                alice_swarm.next_event().await;
+            }
+        })
+    }
+
+    #[test]
+    fn connected_peer_is_reported_as_connected() {
+        let (mut alice_swarm, alice_peer_id) = new_swarm();
+        let (mut bob_swarm, bob_peer_id) = new_swarm();
+        let dial_info = DialInformation {
+            peer_id: bob_peer_id.clone(),
+            address_hint: Some(listen(&mut bob_swarm)),
+        };
+        spawn_confirming(bob_swarm);
+
+        assert!(!alice_swarm.is_connected(&bob_peer_id));
+        alice_swarm.start_announce_protocol(random_swap_digest(), dial_info).unwrap();
+
+        async_std::task::block_on(async move {
+            loop {
+                match alice_swarm.next().await {
+                    BehaviourOutEvent::PeerConnected { peer } => {
+                        assert_eq!(peer, bob_peer_id);
+                        assert!(alice_swarm.is_connected(&bob_peer_id));
+                        assert!(!alice_swarm.is_connected(&alice_peer_id));
+                        return;
+                    }
+                    BehaviourOutEvent::AnnounceQueued { .. } => {}
+                    event => panic!("unexpected event {:?}", event),
+                }
+            }
+        })
+    }
+
+    /// What a node confirms swaps with when a swap id alone is not enough.
+    #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+    struct LedgerConfirmation {
+        swap_id: SwapId,
+        ledger: String,
+    }
+
+    impl Confirmation for LedgerConfirmation {}
+
+    fn new_ledger_swarm() -> (Swarm<Announce<LedgerConfirmation>>, PeerId) {
+        let (peer_id, transport) = testing::transport();
+        let behaviour = Announce::with_config(AnnounceConfig::default());
+        (Swarm::new(transport, behaviour, peer_id.clone()), peer_id)
+    }
+
+    #[test]
+    fn announce_is_confirmed_with_a_custom_confirmation() {
+        let (mut alice_swarm, _) = new_ledger_swarm();
+        let (mut bob_swarm, bob_peer_id) = new_ledger_swarm();
+        let dial_info = DialInformation {
+            peer_id: bob_peer_id,
+            address_hint: Some(listen(&mut bob_swarm)),
+        };
+
+        let confirmation = LedgerConfirmation {
+            swap_id: SwapId::random(),
+            ledger: "bitcoin-testnet".to_owned(),
+        };
+        let swap_digest = random_swap_digest();
+        alice_swarm.start_announce_protocol(swap_digest.clone(), dial_info).unwrap();
+
+        async_std::task::block_on(async move {
+            loop {
+                match future::select(alice_swarm.next().boxed(), bob_swarm.next().boxed()).await {
+                    Either::Left((BehaviourOutEvent::ReceivedConfirmation { digest, swap_id, .. }, _)) => {
+                        assert_eq!(digest, swap_digest);
+                        assert_eq!(swap_id, confirmation);
+                        return;
+                    }
+                    Either::Right((BehaviourOutEvent::ReceivedAnnouncement { io, .. }, _)) => {
+                        async_std::task::spawn(io.confirm(confirmation.clone()));
+                    }
+                    _ => {}
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn announce_is_confirmed_over_websocket() {
+        let (_, mut alice_swarm) = testing::build_swarm_with(testing::ws_transport(), AnnounceConfig::default());
+        let (bob_peer_id, mut bob_swarm) = testing::build_swarm_with(testing::ws_transport(), AnnounceConfig::default());
+
+        Swarm::listen_on(&mut bob_swarm, "/ip4/127.0.0.1/tcp/0/ws".parse().unwrap()).unwrap();
+        let bob_addr = async_std::task::block_on(async {
+            loop {
+                if let SwarmEvent::NewListenAddr(addr) = bob_swarm.next_event().await {
+                    return addr;
+                }
+            }
+        });
+        let dial_info = DialInformation {
+            peer_id: bob_peer_id.clone(),
+            address_hint: Some(bob_addr),
+        };
+        spawn_confirming(bob_swarm);
+
+        let swap_digest = random_swap_digest();
+        alice_swarm.start_announce_protocol(swap_digest.clone(), dial_info).unwrap();
+
+        async_std::task::block_on(async move {
+            loop {
+                match alice_swarm.next().await {
+                    BehaviourOutEvent::ReceivedConfirmation { peer, digest, .. } => {
+                        assert_eq!(peer, bob_peer_id);
+                        assert_eq!(digest, swap_digest);
+                        return;
+                    }
+                    BehaviourOutEvent::PeerConnected { .. } => {}
+                    BehaviourOutEvent::AnnounceInFlight { .. } => {}
+                    BehaviourOutEvent::AnnounceQueued { .. } => {}
+                    event => panic!("unexpected event {:?}", event),
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn announce_is_confirmed_over_noise() {
+        let (_, mut alice_swarm) = testing::build_swarm_with(testing::noise_transport(), AnnounceConfig::default());
+        let (bob_peer_id, mut bob_swarm) = testing::build_swarm_with(testing::noise_transport(), AnnounceConfig::default());
+        let bob_addr = listen(&mut bob_swarm);
+        let dial_info = DialInformation {
+            peer_id: bob_peer_id.clone(),
+            address_hint: Some(bob_addr),
+        };
+        spawn_confirming(bob_swarm);
+
+        let swap_digest = random_swap_digest();
+        alice_swarm.start_announce_protocol(swap_digest.clone(), dial_info).unwrap();
+
+        async_std::task::block_on(async move {
+            loop {
+                match alice_swarm.next().await {
+                    BehaviourOutEvent::ReceivedConfirmation { peer, digest, .. } => {
+                        assert_eq!(peer, bob_peer_id);
+                        assert_eq!(digest, swap_digest);
+                        return;
+                    }
+                    BehaviourOutEvent::PeerConnected { .. } => {}
+                    BehaviourOutEvent::AnnounceInFlight { .. } => {}
+                    BehaviourOutEvent::AnnounceQueued { .. } => {}
+                    event => panic!("unexpected event {:?}", event),
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn announce_is_confirmed_over_yamux() {
+        let (_, mut alice_swarm) = testing::build_swarm_with(testing::yamux_transport(), AnnounceConfig::default());
+        let (bob_peer_id, mut bob_swarm) = testing::build_swarm_with(testing::yamux_transport(), AnnounceConfig::default());
+        let bob_addr = listen(&mut bob_swarm);
+        let dial_info = DialInformation {
+            peer_id: bob_peer_id.clone(),
+            address_hint: Some(bob_addr),
+        };
+        spawn_confirming(bob_swarm);
+
+        let swap_digest = random_swap_digest();
+        alice_swarm.start_announce_protocol(swap_digest.clone(), dial_info).unwrap();
+
+        async_std::task::block_on(async move {
+            loop {
+                match alice_swarm.next().await {
+                    BehaviourOutEvent::ReceivedConfirmation { peer, digest, .. } => {
+                        assert_eq!(peer, bob_peer_id);
+                        assert_eq!(digest, swap_digest);
+                        return;
+                    }
+                    BehaviourOutEvent::PeerConnected { .. } => {}
+                    BehaviourOutEvent::AnnounceInFlight { .. } => {}
+                    BehaviourOutEvent::AnnounceQueued { .. } => {}
+                    event => panic!("unexpected event {:?}", event),
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn wire_inspector_sees_the_announce_frame() {
+        let frames = Arc::new(Mutex::new(Vec::new()));
+        let captured = frames.clone();
+        let config = AnnounceConfig::default().wire_inspector(Arc::new(move |direction, frame| {
+            captured.lock().unwrap().push((direction, frame.to_vec()))
+        }));
+        let (mut alice_swarm, _) = new_swarm_with(config);
+        let (mut bob_swarm, bob_peer_id) = new_swarm();
+        let bob_addr = listen(&mut bob_swarm);
+        spawn_confirming(bob_swarm);
+
+        let dial_info = DialInformation {
+            peer_id: bob_peer_id,
+            address_hint: Some(bob_addr),
+        };
+        // The digest of the test vectors.
+        alice_swarm.start_announce_protocol(random_swap_digest(), dial_info).unwrap();
+
+        async_std::task::block_on(async move {
+            loop {
+                if let BehaviourOutEvent::ReceivedConfirmation { .. } = alice_swarm.next().await {
+                    return;
+                }
+            }
+        });
+
+        let frames = frames.lock().unwrap();
+        let announce_frame = &protocol::test_vectors::ANNOUNCE_FRAME[1..];
+        assert!(frames.contains(&(Direction::Sent, announce_frame.to_vec())));
+        assert!(frames
+            .iter()
+            .any(|(direction, frame)| *direction == Direction::Received && !frame.is_empty()));
+    }
+
+    #[test]
+    fn event_stream_yields_the_behaviour_events() {
+        let (mut alice_swarm, _) = new_swarm();
+        let (mut bob_swarm, bob_peer_id) = new_swarm();
+        let bob_addr = listen(&mut bob_swarm);
+        spawn_confirming(bob_swarm);
+
+        let mut swap_digests = (0u8..2)
+            .map(|i| SwapDigest::new(Sha2_256::digest(&[i])))
+            .collect::<Vec<_>>();
+        swap_digests.sort();
+        for swap_digest in &swap_digests {
+            let dial_info = DialInformation {
+                peer_id: bob_peer_id.clone(),
+                address_hint: Some(bob_addr.clone()),
+            };
+            alice_swarm.start_announce_protocol(swap_digest.clone(), dial_info).unwrap();
+        }
+
+        let mut confirmed = async_std::task::block_on(
+            into_event_stream(alice_swarm)
+                .filter_map(|event| {
+                    future::ready(match event {
+                        BehaviourOutEvent::ReceivedConfirmation { digest, .. } => Some(digest),
+                        _ => None,
+                    })
+                })
+                .take(2)
+                .collect::<Vec<_>>(),
+        );
+
+        confirmed.sort();
+        assert_eq!(confirmed, swap_digests);
+    }
 }
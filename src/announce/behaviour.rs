@@ -1,22 +1,47 @@
 use crate::{
     swap_id::SwapId,
     announce::{
-        handler::{self, Handler, HandlerEvent},
-        protocol::{OutboundConfig, ReplySubstream},
+        deadlines::DeadlineQueue,
+        handler::{self, DigestFilter, Error, Handler, HandlerEvent, HandlerInEvent, KeepAlivePolicy},
+        metrics::AnnounceMetrics,
+        protocol::{
+            self, Confirmation, Confirmed, InspectFn, OutboundConfig, ProtocolVersion, RejectReason,
+            ReplySubstream, WireInspector,
+        },
         SwapDigest,
     }
 };
+use futures::{
+    channel::{mpsc, oneshot},
+    future::Future,
+    stream::{Stream, StreamExt},
+    FutureExt,
+};
 use libp2p::{
-    core::{connection::ConnectionId, ConnectedPoint, Multiaddr, PeerId},
+    core::{
+        connection::ConnectionId,
+        multiaddr::Protocol,
+        upgrade::{ProtocolName, UpgradeInfo},
+        ConnectedPoint, Multiaddr, PeerId,
+    },
     swarm::{
         NegotiatedSubstream, NetworkBehaviour, NetworkBehaviourAction, NotifyHandler,
-        PollParameters, ProtocolsHandler,
+        PollParameters, ProtocolsHandler, Swarm,
     },
 };
+use rand::{rngs::StdRng, Rng, SeedableRng};
 use std::{
     collections::{hash_map::Entry, HashMap, HashSet, VecDeque},
+    convert::TryFrom,
+    fmt,
+    pin::Pin,
+    sync::Arc,
     task::{Context, Poll},
+    time::Duration,
 };
+use wasm_timer::Instant;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 
 
 #[derive(Clone, Debug, PartialEq)]
@@ -25,27 +50,574 @@ pub struct DialInformation {
     pub address_hint: Option<Multiaddr>,
 }
 
+impl DialInformation {
+    /// Checks that the address hint, if any, says where to dial the peer
+    /// rather than e.g. only naming it with `/p2p/...`.
+    fn validate(&self) -> Result<(), InvalidAddressHint> {
+        let address = match &self.address_hint {
+            Some(address) => address,
+            None => return Ok(()),
+        };
+
+        if is_dialable(address) {
+            Ok(())
+        } else {
+            Err(InvalidAddressHint(address.clone()))
+        }
+    }
+}
+
+/// Whether the address says where to dial a peer.
+fn is_dialable(address: &Multiaddr) -> bool {
+    address.iter().any(|protocol| {
+        matches!(
+            protocol,
+            Protocol::Ip4(_)
+                | Protocol::Ip6(_)
+                | Protocol::Dns4(_)
+                | Protocol::Dns6(_)
+                | Protocol::Memory(_)
+                | Protocol::Unix(_)
+                | Protocol::Onion(..)
+                | Protocol::Onion3(_)
+        )
+    })
+}
+
+/// The address hint of a `DialInformation` cannot be dialed.
+#[derive(Clone, Debug, PartialEq, thiserror::Error)]
+#[error("address hint {0} does not say how to reach the peer")]
+pub struct InvalidAddressHint(pub Multiaddr);
+
+/// An announce could not be started.
+#[derive(Clone, Debug, PartialEq, thiserror::Error)]
+pub enum StartAnnounceError {
+    #[error(transparent)]
+    InvalidAddressHint(#[from] InvalidAddressHint),
+    #[error("the behaviour is shutting down")]
+    ShuttingDown,
+    #[error("too many announces to peer {0} are queued or in flight")]
+    QueueFull(PeerId),
+    #[error("not connected to peer {0} and dialing is disabled")]
+    NotConnected(PeerId),
+    #[error("address {0} does not end with the /p2p/ id of the peer")]
+    NoPeerId(Multiaddr),
+}
+
+/// The announces pending in an `Announce` behaviour, exported to resume
+/// them after a restart.
+///
+/// Only what is needed to start an announce again is kept, its substreams,
+/// retries and handles are lost.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct AnnounceState {
+    pub announces: Vec<PendingAnnounceState>,
+}
+
+/// A pending announce within an `AnnounceState`.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct PendingAnnounceState {
+    #[cfg_attr(feature = "serde", serde(with = "serde_peer_id"))]
+    pub peer: PeerId,
+    pub digest: SwapDigest,
+    pub address_hint: Option<Multiaddr>,
+    pub context: Option<u64>,
+}
+
+
+/// Configuration of the `Announce` behaviour.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AnnounceConfig {
+    timeout: Duration,
+    negotiation_timeout: Duration,
+    max_confirmation_size: usize,
+    max_in_flight_per_peer: usize,
+    versions: Vec<ProtocolVersion>,
+    max_retries: u32,
+    base_backoff: Duration,
+    max_backoff: Duration,
+    backoff_seed: Option<u64>,
+    keep_alive: KeepAlivePolicy,
+    reply_timeout: Duration,
+    read_timeout: Duration,
+    protocol_prefix: String,
+    pipeline_announces: bool,
+    push_confirmations: bool,
+    max_inbound_per_peer_per_second: Option<u32>,
+    recently_completed_capacity: usize,
+    dial_peers: bool,
+    max_concurrent_outbound: Option<usize>,
+    listen: bool,
+    resend_on_reconnect: Option<Duration>,
+    wire_inspector: Option<WireInspector>,
+}
+
+impl Default for AnnounceConfig {
+    fn default() -> Self {
+        AnnounceConfig {
+            timeout: protocol::DEFAULT_TIMEOUT,
+            negotiation_timeout: handler::DEFAULT_NEGOTIATION_TIMEOUT,
+            max_confirmation_size: protocol::MAX_CONFIRMATION_SIZE,
+            max_in_flight_per_peer: handler::DEFAULT_MAX_IN_FLIGHT,
+            versions: ProtocolVersion::ALL.to_vec(),
+            max_retries: 0,
+            base_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            backoff_seed: None,
+            keep_alive: KeepAlivePolicy::default(),
+            reply_timeout: protocol::DEFAULT_TIMEOUT,
+            read_timeout: protocol::DEFAULT_TIMEOUT,
+            protocol_prefix: protocol::DEFAULT_PROTOCOL_PREFIX.to_owned(),
+            pipeline_announces: false,
+            push_confirmations: false,
+            max_inbound_per_peer_per_second: None,
+            recently_completed_capacity: 0,
+            dial_peers: true,
+            max_concurrent_outbound: None,
+            listen: true,
+            resend_on_reconnect: None,
+            wire_inspector: None,
+        }
+    }
+}
+
+impl AnnounceConfig {
+    /// How long an outbound substream waits for the confirmation before the
+    /// announce fails.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// How long the peer has to agree on a protocol for an outbound
+    /// substream, from the substream being requested. `timeout` only starts
+    /// once it has. A peer that stalls the negotiation fails the announce
+    /// with `AnnounceFailed` after `negotiation_timeout`.
+    pub fn negotiation_timeout(mut self, negotiation_timeout: Duration) -> Self {
+        self.negotiation_timeout = negotiation_timeout;
+        self
+    }
+
+    /// How large a confirmation may be, in bytes, by default
+    /// `protocol::MAX_CONFIRMATION_SIZE`. Larger ones fail the announce.
+    pub fn max_confirmation_size(mut self, max: usize) -> Self {
+        self.max_confirmation_size = max;
+        self
+    }
+
+    /// How many announces to a single peer may be queued or in flight before
+    /// further announces are refused with `StartAnnounceError::QueueFull`.
+    pub fn max_in_flight_per_peer(mut self, max: usize) -> Self {
+        self.max_in_flight_per_peer = max;
+        self
+    }
+
+    /// The protocol versions to offer and accept, most preferred first.
+    pub fn versions(mut self, versions: Vec<ProtocolVersion>) -> Self {
+        self.versions = versions;
+        self
+    }
+
+    /// How many times a failed announce is retried before it is reported
+    /// as failed. Announces are not retried by default.
+    pub fn max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// How long to wait at most before the first retry, every further retry
+    /// may wait twice as long as the one before. The actual wait is drawn at
+    /// random from zero up to that, so announces that failed together are
+    /// not all retried at once.
+    pub fn base_backoff(mut self, base_backoff: Duration) -> Self {
+        self.base_backoff = base_backoff;
+        self
+    }
+
+    /// How long to wait at most before a retry, no matter how many came
+    /// before it. A minute by default.
+    pub fn max_backoff(mut self, max_backoff: Duration) -> Self {
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    /// Seeds the random waits before retries, so they are the same on every
+    /// run, e.g. in tests. Seeded from the operating system by default.
+    pub fn backoff_seed(mut self, seed: u64) -> Self {
+        self.backoff_seed = Some(seed);
+        self
+    }
+
+    /// How long a connection is kept open once there are no more announces
+    /// in flight on it, short for `keep_alive(KeepAlivePolicy::UntilIdle(..))`.
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.keep_alive = KeepAlivePolicy::UntilIdle(idle_timeout);
+        self
+    }
+
+    /// Whether and for how long a connection is kept open once there are no
+    /// more announces on it. By default it is closed after
+    /// `handler::DEFAULT_IDLE_TIMEOUT`.
+    pub fn keep_alive(mut self, policy: KeepAlivePolicy) -> Self {
+        self.keep_alive = policy;
+        self
+    }
+
+    /// How long the application has to confirm a `ReceivedAnnouncement`
+    /// before its reply substream is dropped.
+    pub fn reply_timeout(mut self, reply_timeout: Duration) -> Self {
+        self.reply_timeout = reply_timeout;
+        self
+    }
+
+    /// How long a peer has to send its announcement once it opened a
+    /// substream to us, a peer sending only part of it does not keep the
+    /// substream open for longer.
+    pub fn read_timeout(mut self, read_timeout: Duration) -> Self {
+        self.read_timeout = read_timeout;
+        self
+    }
+
+    /// What the names of the announce protocols start with, by default
+    /// `/comit/swap/announce`. Nodes using different prefixes cannot
+    /// announce swaps to each other, which keeps separate networks apart.
+    pub fn protocol_name(mut self, prefix: String) -> Self {
+        self.protocol_prefix = prefix;
+        self
+    }
+
+    /// Whether announces queued for the same peer are sent together on one
+    /// substream rather than each on its own. Only peers that enabled this
+    /// as well accept such announces. Disabled by default.
+    pub fn pipeline_announces(mut self, pipeline_announces: bool) -> Self {
+        self.pipeline_announces = pipeline_announces;
+        self
+    }
+
+    /// Whether the peers we announce to push their confirmations with
+    /// `Announce::push_confirmation`, on substreams of
+    /// `protocol::CONFIRM_PROTOCOL` they open themselves, rather than
+    /// replying on the announce substream. Announces are sent without
+    /// waiting for a reply and are not pipelined then, a peer replying on the
+    /// announce substream anyway is not heard. Disabled by default.
+    pub fn push_confirmations(mut self, push_confirmations: bool) -> Self {
+        self.push_confirmations = push_confirmations;
+        self
+    }
+
+    /// How many announcements a peer may send us per second. Beyond that its
+    /// announcements are dropped and its inbound substreams refused for the
+    /// rest of the second. Unlimited by default.
+    pub fn max_inbound_per_peer_per_second(mut self, max: u32) -> Self {
+        self.max_inbound_per_peer_per_second = Some(max);
+        self
+    }
+
+    /// How many of the most recently completed announces to remember, in
+    /// either direction. A remembered swap that is announced or confirmed by
+    /// the same peer again is not reported a second time, a replayed
+    /// announcement is dropped unanswered. Nothing is remembered by default.
+    pub fn recently_completed_capacity(mut self, capacity: usize) -> Self {
+        self.recently_completed_capacity = capacity;
+        self
+    }
+
+    /// Whether peers we are not connected to are dialed. If not, announces
+    /// are only sent over connections the swarm already has and starting
+    /// one to any other peer fails with `StartAnnounceError::NotConnected`,
+    /// which keeps whom we intend to reach to ourselves. Enabled by default.
+    pub fn dial_peers(mut self, dial_peers: bool) -> Self {
+        self.dial_peers = dial_peers;
+        self
+    }
+
+    /// How many announces may be in flight across all peers at once, each
+    /// needing a substream and possibly a connection. Further announces are
+    /// queued and sent as earlier ones are confirmed or fail. Unlimited by
+    /// default.
+    pub fn max_concurrent_outbound(mut self, max: usize) -> Self {
+        self.max_concurrent_outbound = Some(max);
+        self
+    }
+
+    /// Whether announcements from peers are accepted. A node that only ever
+    /// announces swaps, e.g. a command line client, can turn this off. It
+    /// then offers no protocol for inbound substreams, so peers announcing
+    /// to it fail as if it did not speak the protocol. Enabled by default.
+    pub fn listen(mut self, listen: bool) -> Self {
+        self.listen = listen;
+        self
+    }
+
+    /// How long announces to a peer that disconnected are held for it to
+    /// reconnect. They are sent again once it does, instead of being retried
+    /// or failing, and fail with `AnnounceExpired` if it does not reconnect
+    /// within `window`. An announce whose substream is closed or fails to
+    /// read or write is held too, since that is how a closing connection
+    /// often shows before the disconnect does. Disabled by default.
+    pub fn resend_on_reconnect(mut self, window: Duration) -> Self {
+        self.resend_on_reconnect = Some(window);
+        self
+    }
+
+    /// Shows `inspector` every frame sent or received on the substreams of
+    /// the behaviour, with the way it went, to see the exact bytes when
+    /// debugging interop problems. Only meant for debugging, none is set by
+    /// default.
+    pub fn wire_inspector(mut self, inspector: Arc<InspectFn>) -> Self {
+        self.wire_inspector = Some(WireInspector::new(inspector));
+        self
+    }
+}
 
 /// Network behaviour that announces a swap to peer by sending a `swap_digest`
 /// and receives the `swap_id` back.
+///
+/// Deliberately not `Clone`: the connections, announces and reply substreams
+/// it tracks belong to exactly one swarm. Use `clone_config` to get a fresh
+/// behaviour with the same configuration.
+///
+/// Peers confirm swaps with a `TConfirmation`, by default the `SwapId` they
+/// assigned to the swap.
 #[derive(Debug)]
-pub struct Announce {
+pub struct Announce<TConfirmation = SwapId> {
+    config: AnnounceConfig,
     /// Pending events to be emitted when polled.
-    events: VecDeque<NetworkBehaviourAction<OutboundConfig, BehaviourOutEvent>>,
+    events: VecDeque<
+        NetworkBehaviourAction<HandlerInEvent<TConfirmation>, BehaviourOutEvent<TConfirmation>>,
+    >,
     /// Stores connection state for nodes we connect to.
     connections: HashMap<PeerId, ConnectionState>,
+    /// Addresses we were told to reach peers at, most promising first.
+    address_hints: HashMap<PeerId, VecDeque<Multiaddr>>,
+    /// Announces that have been started but not yet confirmed, failed or
+    /// cancelled.
+    pending: HashMap<(PeerId, SwapDigest), PendingAnnounce<TConfirmation>>,
+    /// Failed announces waiting for their backoff to elapse, with the number
+    /// of the retry.
+    retries: DeadlineQueue<((PeerId, SwapDigest), u64)>,
+    /// The number the next retry is scheduled as.
+    next_retry: u64,
+    /// Draws the backoff of every retry.
+    backoff_rng: StdRng,
+    /// Announces that have been sent, counted against
+    /// `AnnounceConfig::max_concurrent_outbound`.
+    outbound: HashSet<(PeerId, SwapDigest)>,
+    /// Announces waiting for fewer to be in flight before they are sent,
+    /// oldest first.
+    deferred: VecDeque<(SwapDigest, DialInformation)>,
+    /// Announces to disconnected peers held for them to reconnect, see
+    /// `AnnounceConfig::resend_on_reconnect`.
+    awaiting_reconnect: HashSet<(PeerId, SwapDigest)>,
+    /// When the announces awaiting a reconnect expire.
+    reconnect_deadlines: DeadlineQueue<(PeerId, SwapDigest)>,
+    /// Announcements received from each peer in the current second.
+    inbound: HashMap<PeerId, InboundWindow>,
+    /// Announces recently confirmed by or to a peer.
+    completed: RecentlyCompleted,
+    /// Swaps announced with `announce_race` and the peers racing to confirm
+    /// them.
+    races: Vec<(SwapDigest, HashSet<PeerId>)>,
+    /// Told once the last pending announce is resolved, `None` unless
+    /// `shutdown` was called.
+    shutting_down: Option<Vec<oneshot::Sender<()>>>,
+    /// How many connections, each with its own handler, are established.
+    established_connections: usize,
+    metrics: AnnounceMetrics,
+    /// Inbound announces not passing it are rejected by the handlers.
+    digest_filter: Option<DigestFilter>,
+}
+
+/// A set of at most `capacity` completed announces, the least recently
+/// inserted one is forgotten first.
+#[derive(Debug)]
+struct RecentlyCompleted {
+    capacity: usize,
+    order: VecDeque<(PeerId, SwapDigest)>,
+    entries: HashSet<(PeerId, SwapDigest)>,
+}
+
+impl RecentlyCompleted {
+    fn new(capacity: usize) -> Self {
+        RecentlyCompleted {
+            capacity,
+            order: VecDeque::with_capacity(capacity),
+            entries: HashSet::with_capacity(capacity),
+        }
+    }
+
+    fn contains(&self, peer: &PeerId, swap_digest: &SwapDigest) -> bool {
+        self.entries.contains(&(peer.clone(), swap_digest.clone()))
+    }
+
+    /// Remembers the announce, returning whether it already was.
+    fn insert(&mut self, peer: PeerId, swap_digest: SwapDigest) -> bool {
+        if self.capacity == 0 {
+            return false;
+        }
+
+        let key = (peer, swap_digest);
+        if self.entries.contains(&key) {
+            // Refresh it, so it is forgotten last.
+            self.order.retain(|entry| *entry != key);
+            self.order.push_back(key);
+            return true;
+        }
+
+        if self.order.len() == self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.entries.insert(key.clone());
+        self.order.push_back(key);
+
+        false
+    }
+
+    fn remove(&mut self, peer: &PeerId, swap_digest: &SwapDigest) {
+        let key = (peer.clone(), swap_digest.clone());
+        if self.entries.remove(&key) {
+            self.order.retain(|entry| *entry != key);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct InboundWindow {
+    start: Instant,
+    count: u32,
+}
+
+#[derive(Debug)]
+struct PendingAnnounce<TConfirmation> {
+    /// Used to dial the peer again when the announce is retried.
+    address_hint: Option<Multiaddr>,
+    /// How many times the announce has been retried so far.
+    retries: u32,
+    /// The number of the retry waiting for its backoff to elapse, if any.
+    /// Deadlines of retries scheduled before the announce was cancelled and
+    /// started again do not match it.
+    retry: Option<u64>,
+    /// Given by the application, echoed in the events of the announce.
+    context: Option<u64>,
+    /// Sent along with the announce, see `start_announce_protocol_with_ttl`.
+    ttl: Option<Duration>,
+    /// The handles interested in the outcome of this announce.
+    outcomes: Vec<mpsc::UnboundedSender<(PeerId, AnnounceOutcome<TConfirmation>)>>,
+}
+
+impl<TConfirmation: Clone> PendingAnnounce<TConfirmation> {
+    fn report(self, peer: &PeerId, outcome: AnnounceOutcome<TConfirmation>) {
+        for outcomes in self.outcomes {
+            // The handle not being around any more is fine.
+            let _ = outcomes.unbounded_send((peer.clone(), outcome.clone()));
+        }
+    }
 }
 
 impl Default for Announce {
     fn default() -> Self {
-        Self {
+        Announce::new(AnnounceConfig::default())
+    }
+}
+
+impl Announce {
+    pub fn new(config: AnnounceConfig) -> Self {
+        Announce::with_config(config)
+    }
+}
+
+impl<TConfirmation: Confirmation> Announce<TConfirmation> {
+    /// Like `new`, but peers confirm swaps with a `TConfirmation` rather than
+    /// a `SwapId`.
+    pub fn with_config(config: AnnounceConfig) -> Self {
+        Announce {
             events: VecDeque::new(),
             connections: HashMap::new(),
+            address_hints: HashMap::new(),
+            pending: HashMap::new(),
+            retries: DeadlineQueue::new(),
+            next_retry: 0,
+            backoff_rng: match config.backoff_seed {
+                Some(seed) => StdRng::seed_from_u64(seed),
+                None => StdRng::from_entropy(),
+            },
+            outbound: HashSet::new(),
+            deferred: VecDeque::new(),
+            awaiting_reconnect: HashSet::new(),
+            reconnect_deadlines: DeadlineQueue::new(),
+            inbound: HashMap::new(),
+            completed: RecentlyCompleted::new(config.recently_completed_capacity),
+            races: Vec::new(),
+            shutting_down: None,
+            established_connections: 0,
+            metrics: AnnounceMetrics::default(),
+            digest_filter: None,
+            config,
         }
     }
-}
 
-impl Announce {
+    /// A new behaviour with the same configuration as this one, but none of
+    /// its connections, pending announces or metrics.
+    pub fn clone_config(&self) -> Self {
+        Announce::with_config(self.config.clone())
+    }
+
+    pub fn config(&self) -> &AnnounceConfig {
+        &self.config
+    }
+
+    /// The names of the protocols inbound announces are accepted on, most
+    /// preferred first, as advertised to connecting peers.
+    pub fn supported_protocols(&self) -> Vec<String> {
+        self.handler()
+            .listen_protocol()
+            .upgrade()
+            .protocol_info()
+            .map(|id| String::from_utf8_lossy(id.protocol_name()).into_owned())
+            .collect()
+    }
+
+    /// A handler for a new connection, configured as this behaviour is.
+    fn handler(&self) -> Handler<TConfirmation> {
+        Handler::with_timeout(self.config.timeout)
+            .with_negotiation_timeout(self.config.negotiation_timeout)
+            .with_max_confirmation_size(self.config.max_confirmation_size)
+            .with_max_in_flight(self.config.max_in_flight_per_peer)
+            .with_versions(self.config.versions.clone())
+            .with_keep_alive(self.config.keep_alive)
+            .with_reply_timeout(self.config.reply_timeout)
+            .with_read_timeout(self.config.read_timeout)
+            .with_protocol_prefix(self.config.protocol_prefix.clone())
+            .with_pipelining(self.config.pipeline_announces)
+            .with_push_confirmations(self.config.push_confirmations)
+            .with_listen(self.config.listen)
+            .with_wire_inspector(self.config.wire_inspector.clone())
+            .with_digest_filter(self.digest_filter.clone())
+    }
+
+    /// Stops accepting new announces and resolves once those already started
+    /// have been confirmed or have failed, including any retries.
+    ///
+    /// The swarm has to be polled for the announces to make progress. Calling
+    /// this again gives another future resolving at the same time.
+    pub fn shutdown(&mut self) -> impl Future<Output = ()> {
+        let (sender, receiver) = oneshot::channel();
+        let waiting = self.shutting_down.get_or_insert_with(Vec::new);
+
+        if self.pending.is_empty() {
+            let _ = sender.send(());
+        } else {
+            waiting.push(sender);
+        }
+
+        // Resolves as well if the behaviour is dropped first.
+        receiver.map(|_| ())
+    }
+
     /// Start the announce protocol.
     ///
     /// This is the entry point for Alice when wishing to start the announce
@@ -56,8 +628,295 @@ impl Announce {
     ///
     /// * `swap_digest` - The swap to announce.
     /// * `dial_info` - The `PeerId` and address hint to dial to Bob's node.
-    pub fn start_announce_protocol(&mut self, swap_digest: SwapDigest, dial_info: DialInformation) {
+    ///
+    /// Announcing a swap to a peer it is already being announced to does not
+    /// start a second announce, the confirmation is reported only once.
+    ///
+    /// The outcome is reported through the events of the behaviour, the
+    /// returned handle can be awaited instead. Dropping it is fine.
+    ///
+    /// Fails without announcing anything if the address hint cannot be
+    /// dialed, too many announces to the peer are under way already or the
+    /// behaviour is shutting down. Failures after that are reported as
+    /// events.
+    pub fn start_announce_protocol(
+        &mut self,
+        swap_digest: SwapDigest,
+        dial_info: DialInformation,
+    ) -> Result<AnnounceHandle<TConfirmation>, StartAnnounceError> {
+        self.announce_with_context(swap_digest, dial_info, None, None)
+    }
+
+    /// Like `start_announce_protocol`, but the `ReceivedConfirmation`,
+    /// `DialFailed` and `AnnounceFailed` events of the announce carry
+    /// `context`, which saves keeping a map from digests to application
+    /// state.
+    ///
+    /// If the swap is already being announced to the peer the context given
+    /// first is the one reported.
+    pub fn start_announce_protocol_with_context(
+        &mut self,
+        swap_digest: SwapDigest,
+        dial_info: DialInformation,
+        context: u64,
+    ) -> Result<AnnounceHandle<TConfirmation>, StartAnnounceError> {
+        self.announce_with_context(swap_digest, dial_info, Some(context), None)
+    }
+
+    /// Like `start_announce_protocol`, but tells the peer that the swap has
+    /// to be acted on within `ttl`, in the `ttl` of its
+    /// `ReceivedAnnouncement` event.
+    ///
+    /// Only `ProtocolVersion::V2` carries the time-to-live, with any other
+    /// version the swap is announced without it. If the swap is already
+    /// being announced to the peer the time-to-live given first is the one
+    /// sent.
+    pub fn start_announce_protocol_with_ttl(
+        &mut self,
+        swap_digest: SwapDigest,
+        dial_info: DialInformation,
+        ttl: Duration,
+    ) -> Result<AnnounceHandle<TConfirmation>, StartAnnounceError> {
+        self.announce_with_context(swap_digest, dial_info, None, Some(ttl))
+    }
+
+    /// Like `start_announce_protocol`, but the peer is named by the `/p2p/`
+    /// component at the end of `address`, which is dialed without it.
+    ///
+    /// Fails with `StartAnnounceError::NoPeerId` if `address` does not end
+    /// with a peer id.
+    pub fn announce_to_multiaddr(
+        &mut self,
+        swap_digest: SwapDigest,
+        address: Multiaddr,
+    ) -> Result<AnnounceHandle<TConfirmation>, StartAnnounceError> {
+        let mut address_hint = address.clone();
+        let peer_id = match address_hint.pop() {
+            Some(Protocol::P2p(multihash)) => PeerId::from_multihash(multihash)
+                .map_err(|_| StartAnnounceError::NoPeerId(address.clone()))?,
+            _ => return Err(StartAnnounceError::NoPeerId(address)),
+        };
+        if !is_dialable(&address_hint) {
+            return Err(InvalidAddressHint(address).into());
+        }
+
+        let dial_info = DialInformation {
+            peer_id,
+            address_hint: Some(address_hint),
+        };
+        self.announce_with_context(swap_digest, dial_info, None, None)
+    }
+
+    /// Like `start_announce_protocol`, but for a swap that may have been
+    /// confirmed by the peer before, e.g. to announce changed parameters in
+    /// another round. The earlier confirmation is forgotten, so the new one
+    /// is reported even with `AnnounceConfig::recently_completed_capacity`.
+    ///
+    /// An announce of the swap to the peer that is still under way is joined,
+    /// its confirmation is a fresh one already. A peer that remembers
+    /// completed announces itself drops the announcement unanswered.
+    pub fn force_reannounce(
+        &mut self,
+        swap_digest: SwapDigest,
+        dial_info: DialInformation,
+    ) -> Result<AnnounceHandle<TConfirmation>, StartAnnounceError> {
+        let peer_id = dial_info.peer_id.clone();
+        let handle = self.announce_with_context(swap_digest.clone(), dial_info, None, None)?;
+        self.completed.remove(&peer_id, &swap_digest);
+
+        Ok(handle)
+    }
+
+    fn announce_with_context(
+        &mut self,
+        swap_digest: SwapDigest,
+        dial_info: DialInformation,
+        context: Option<u64>,
+        ttl: Option<Duration>,
+    ) -> Result<AnnounceHandle<TConfirmation>, StartAnnounceError> {
+        if self.shutting_down.is_some() {
+            return Err(StartAnnounceError::ShuttingDown);
+        }
+        dial_info.validate()?;
+        self.check_connected(&dial_info)?;
+        self.check_queue(&swap_digest, &dial_info)?;
+
+        let (sender, receiver) = mpsc::unbounded();
+
+        self.announce(swap_digest.clone(), dial_info, context, ttl, sender);
+
+        Ok(AnnounceHandle {
+            swap_digest,
+            outcome: receiver,
+        })
+    }
+
+    /// Fails if the peer would have to be dialed but dialing is disabled.
+    fn check_connected(&self, dial_info: &DialInformation) -> Result<(), StartAnnounceError> {
+        if self.config.dial_peers || self.is_connected(&dial_info.peer_id) {
+            return Ok(());
+        }
+
+        Err(StartAnnounceError::NotConnected(dial_info.peer_id.clone()))
+    }
+
+    /// Fails if announcing the swap would take the number of announces under
+    /// way to the peer over the limit. Joining an announce of the same swap
+    /// does not count.
+    fn check_queue(
+        &self,
+        swap_digest: &SwapDigest,
+        dial_info: &DialInformation,
+    ) -> Result<(), StartAnnounceError> {
+        let peer_id = &dial_info.peer_id;
+        if self.pending.contains_key(&(peer_id.clone(), swap_digest.clone())) {
+            return Ok(());
+        }
+
+        let under_way = self.pending.keys().filter(|(peer, _)| peer == peer_id).count();
+        if under_way >= self.config.max_in_flight_per_peer {
+            return Err(StartAnnounceError::QueueFull(peer_id.clone()));
+        }
+
+        Ok(())
+    }
+
+    /// Starts announcing the swap to the peer unless that is already under
+    /// way, `outcomes` is told how it went.
+    fn announce(
+        &mut self,
+        swap_digest: SwapDigest,
+        dial_info: DialInformation,
+        context: Option<u64>,
+        ttl: Option<Duration>,
+        outcomes: mpsc::UnboundedSender<(PeerId, AnnounceOutcome<TConfirmation>)>,
+    ) {
+        if let Some(pending) = self
+            .pending
+            .get_mut(&(dial_info.peer_id.clone(), swap_digest.clone()))
+        {
+            pending.outcomes.push(outcomes);
+            if let Some(address) = dial_info.address_hint {
+                pending.address_hint = Some(address.clone());
+                self.add_address_hint(dial_info.peer_id, address);
+            }
+            return;
+        }
+
+        self.pending.insert(
+            (dial_info.peer_id.clone(), swap_digest.clone()),
+            PendingAnnounce {
+                address_hint: dial_info.address_hint.clone(),
+                retries: 0,
+                retry: None,
+                context,
+                ttl,
+                outcomes: vec![outcomes],
+            },
+        );
+
+        if !self.is_connected(&dial_info.peer_id) {
+            self.events.push_back(NetworkBehaviourAction::GenerateEvent(
+                BehaviourOutEvent::AnnounceQueued {
+                    peer: dial_info.peer_id.clone(),
+                    digest: swap_digest.clone(),
+                },
+            ));
+        }
+
+        self.send_announce(swap_digest, dial_info);
+    }
+
+    /// Only accepts inbound announces of swaps `filter` returns true for.
+    ///
+    /// The others are rejected with `RejectReason::UnknownDigest` as soon as
+    /// they are read, without a `ReceivedAnnouncement` event. Replaces any
+    /// filter set before, on existing connections too.
+    pub fn set_digest_filter(&mut self, filter: impl Fn(&SwapDigest) -> bool + Send + Sync + 'static) {
+        let filter = DigestFilter::new(filter);
+
+        for (peer_id, connection) in &self.connections {
+            if let ConnectionState::Connected { .. } = connection {
+                self.events.push_back(NetworkBehaviourAction::NotifyHandler {
+                    peer_id: peer_id.clone(),
+                    handler: NotifyHandler::All,
+                    event: HandlerInEvent::SetDigestFilter(filter.clone()),
+                });
+            }
+        }
+        self.digest_filter = Some(filter);
+    }
+
+    /// Counts an announcement from the peer, returning whether it is over
+    /// the limit. The first announcement over the limit makes the peer's
+    /// handlers refuse inbound substreams until the second is over.
+    fn inbound_rate_limited(&mut self, peer: &PeerId) -> bool {
+        let max = match self.config.max_inbound_per_peer_per_second {
+            Some(max) => max,
+            None => return false,
+        };
+
+        let now = Instant::now();
+        let window = self.inbound.entry(peer.clone()).or_insert(InboundWindow {
+            start: now,
+            count: 0,
+        });
+        if now.duration_since(window.start) >= Duration::from_secs(1) {
+            window.start = now;
+            window.count = 0;
+        }
+
+        window.count += 1;
+        if window.count <= max {
+            return false;
+        }
+
+        if window.count == max + 1 {
+            self.events.push_back(NetworkBehaviourAction::NotifyHandler {
+                peer_id: peer.clone(),
+                handler: NotifyHandler::All,
+                event: HandlerInEvent::RefuseInboundUntil(window.start + Duration::from_secs(1)),
+            });
+            self.events.push_back(NetworkBehaviourAction::GenerateEvent(
+                BehaviourOutEvent::InboundRateLimited { peer: peer.clone() },
+            ));
+        }
+
+        true
+    }
+
+    /// Sends the announce to the peer, dialing it first if necessary.
+    fn send_announce(&mut self, swap_digest: SwapDigest, dial_info: DialInformation) {
+        let key = (dial_info.peer_id.clone(), swap_digest.clone());
+        if matches!(self.config.max_concurrent_outbound, Some(max) if self.outbound.len() >= max) {
+            // Peers not connected to were told about the announce being
+            // queued when it was started.
+            if self.is_connected(&dial_info.peer_id) && !self.is_retry(&key) {
+                self.events.push_back(NetworkBehaviourAction::GenerateEvent(
+                    BehaviourOutEvent::AnnounceQueued {
+                        peer: dial_info.peer_id.clone(),
+                        digest: swap_digest.clone(),
+                    },
+                ));
+            }
+            self.deferred.push_back((swap_digest, dial_info));
+            return;
+        }
+        let mut config = OutboundConfig::new(swap_digest.clone());
+        config.ttl = self.pending.get(&key).and_then(|pending| pending.ttl);
+        self.outbound.insert(key);
 
+        self.metrics.inc_announces_sent();
+
+        if let Some(address) = dial_info.address_hint {
+            self.add_address_hint(dial_info.peer_id.clone(), address);
+        }
+
+        // A retry after the peer disconnected.
+        if !self.config.dial_peers && !self.connections.contains_key(&dial_info.peer_id) {
+            self.announce_failed(dial_info.peer_id, swap_digest, Failure::Dial);
+            return;
+        }
 
         match self.connections.entry(dial_info.peer_id.clone()) {
             Entry::Vacant(entry) => {
@@ -66,116 +925,702 @@ impl Announce {
                     condition: Default::default(),
                 });
 
-                let mut address_hints = VecDeque::new();
-                if let Some(address) = dial_info.address_hint {
-                    address_hints.push_back(address);
-                }
-
-                let pending_events = vec![OutboundConfig::new(swap_digest)];
+                let pending_events = vec![config];
 
-                entry.insert(ConnectionState::Connecting {
-                    pending_events,
-                    address_hints,
-                });
+                entry.insert(ConnectionState::Connecting { pending_events });
             }
             Entry::Occupied(mut entry) => {
                 let connection_state = entry.get_mut();
 
                 match connection_state {
-                    ConnectionState::Connecting {
-                        pending_events,
-                        address_hints,
-                    } => {
-                        pending_events.push(OutboundConfig::new(swap_digest));
-                        if let Some(address) = dial_info.address_hint {
-                            // We push to the front because we consider the new address to be the
-                            // most likely one to succeed. The order of this queue is important
-                            // when returning it from `addresses_of_peer()` because it will be tried
-                            // by libp2p in the returned order.
-                            address_hints.push_front(address);
-                        }
+                    ConnectionState::Connecting { pending_events } => {
+                        pending_events.push(config);
                     }
                     ConnectionState::Connected { .. } => {
                         self.events
                             .push_back(NetworkBehaviourAction::NotifyHandler {
                                 peer_id: dial_info.peer_id.clone(),
                                 handler: NotifyHandler::Any,
-                                event: OutboundConfig::new(swap_digest),
+                                event: HandlerInEvent::Announce(config),
                             });
                     }
                 }
             }
         }
     }
-}
 
-impl NetworkBehaviour for Announce {
-    type ProtocolsHandler = Handler;
-    type OutEvent = BehaviourOutEvent;
-
-    fn new_handler(&mut self) -> Self::ProtocolsHandler {
-        Handler::default()
+    /// Whether the announce failed before and is being retried.
+    fn is_retry(&self, key: &(PeerId, SwapDigest)) -> bool {
+        matches!(self.pending.get(key), Some(pending) if pending.retries > 0)
     }
 
-    fn addresses_of_peer(&mut self, peer_id: &PeerId) -> Vec<Multiaddr> {
-        self.connections
-            .iter()
-            .find_map(|(candidate, addresses)| {
-                if candidate == peer_id {
-                    Some(addresses)
-                } else {
-                    None
-                }
-            })
-            .map(|connection_state| match connection_state {
-                ConnectionState::Connecting { address_hints, .. } => {
-                    let addresses: Vec<Multiaddr> = address_hints.clone().into();
-                    addresses
-                }
-                ConnectionState::Connected { addresses } => addresses.iter().cloned().collect(),
-            })
-            .unwrap_or_else(Vec::new)
-    }
+    /// Frees the slots of announces that are no longer in flight and sends
+    /// deferred announces in their place.
+    fn send_deferred(&mut self) {
+        let pending = &self.pending;
+        self.outbound.retain(|key| pending.contains_key(key));
 
-    fn inject_connected(&mut self, _: &PeerId) {}
+        while !matches!(self.config.max_concurrent_outbound, Some(max) if self.outbound.len() >= max) {
+            let (swap_digest, dial_info) = match self.deferred.pop_front() {
+                Some(deferred) => deferred,
+                None => break,
+            };
+            let key = (dial_info.peer_id.clone(), swap_digest.clone());
+            // Cancelled while deferred, or started again and already sent, or
+            // waiting for the peer to reconnect.
+            if !self.pending.contains_key(&key)
+                || self.outbound.contains(&key)
+                || self.awaiting_reconnect.contains(&key)
+            {
+                continue;
+            }
 
-    fn inject_disconnected(&mut self, _: &PeerId) {}
+            self.send_announce(swap_digest, dial_info);
+        }
+    }
 
-    fn inject_connection_established(
+    /// Announce the swap to each of `peers`.
+    ///
+    /// Every peer's confirmation is reported as a separate
+    /// `ReceivedConfirmation` event, just like with `start_announce_protocol`.
+    /// The returned handle additionally yields the outcome for each peer.
+    ///
+    /// Fails without announcing anything if any of the address hints cannot
+    /// be dialed, too many announces to any of the peers are under way
+    /// already or the behaviour is shutting down.
+    pub fn announce_to_many(
         &mut self,
-        peer_id: &PeerId,
-        _: &ConnectionId,
-        endpoint: &ConnectedPoint,
-    ) {
+        swap_digest: SwapDigest,
+        peers: Vec<DialInformation>,
+    ) -> Result<BroadcastHandle<TConfirmation>, StartAnnounceError> {
+        if self.shutting_down.is_some() {
+            return Err(StartAnnounceError::ShuttingDown);
+        }
+        for dial_info in peers.iter() {
+            dial_info.validate()?;
+            self.check_connected(dial_info)?;
+            self.check_queue(&swap_digest, dial_info)?;
+        }
 
+        let (sender, receiver) = mpsc::unbounded();
 
-        let address = match endpoint {
-            ConnectedPoint::Dialer { address } => address,
-            ConnectedPoint::Listener { send_back_addr, .. } => send_back_addr,
-        };
+        for dial_info in peers {
+            self.announce(swap_digest.clone(), dial_info, None, None, sender.clone());
+        }
 
-        match self.connections.entry(peer_id.clone()) {
-            Entry::Occupied(entry) => {
-                let connection_state = entry.remove();
+        Ok(BroadcastHandle {
+            swap_digest,
+            outcomes: receiver,
+        })
+    }
 
-                match connection_state {
-                    ConnectionState::Connected { mut addresses } => {
-                        addresses.insert(address.clone());
-                        self.connections
-                            .insert(peer_id.clone(), ConnectionState::Connected { addresses });
+    /// Announce the swap to each of `peers`, but settle for the first one to
+    /// confirm it.
+    ///
+    /// Once a peer confirmed, the announces to the others are cancelled and
+    /// a `RaceLost` event is emitted for each of them. That includes
+    /// announces of the swap to them that were started otherwise. The
+    /// returned handle resolves with the winner.
+    ///
+    /// Fails like `announce_to_many`.
+    pub fn announce_race(
+        &mut self,
+        swap_digest: SwapDigest,
+        peers: Vec<DialInformation>,
+    ) -> Result<AnnounceRaceHandle<TConfirmation>, StartAnnounceError> {
+        let racing = peers.iter().map(|dial_info| dial_info.peer_id.clone()).collect();
+        let handle = self.announce_to_many(swap_digest.clone(), peers)?;
+        self.races.push((swap_digest, racing));
+
+        Ok(AnnounceRaceHandle {
+            broadcast: handle,
+            failure: None,
+        })
+    }
+
+    /// Cancels the announces that lost a race to `winner`, and forgets the
+    /// races nobody can win anymore.
+    fn race_won(&mut self, winner: &PeerId, swap_digest: &SwapDigest) {
+        let pending = &self.pending;
+        let mut losers = Vec::new();
+
+        self.races.retain(|(digest, racing)| {
+            if digest == swap_digest && racing.contains(winner) {
+                losers.extend(racing.iter().filter(|peer| *peer != winner).cloned());
+                return false;
+            }
+
+            racing
+                .iter()
+                .any(|peer| pending.contains_key(&(peer.clone(), digest.clone())))
+        });
+
+        for peer in losers {
+            if self.cancel(&peer, swap_digest) {
+                self.events.push_back(NetworkBehaviourAction::GenerateEvent(
+                    BehaviourOutEvent::RaceLost {
+                        peer,
+                        digest: swap_digest.clone(),
+                    },
+                ));
+            }
+        }
+    }
+
+    /// Remembers addresses of the peer learned elsewhere, e.g. from the
+    /// listen addresses `identify` reports, so announces to it need no
+    /// address hint. They are tried after any address hints given to
+    /// `start_announce_protocol`, addresses that cannot be dialed are
+    /// ignored.
+    pub fn inject_identified_addresses(&mut self, peer: PeerId, addresses: Vec<Multiaddr>) {
+        let address_hints = self.address_hints.entry(peer).or_default();
+
+        for address in addresses {
+            if is_dialable(&address) && !address_hints.contains(&address) {
+                address_hints.push_back(address);
+            }
+        }
+    }
+
+    fn add_address_hint(&mut self, peer_id: PeerId, address: Multiaddr) {
+        let address_hints = self.address_hints.entry(peer_id).or_default();
+
+        // We push to the front because we consider the new address to be the
+        // most likely one to succeed. The order of this queue is important
+        // when returning it from `addresses_of_peer()` because it will be tried
+        // by libp2p in the returned order.
+        address_hints.retain(|hint| hint != &address);
+        address_hints.push_front(address);
+    }
+
+    /// Replaces the old address of a connection to the peer with its new
+    /// one, e.g. after a NAT rebinding, so the peer is dialed at the new
+    /// address from now on, including by retries.
+    ///
+    /// Mirrors `NetworkBehaviour::inject_address_change` of later libp2p
+    /// versions. The swarm of libp2p 0.18 does not report address changes,
+    /// whoever learns of one has to call this.
+    pub fn inject_address_change(
+        &mut self,
+        peer_id: &PeerId,
+        _: &ConnectionId,
+        old: &ConnectedPoint,
+        new: &ConnectedPoint,
+    ) {
+        let (old, new) = match (old, new) {
+            (ConnectedPoint::Dialer { address: old }, ConnectedPoint::Dialer { address: new }) => (old, new),
+            (
+                ConnectedPoint::Listener { send_back_addr: old, .. },
+                ConnectedPoint::Listener { send_back_addr: new, .. },
+            ) => (old, new),
+            // A connection does not change which side dialed it.
+            _ => return,
+        };
+
+        if let Some(ConnectionState::Connected { addresses }) = self.connections.get_mut(peer_id) {
+            if addresses.remove(old) {
+                addresses.insert(new.clone());
+            }
+        }
+
+        let hinted = self
+            .address_hints
+            .get_mut(peer_id)
+            .map(|address_hints| {
+                let hints = address_hints.len();
+                address_hints.retain(|hint| hint != old);
+                address_hints.len() != hints
+            })
+            .unwrap_or(false);
+        if !hinted {
+            return;
+        }
+        self.add_address_hint(peer_id.clone(), new.clone());
+
+        for ((peer, _), pending) in self.pending.iter_mut() {
+            if peer == peer_id && pending.address_hint.as_ref() == Some(old) {
+                pending.address_hint = Some(new.clone());
+            }
+        }
+    }
+
+    /// A snapshot of the counters describing this behaviour's activity.
+    pub fn metrics(&self) -> AnnounceMetrics {
+        self.metrics.clone()
+    }
+
+    /// Announces that have been started but not yet confirmed or failed.
+    ///
+    /// Announces waiting for a connection, for a confirmation or for their
+    /// retry backoff to elapse are all included.
+    pub fn pending_announcements(&self) -> impl Iterator<Item = (&PeerId, &SwapDigest)> {
+        self.pending.keys().map(|(peer, digest)| (peer, digest))
+    }
+
+    /// Takes the events waiting to be emitted, in order, without polling.
+    /// Dials and notifications of handlers stay queued for `poll`.
+    #[cfg(test)]
+    pub(crate) fn drain_events(&mut self) -> Vec<BehaviourOutEvent<TConfirmation>> {
+        let mut events = Vec::new();
+        let mut actions = VecDeque::with_capacity(self.events.len());
+        for action in self.events.drain(..) {
+            match action {
+                NetworkBehaviourAction::GenerateEvent(event) => events.push(event),
+                action => actions.push_back(action),
+            }
+        }
+        self.events = actions;
+
+        events
+    }
+
+    /// Snapshots the pending announces, see `import_state`.
+    pub fn export_state(&self) -> AnnounceState {
+        let announces = self
+            .pending
+            .iter()
+            .map(|((peer, digest), pending)| PendingAnnounceState {
+                peer: peer.clone(),
+                digest: digest.clone(),
+                address_hint: pending.address_hint.clone(),
+                context: pending.context,
+            })
+            .collect();
+
+        AnnounceState { announces }
+    }
+
+    /// Starts the announces of a snapshot taken with `export_state` again,
+    /// e.g. after a restart. They are started like with
+    /// `start_announce_protocol_with_context`, from scratch.
+    ///
+    /// Returns the outcome of starting each announce, in the order of the
+    /// snapshot.
+    pub fn import_state(
+        &mut self,
+        state: AnnounceState,
+    ) -> Vec<Result<AnnounceHandle<TConfirmation>, StartAnnounceError>> {
+        state
+            .announces
+            .into_iter()
+            .map(|announce| {
+                let dial_info = DialInformation {
+                    peer_id: announce.peer,
+                    address_hint: announce.address_hint,
+                };
+                self.announce_with_context(announce.digest, dial_info, announce.context, None)
+            })
+            .collect()
+    }
+
+    /// Whether at least one connection to the peer is established, so an
+    /// announce to it is sent without dialing.
+    pub fn is_connected(&self, peer_id: &PeerId) -> bool {
+        matches!(self.connections.get(peer_id), Some(ConnectionState::Connected { .. }))
+    }
+
+    /// How many handlers the behaviour currently has, one for every
+    /// established connection. Connections are closed once idle, so this
+    /// growing while nothing is announced points at a leak.
+    pub fn active_handler_count(&self) -> usize {
+        self.established_connections
+    }
+
+    /// How many announces are in flight, i.e. the number of
+    /// `pending_announcements`.
+    pub fn in_flight_count(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// The peers whose announcements are currently refused because they
+    /// made more than `AnnounceConfig::max_inbound_per_peer_per_second` in
+    /// the current second, in no particular order.
+    pub fn rate_limited_peers(&self) -> Vec<PeerId> {
+        self.rate_limited_peers_at(Instant::now())
+    }
+
+    /// The peers rate limited at `now`, see `rate_limited_peers`.
+    fn rate_limited_peers_at(&self, now: Instant) -> Vec<PeerId> {
+        let max = match self.config.max_inbound_per_peer_per_second {
+            Some(max) => max,
+            None => return Vec::new(),
+        };
+
+        self.inbound
+            .iter()
+            .filter(|(_, window)| {
+                window.count > max && now.duration_since(window.start) < Duration::from_secs(1)
+            })
+            .map(|(peer, _)| peer.clone())
+            .collect()
+    }
+
+    /// Confirms an announcement received from `peer_id` by pushing `swap_id`
+    /// on a substream we open, for a peer that set
+    /// `AnnounceConfig::push_confirmations`. The `ReplySubstream` of the
+    /// announcement is not needed for that and can be dropped.
+    ///
+    /// `ConfirmationSent` or `ConfirmationSendFailed` report the outcome.
+    /// Returns `false` without pushing anything if we are not connected to
+    /// the peer.
+    pub fn push_confirmation(
+        &mut self,
+        peer_id: PeerId,
+        swap_digest: SwapDigest,
+        swap_id: TConfirmation,
+    ) -> bool {
+        if !self.is_connected(&peer_id) {
+            return false;
+        }
+
+        self.events.push_back(NetworkBehaviourAction::NotifyHandler {
+            peer_id,
+            handler: NotifyHandler::Any,
+            event: HandlerInEvent::PushConfirmation { swap_digest, swap_id },
+        });
+        true
+    }
+
+    /// Cancel all in-flight announces of the swap with this digest.
+    ///
+    /// Queued announces are dropped and handlers that already opened a
+    /// substream for the swap discard its outcome, so no
+    /// `ReceivedConfirmation` is emitted for it. Returns `false` if there was
+    /// nothing to cancel, e.g. because the announce was already confirmed.
+    pub fn cancel_announce(&mut self, swap_digest: &SwapDigest) -> bool {
+        let peers = self
+            .pending
+            .keys()
+            .filter(|(_, digest)| digest == swap_digest)
+            .map(|(peer, _)| peer.clone())
+            .collect::<Vec<_>>();
+
+        for peer in peers.iter() {
+            self.cancel(peer, swap_digest);
+        }
+
+        !peers.is_empty()
+    }
+
+    /// Forgets everything we know about the peer, e.g. once it is
+    /// blacklisted: its pending announces are cancelled as by
+    /// `cancel_announce`, and its address hints and the count of
+    /// announcements it made against the inbound rate limit are dropped.
+    ///
+    /// Returns how many pending announces were cancelled.
+    pub fn remove_peer(&mut self, peer: &PeerId) -> usize {
+        let swap_digests = self
+            .pending
+            .keys()
+            .filter(|(pending_peer, _)| pending_peer == peer)
+            .map(|(_, digest)| digest.clone())
+            .collect::<Vec<_>>();
+
+        for swap_digest in swap_digests.iter() {
+            self.cancel(peer, swap_digest);
+        }
+
+        self.address_hints.remove(peer);
+        self.inbound.remove(peer);
+
+        swap_digests.len()
+    }
+
+    /// Cancels the announce of the swap to the peer, returning whether there
+    /// was one.
+    fn cancel(&mut self, peer: &PeerId, swap_digest: &SwapDigest) -> bool {
+        let key = (peer.clone(), swap_digest.clone());
+        if self.pending.remove(&key).is_none() {
+            return false;
+        }
+        self.awaiting_reconnect.remove(&key);
+
+        match self.connections.get_mut(peer) {
+            Some(ConnectionState::Connecting { pending_events, .. }) => {
+                pending_events.retain(|config| &config.swap_digest != swap_digest);
+            }
+            Some(ConnectionState::Connected { .. }) => {
+                self.events.retain_mut(|action| match action {
+                    NetworkBehaviourAction::NotifyHandler {
+                        peer_id,
+                        event: HandlerInEvent::Announce(config),
+                        ..
+                    } => peer_id != peer || &config.swap_digest != swap_digest,
+                    NetworkBehaviourAction::NotifyHandler {
+                        peer_id,
+                        event: HandlerInEvent::AnnounceAll(configs),
+                        ..
+                    } if peer_id == peer => {
+                        configs.retain(|config| &config.swap_digest != swap_digest);
+                        !configs.is_empty()
+                    }
+                    _ => true,
+                });
+                self.events.push_back(NetworkBehaviourAction::NotifyHandler {
+                    peer_id: peer.clone(),
+                    handler: NotifyHandler::All,
+                    event: HandlerInEvent::Cancel(swap_digest.clone()),
+                });
+            }
+            None => {}
+        }
+
+        true
+    }
+
+    /// Sends the retries whose backoff has elapsed.
+    fn send_due_retries(&mut self, cx: &mut Context<'_>) {
+        while let Poll::Ready(((peer_id, swap_digest), retry)) = self.retries.poll_expired(cx) {
+            // Cancelled, and maybe started again since.
+            let address_hint = match self.pending.get_mut(&(peer_id.clone(), swap_digest.clone())) {
+                Some(pending) if pending.retry == Some(retry) => {
+                    pending.retry = None;
+                    pending.address_hint.clone()
+                }
+                _ => continue,
+            };
+
+            self.send_announce(swap_digest, DialInformation { peer_id, address_hint });
+        }
+    }
+
+    /// Fails an announce whose substream could not be upgraded, or holds it
+    /// for the peer to reconnect if the substream went away. The connection
+    /// may be closing then, with the disconnect still to come.
+    fn upgrade_failed(&mut self, peer: PeerId, swap_digest: SwapDigest, lost_substream: bool) {
+        self.metrics.inc_upgrade_errors();
+        match self.config.resend_on_reconnect {
+            Some(window) if lost_substream => self.hold_for_reconnect((peer, swap_digest), window),
+            _ => self.announce_failed(peer, swap_digest, Failure::Announce),
+        }
+    }
+
+    /// Holds a pending announce for the peer to reconnect within `window`,
+    /// unless it is held already. The reconnect resends it, not a retry.
+    fn hold_for_reconnect(&mut self, key: (PeerId, SwapDigest), window: Duration) {
+        self.outbound.remove(&key);
+        match self.pending.get_mut(&key) {
+            Some(pending) => pending.retry = None,
+            None => return,
+        }
+
+        if self.awaiting_reconnect.insert(key.clone()) {
+            self.reconnect_deadlines.insert(key, Instant::now() + window);
+        }
+    }
+
+    /// Schedules a retry of a failed announce, or reports it as failed once
+    /// it ran out of retries.
+    fn announce_failed(&mut self, peer: PeerId, swap_digest: SwapDigest, failure: Failure) {
+        let key = (peer, swap_digest);
+
+        // Retried or not, the announce is not in flight any more.
+        self.outbound.remove(&key);
+
+        let pending = match self.pending.get_mut(&key) {
+            Some(pending) => pending,
+            // The announce was cancelled in the meantime.
+            None => return,
+        };
+
+        if pending.retries < self.config.max_retries {
+            let backoff = jittered_backoff(
+                &mut self.backoff_rng,
+                self.config.base_backoff,
+                self.config.max_backoff,
+                pending.retries,
+            );
+            pending.retries += 1;
+            pending.retry = Some(self.next_retry);
+
+            self.retries.insert((key, self.next_retry), Instant::now() + backoff);
+            self.next_retry += 1;
+            return;
+        }
+
+        let context = pending.context;
+        let (outcome, event) = match failure {
+            Failure::Dial => (
+                AnnounceOutcome::DialFailed,
+                BehaviourOutEvent::DialFailed {
+                    peer: key.0.clone(),
+                    digest: key.1.clone(),
+                    context,
+                },
+            ),
+            Failure::Announce => (
+                AnnounceOutcome::Failed,
+                BehaviourOutEvent::AnnounceFailed {
+                    peer: key.0.clone(),
+                    digest: key.1.clone(),
+                    context,
+                },
+            ),
+        };
+
+        if let Some(pending) = self.pending.remove(&key) {
+            pending.report(&key.0, outcome);
+        }
+
+        self.events
+            .push_back(NetworkBehaviourAction::GenerateEvent(event));
+    }
+}
+
+impl<TConfirmation: Confirmation> NetworkBehaviour for Announce<TConfirmation> {
+    type ProtocolsHandler = Handler<TConfirmation>;
+    type OutEvent = BehaviourOutEvent<TConfirmation>;
+
+    fn new_handler(&mut self) -> Self::ProtocolsHandler {
+        self.handler()
+    }
+
+    fn addresses_of_peer(&mut self, peer_id: &PeerId) -> Vec<Multiaddr> {
+        let mut addresses: Vec<Multiaddr> = self
+            .address_hints
+            .get(peer_id)
+            .map(|address_hints| address_hints.iter().cloned().collect())
+            .unwrap_or_default();
+
+        if let Some(ConnectionState::Connected {
+            addresses: connected,
+        }) = self.connections.get(peer_id)
+        {
+            for address in connected {
+                if !addresses.contains(address) {
+                    addresses.push(address.clone());
+                }
+            }
+        }
+
+        addresses
+    }
+
+    fn inject_addr_reach_failure(
+        &mut self,
+        peer_id: Option<&PeerId>,
+        address: &Multiaddr,
+        _: &dyn std::error::Error,
+    ) {
+        // Don't keep suggesting an address that didn't work.
+        if let Some(address_hints) =
+            peer_id.and_then(|peer_id| self.address_hints.get_mut(peer_id))
+        {
+            address_hints.retain(|hint| hint != address);
+        }
+    }
+
+    fn inject_dial_failure(&mut self, peer_id: &PeerId) {
+        if let Some(ConnectionState::Connecting { pending_events, .. }) =
+            self.connections.remove(peer_id)
+        {
+            for config in pending_events {
+                self.announce_failed(peer_id.clone(), config.swap_digest, Failure::Dial);
+            }
+        }
+    }
+
+    fn inject_connected(&mut self, peer_id: &PeerId) {
+        if self.pending.keys().any(|(peer, _)| peer == peer_id) {
+            self.events.push_back(NetworkBehaviourAction::GenerateEvent(
+                BehaviourOutEvent::PeerConnected {
+                    peer: peer_id.clone(),
+                },
+            ));
+        }
+
+        // The connection is established already, the held announces go out
+        // on it.
+        let reconnected = self
+            .awaiting_reconnect
+            .iter()
+            .filter(|(peer, _)| peer == peer_id)
+            .cloned()
+            .collect::<Vec<_>>();
+        for key in reconnected {
+            self.awaiting_reconnect.remove(&key);
+            let address_hint = match self.pending.get(&key) {
+                Some(pending) => pending.address_hint.clone(),
+                None => continue,
+            };
+
+            let (peer_id, swap_digest) = key;
+            self.send_announce(swap_digest, DialInformation { peer_id, address_hint });
+        }
+    }
+
+    fn inject_disconnected(&mut self, peer_id: &PeerId) {
+        self.inbound.remove(peer_id);
+
+        let swap_digests = self
+            .pending
+            .keys()
+            .filter(|(peer, _)| peer == peer_id)
+            .map(|(_, swap_digest)| swap_digest.clone())
+            .collect::<Vec<_>>();
+        if swap_digests.is_empty() {
+            return;
+        }
+
+        self.events.push_back(NetworkBehaviourAction::GenerateEvent(
+            BehaviourOutEvent::PeerDisconnected {
+                peer: peer_id.clone(),
+            },
+        ));
+
+        // Only the announces in flight went down with the connection, the
+        // retries of those waiting out a backoff still go out.
+        let in_flight = swap_digests
+            .into_iter()
+            .map(|swap_digest| (peer_id.clone(), swap_digest))
+            .filter(|key| self.outbound.contains(key))
+            .collect::<Vec<_>>();
+
+        if let Some(window) = self.config.resend_on_reconnect {
+            for key in in_flight {
+                self.hold_for_reconnect(key, window);
+            }
+            return;
+        }
+
+        // They are retried or failed like any other failed announce.
+        for (peer_id, swap_digest) in in_flight {
+            self.announce_failed(peer_id, swap_digest, Failure::Announce);
+        }
+    }
+
+    fn inject_connection_established(
+        &mut self,
+        peer_id: &PeerId,
+        _: &ConnectionId,
+        endpoint: &ConnectedPoint,
+    ) {
+        self.established_connections += 1;
+
+        let address = match endpoint {
+            ConnectedPoint::Dialer { address } => address,
+            ConnectedPoint::Listener { send_back_addr, .. } => send_back_addr,
+        };
+
+        match self.connections.entry(peer_id.clone()) {
+            Entry::Occupied(entry) => {
+                let connection_state = entry.remove();
+
+                match connection_state {
+                    ConnectionState::Connected { mut addresses } => {
+                        addresses.insert(address.clone());
+                        self.connections
+                            .insert(peer_id.clone(), ConnectionState::Connected { addresses });
                     }
-                    ConnectionState::Connecting {
-                        pending_events,
-                        address_hints: _we_no_longer_care_at_this_stage,
-                    } => {
-                        for event in pending_events {
-                            self.events
-                                .push_back(NetworkBehaviourAction::NotifyHandler {
-                                    peer_id: peer_id.clone(),
-                                    handler: NotifyHandler::Any,
-                                    event,
-                                })
-                        }
+                    ConnectionState::Connecting { pending_events } => {
+                        // Handing them over together lets the handler
+                        // pipeline them.
+                        self.events
+                            .push_back(NetworkBehaviourAction::NotifyHandler {
+                                peer_id: peer_id.clone(),
+                                handler: NotifyHandler::Any,
+                                event: HandlerInEvent::AnnounceAll(pending_events),
+                            });
 
                         let mut addresses = HashSet::new();
                         addresses.insert(address.clone());
@@ -200,7 +1645,7 @@ impl NetworkBehaviour for Announce {
         _: &ConnectionId,
         endpoint: &ConnectedPoint,
     ) {
-
+        self.established_connections -= 1;
 
         let address = match endpoint {
             ConnectedPoint::Dialer { address } => address,
@@ -209,7 +1654,7 @@ impl NetworkBehaviour for Announce {
 
         if let Some(ConnectionState::Connected { mut addresses }) = self.connections.remove(peer_id)
         {
-            addresses.remove(&address);
+            addresses.remove(address);
 
             if !addresses.is_empty() {
                 self.connections
@@ -218,30 +1663,153 @@ impl NetworkBehaviour for Announce {
         }
     }
 
-    fn inject_event(&mut self, peer_id: PeerId, _: ConnectionId, event: HandlerEvent) {
+    fn inject_event(&mut self, peer_id: PeerId, _: ConnectionId, event: HandlerEvent<TConfirmation>) {
         match event {
             HandlerEvent::ReceivedConfirmation(confirmed) => {
+                let context = match self
+                    .pending
+                    .remove(&(peer_id.clone(), confirmed.swap_digest().clone()))
+                {
+                    Some(pending) => {
+                        let context = pending.context;
+                        pending.report(&peer_id, AnnounceOutcome::Confirmed(confirmed.clone()));
+                        context
+                    }
+                    // The announce was cancelled while the confirmation was
+                    // on its way, or the swap was never announced to this
+                    // peer.
+                    None => return,
+                };
+
+                self.metrics.inc_confirmations_received();
+                self.race_won(&peer_id, confirmed.swap_digest());
+                if self
+                    .completed
+                    .insert(peer_id.clone(), confirmed.swap_digest().clone())
+                {
+                    tracing::debug!(
+                        peer = %peer_id,
+                        swap_digest = %confirmed.swap_digest(),
+                        "suppressing replayed confirmation"
+                    );
+                    return;
+                }
                 self.events.push_back(NetworkBehaviourAction::GenerateEvent(
                     BehaviourOutEvent::ReceivedConfirmation {
                         peer: peer_id,
-                        swap_id: confirmed.swap_id,
-                        swap_digest: confirmed.swap_digest,
+                        swap_id: confirmed.swap_id(),
+                        digest: confirmed.swap_digest().clone(),
+                        context,
                     },
                 ));
             }
             HandlerEvent::AwaitingConfirmation(sender) => {
+                if self.inbound_rate_limited(&peer_id) {
+                    sender.drop_unanswered();
+                    return;
+                }
+                if self.completed.contains(&peer_id, &sender.swap_digest) {
+                    tracing::debug!(
+                        peer = %peer_id,
+                        swap_digest = %sender.swap_digest,
+                        "dropping replayed announcement"
+                    );
+                    sender.drop_unanswered();
+                    return;
+                }
+
+                self.metrics.inc_announcements_received();
                 self.events.push_back(NetworkBehaviourAction::GenerateEvent(
                     BehaviourOutEvent::ReceivedAnnouncement {
                         peer: peer_id,
+                        version: sender.version,
+                        ttl: sender.ttl,
                         io: sender,
                     },
                 ));
             }
-            HandlerEvent::Error(error) => {
+            HandlerEvent::Error(Error::Timeout { swap_digest }) => {
+                self.metrics.inc_timeouts();
+                self.announce_failed(peer_id, swap_digest, Failure::Announce);
+            }
+            HandlerEvent::Error(Error::Upgrade { swap_digest, error }) => {
+                let lost_substream = handler::lost_substream(&error);
+                self.upgrade_failed(peer_id, swap_digest, lost_substream);
+            }
+            HandlerEvent::Error(Error::PipelineFailed {
+                swap_digest,
+                lost_substream,
+            }) => self.upgrade_failed(peer_id, swap_digest, lost_substream),
+            HandlerEvent::OutboundSubstreamOpened { swap_digest } => {
+                if !self.pending.contains_key(&(peer_id.clone(), swap_digest.clone())) {
+                    return;
+                }
+                self.events.push_back(NetworkBehaviourAction::GenerateEvent(
+                    BehaviourOutEvent::AnnounceInFlight {
+                        peer: peer_id,
+                        digest: swap_digest,
+                    },
+                ));
+            }
+            HandlerEvent::ConfirmationSent {
+                swap_digest,
+                swap_id,
+            } => {
+                self.completed.insert(peer_id.clone(), swap_digest.clone());
+                self.events.push_back(NetworkBehaviourAction::GenerateEvent(
+                    BehaviourOutEvent::ConfirmationSent {
+                        peer: peer_id,
+                        digest: swap_digest,
+                        swap_id,
+                    },
+                ));
+            }
+            HandlerEvent::Error(Error::ConfirmationSendFailed { swap_digest, .. })
+            | HandlerEvent::Error(Error::PushFailed { swap_digest, .. }) => {
+                self.events.push_back(NetworkBehaviourAction::GenerateEvent(
+                    BehaviourOutEvent::ConfirmationSendFailed {
+                        peer: peer_id,
+                        digest: swap_digest,
+                    },
+                ));
+            }
+            HandlerEvent::Error(Error::ReplyTimeout { swap_digest }) => {
+                self.events.push_back(NetworkBehaviourAction::GenerateEvent(
+                    BehaviourOutEvent::ReplyTimedOut {
+                        peer: peer_id,
+                        digest: swap_digest,
+                    },
+                ));
+            }
+            HandlerEvent::Error(Error::InboundUpgrade(_)) => {
+                self.events.push_back(NetworkBehaviourAction::GenerateEvent(
+                    BehaviourOutEvent::InboundFailed { peer: peer_id },
+                ));
+            }
+            HandlerEvent::Error(Error::Rejected {
+                swap_digest,
+                reason,
+            }) => {
+                // The peer made up its mind, retrying would not change it.
+                if let Some(pending) = self.pending.remove(&(peer_id.clone(), swap_digest.clone())) {
+                    pending.report(&peer_id, AnnounceOutcome::Rejected(reason));
+                }
+                self.events.push_back(NetworkBehaviourAction::GenerateEvent(
+                    BehaviourOutEvent::AnnounceRejected {
+                        peer: peer_id,
+                        digest: swap_digest,
+                        reason,
+                    },
+                ));
+            }
+            HandlerEvent::Error(Error::QueueFull { swap_digest }) => {
+                if let Some(pending) = self.pending.remove(&(peer_id.clone(), swap_digest.clone())) {
+                    pending.report(&peer_id, AnnounceOutcome::Dropped);
+                }
                 self.events.push_back(NetworkBehaviourAction::GenerateEvent(
-                    BehaviourOutEvent::Error {
+                    BehaviourOutEvent::AnnounceDropped {
                         peer: peer_id,
-                        error,
+                        digest: swap_digest,
                     },
                 ));
             }
@@ -250,7 +1818,7 @@ impl NetworkBehaviour for Announce {
 
     fn poll(
         &mut self,
-        _cx: &mut Context<'_>,
+        cx: &mut Context<'_>,
         _params: &mut impl PollParameters,
     ) -> Poll<
         NetworkBehaviourAction<
@@ -258,6 +1826,30 @@ impl NetworkBehaviour for Announce {
             Self::OutEvent,
         >,
     > {
+        self.send_due_retries(cx);
+        while let Poll::Ready(key) = self.reconnect_deadlines.poll_expired(cx) {
+            // Reconnected in time, or cancelled.
+            if !self.awaiting_reconnect.remove(&key) {
+                continue;
+            }
+            if let Some(pending) = self.pending.remove(&key) {
+                pending.report(&key.0, AnnounceOutcome::Failed);
+                let (peer, digest) = key;
+                self.events.push_back(NetworkBehaviourAction::GenerateEvent(
+                    BehaviourOutEvent::AnnounceExpired { peer, digest },
+                ));
+            }
+        }
+        self.send_deferred();
+
+        if self.pending.is_empty() {
+            if let Some(waiting) = self.shutting_down.as_mut() {
+                for sender in waiting.drain(..) {
+                    let _ = sender.send(());
+                }
+            }
+        }
+
         if let Some(event) = self.events.pop_front() {
             return Poll::Ready(event);
         }
@@ -275,44 +1867,1443 @@ enum ConnectionState {
     Connecting {
         // Vec is fine here, we iterate over this to remove items.
         pending_events: Vec<OutboundConfig>,
-        // VecDeque because we push new addresses to the front.
-        address_hints: VecDeque<Multiaddr>,
     },
 }
 
 /// Event emitted  by the `Announce` behaviour.
-#[derive(Debug)]
-pub enum BehaviourOutEvent {
+///
+/// With the `serde` feature the events can be serialized, e.g. for audit
+/// logs. Only the digest and time-to-live of a `ReceivedAnnouncement` are
+/// serialized, it cannot be deserialized as its reply substream is gone.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum BehaviourOutEvent<TConfirmation = SwapId> {
     /// This event created when a confirmation message containing a `swap_id` is
-    /// received in response to an announce messagunlo  e containing a
+    /// received in response to an announce message containing a
     /// `swap_digest`. The Event contains both the swap id and
-    /// the swap digest. The announce message is sent by Alice to Bob.
+    /// the swap digest, which correlates it with the announce it answers.
+    /// The announce message is sent by Alice to Bob.
     ReceivedConfirmation {
-        /// The peer (Bob) that the swap has been announced to.
+        /// The peer (Bob) that the swap has been announced to. The
+        /// confirmation came over a connection authenticated as this peer,
+        /// confirmations from any other peer are dropped.
+        #[cfg_attr(feature = "serde", serde(with = "serde_peer_id"))]
         peer: PeerId,
+        /// The swap digest that was announced.
+        digest: SwapDigest,
         /// The swap_id returned by the peer (Bob).
-        swap_id: SwapId,
-        /// The swap_digest
-        swap_digest: SwapDigest,
+        swap_id: TConfirmation,
+        /// As given to `start_announce_protocol_with_context`.
+        context: Option<u64>,
     },
 
     /// The event is created when a remote sends a `swap_digest`. The event
     /// contains a reply substream for the receiver to send back the
     /// `swap_id` that corresponds to the swap digest. Bob sends the
     /// confirmations message to Alice using the the reply substream.
+    ///
+    /// The announcements of a peer are reported in the order its connection
+    /// received them. Announcements pipelined on one substream, see
+    /// `AnnounceConfig::pipeline_announces`, arrive in the order the peer
+    /// sent them. Substreams of their own are negotiated concurrently, so
+    /// announcements on them may arrive in any order.
     ReceivedAnnouncement {
         /// The peer (Alice) that the reply substream is connected to.
+        #[cfg_attr(feature = "serde", serde(with = "serde_peer_id"))]
         peer: PeerId,
         /// The substream (inc. `swap_digest`) to reply on (i.e., send
         /// `swap_id`).
-        io: Box<ReplySubstream<NegotiatedSubstream>>,
+        #[cfg_attr(
+            feature = "serde",
+            serde(
+                rename = "digest",
+                serialize_with = "serde_reply::serialize",
+                deserialize_with = "serde_reply::deserialize"
+            )
+        )]
+        io: Box<ReplySubstream<NegotiatedSubstream, TConfirmation>>,
+        /// The protocol version Alice announced with, which `io` replies in.
+        /// Two peers on the default configuration negotiate
+        /// `ProtocolVersion::V2`, or `ProtocolVersion::Pipelined` if Alice
+        /// pipelines her announces.
+        version: ProtocolVersion,
+        /// How long Alice can wait for the swap to be acted on, confirm only
+        /// if that is possible. `None` unless the announce was made with
+        /// `ProtocolVersion::V2` and a time-to-live.
+        ttl: Option<Duration>,
     },
 
-    /// Error while attempting to announce swap to the remote.
-    Error {
-        /// The peer with whom the error originated.
+    /// We could not connect to the peer (Bob) to announce the swap to it,
+    /// including any retries configured through `AnnounceConfig::max_retries`.
+    /// Trying again with a different address might help.
+    DialFailed {
+        /// The peer (Bob) the swap was to be announced to.
+        #[cfg_attr(feature = "serde", serde(with = "serde_peer_id"))]
         peer: PeerId,
-        /// The error that occurred.
-        error: handler::Error,
+        /// The swap digest that was not announced.
+        digest: SwapDigest,
+        /// As given to `start_announce_protocol_with_context`.
+        context: Option<u64>,
     },
+
+    /// The announced swap could not be confirmed because the peer (Bob) did
+    /// not speak the protocol or did not reply in time, including any retries
+    /// configured through `AnnounceConfig::max_retries`. The announce can be
+    /// started again by calling `start_announce_protocol`.
+    AnnounceFailed {
+        /// The peer (Bob) that the swap was announced to.
+        #[cfg_attr(feature = "serde", serde(with = "serde_peer_id"))]
+        peer: PeerId,
+        /// The swap digest that was not confirmed.
+        digest: SwapDigest,
+        /// As given to `start_announce_protocol_with_context`.
+        context: Option<u64>,
+    },
+
+    /// The announce was never sent because too many announces to this peer
+    /// were already queued.
+    AnnounceDropped {
+        /// The peer (Bob) the swap was to be announced to.
+        #[cfg_attr(feature = "serde", serde(with = "serde_peer_id"))]
+        peer: PeerId,
+        /// The swap digest that was not announced.
+        digest: SwapDigest,
+    },
+
+    /// The peer (Bob) disconnected while the announce was under way and did
+    /// not reconnect within `AnnounceConfig::resend_on_reconnect`.
+    AnnounceExpired {
+        /// The peer (Bob) the swap was announced to.
+        #[cfg_attr(feature = "serde", serde(with = "serde_peer_id"))]
+        peer: PeerId,
+        /// The swap digest that was not confirmed.
+        digest: SwapDigest,
+    },
+
+    /// The peer (Bob) rejected the announce, it is not retried.
+    AnnounceRejected {
+        /// The peer (Bob) that the swap has been announced to.
+        #[cfg_attr(feature = "serde", serde(with = "serde_peer_id"))]
+        peer: PeerId,
+        /// The swap digest that was announced.
+        digest: SwapDigest,
+        /// Why Bob rejected it.
+        reason: RejectReason,
+    },
+
+    /// An announce was started while the peer is not connected yet, it is
+    /// sent once the connection is established. Emitted before any other
+    /// event of the announce, e.g. to show that the peer is being dialed.
+    ///
+    /// Also emitted for an announce to a connected peer that waits for
+    /// fewer than `AnnounceConfig::max_concurrent_outbound` to be in flight.
+    AnnounceQueued {
+        #[cfg_attr(feature = "serde", serde(with = "serde_peer_id"))]
+        peer: PeerId,
+        digest: SwapDigest,
+    },
+
+    /// Another peer confirmed a swap announced with `announce_race` first,
+    /// the announce to this one was cancelled.
+    RaceLost {
+        #[cfg_attr(feature = "serde", serde(with = "serde_peer_id"))]
+        peer: PeerId,
+        digest: SwapDigest,
+    },
+
+    /// The announce has been sent to the peer over a newly negotiated
+    /// substream and its reply is awaited. Until then the peer is still
+    /// being dialed or the substream negotiated.
+    AnnounceInFlight {
+        #[cfg_attr(feature = "serde", serde(with = "serde_peer_id"))]
+        peer: PeerId,
+        digest: SwapDigest,
+    },
+
+    /// A peer we have pending announces to connected.
+    PeerConnected {
+        #[cfg_attr(feature = "serde", serde(with = "serde_peer_id"))]
+        peer: PeerId,
+    },
+
+    /// A peer we have pending announces to disconnected, the announces are
+    /// retried or fail with `AnnounceFailed`, unless they are held for the
+    /// peer to reconnect.
+    PeerDisconnected {
+        #[cfg_attr(feature = "serde", serde(with = "serde_peer_id"))]
+        peer: PeerId,
+    },
+
+    /// The peer (Alice) sent more announcements than
+    /// `AnnounceConfig::max_inbound_per_peer_per_second` allows. Its further
+    /// announcements are dropped for the rest of the second.
+    InboundRateLimited {
+        #[cfg_attr(feature = "serde", serde(with = "serde_peer_id"))]
+        peer: PeerId,
+    },
+
+    /// Our confirmation of an announcement has been sent and the reply
+    /// substream closed.
+    ConfirmationSent {
+        /// The peer (Alice) that announced the swap.
+        #[cfg_attr(feature = "serde", serde(with = "serde_peer_id"))]
+        peer: PeerId,
+        /// The swap digest that was confirmed.
+        digest: SwapDigest,
+        /// The swap_id we sent back.
+        swap_id: TConfirmation,
+    },
+
+    /// Our confirmation of an announcement could not be sent, the error is
+    /// returned by `ReplySubstream::confirm`.
+    ConfirmationSendFailed {
+        /// The peer (Alice) that announced the swap.
+        #[cfg_attr(feature = "serde", serde(with = "serde_peer_id"))]
+        peer: PeerId,
+        /// The swap digest that was not confirmed.
+        digest: SwapDigest,
+    },
+
+    /// We did not confirm an announcement within
+    /// `AnnounceConfig::reply_timeout`, its reply substream has been dropped.
+    ReplyTimedOut {
+        /// The peer (Alice) that sent the announce.
+        #[cfg_attr(feature = "serde", serde(with = "serde_peer_id"))]
+        peer: PeerId,
+        /// The swap digest that was announced.
+        digest: SwapDigest,
+    },
+
+    /// A peer (Alice) opened an announce substream but did not send a valid
+    /// announce on it.
+    InboundFailed {
+        /// The peer (Alice) that sent the announce.
+        #[cfg_attr(feature = "serde", serde(with = "serde_peer_id"))]
+        peer: PeerId,
+    },
+}
+
+/// Shows digests in their short form, the full ones clutter logs.
+impl<TConfirmation: fmt::Debug> fmt::Debug for BehaviourOutEvent<TConfirmation> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn peer_and_digest(
+            f: &mut fmt::Formatter<'_>,
+            name: &str,
+            peer: &PeerId,
+            digest: &SwapDigest,
+        ) -> fmt::Result {
+            f.debug_struct(name)
+                .field("peer", peer)
+                .field("digest", &format_args!("{}", digest.short()))
+                .finish()
+        }
+
+        match self {
+            BehaviourOutEvent::ReceivedConfirmation {
+                peer,
+                digest,
+                swap_id,
+                context,
+            } => f
+                .debug_struct("ReceivedConfirmation")
+                .field("peer", peer)
+                .field("digest", &format_args!("{}", digest.short()))
+                .field("swap_id", swap_id)
+                .field("context", context)
+                .finish(),
+            BehaviourOutEvent::ReceivedAnnouncement {
+                peer,
+                io,
+                version,
+                ttl,
+            } => f
+                .debug_struct("ReceivedAnnouncement")
+                .field("peer", peer)
+                .field("digest", &format_args!("{}", io.swap_digest.short()))
+                .field("version", version)
+                .field("ttl", ttl)
+                .finish(),
+            BehaviourOutEvent::DialFailed {
+                peer,
+                digest,
+                context,
+            } => f
+                .debug_struct("DialFailed")
+                .field("peer", peer)
+                .field("digest", &format_args!("{}", digest.short()))
+                .field("context", context)
+                .finish(),
+            BehaviourOutEvent::AnnounceFailed {
+                peer,
+                digest,
+                context,
+            } => f
+                .debug_struct("AnnounceFailed")
+                .field("peer", peer)
+                .field("digest", &format_args!("{}", digest.short()))
+                .field("context", context)
+                .finish(),
+            BehaviourOutEvent::AnnounceDropped { peer, digest } => {
+                peer_and_digest(f, "AnnounceDropped", peer, digest)
+            }
+            BehaviourOutEvent::AnnounceExpired { peer, digest } => {
+                peer_and_digest(f, "AnnounceExpired", peer, digest)
+            }
+            BehaviourOutEvent::AnnounceRejected {
+                peer,
+                digest,
+                reason,
+            } => f
+                .debug_struct("AnnounceRejected")
+                .field("peer", peer)
+                .field("digest", &format_args!("{}", digest.short()))
+                .field("reason", reason)
+                .finish(),
+            BehaviourOutEvent::AnnounceQueued { peer, digest } => {
+                peer_and_digest(f, "AnnounceQueued", peer, digest)
+            }
+            BehaviourOutEvent::RaceLost { peer, digest } => {
+                peer_and_digest(f, "RaceLost", peer, digest)
+            }
+            BehaviourOutEvent::AnnounceInFlight { peer, digest } => {
+                peer_and_digest(f, "AnnounceInFlight", peer, digest)
+            }
+            BehaviourOutEvent::PeerConnected { peer } => {
+                f.debug_struct("PeerConnected").field("peer", peer).finish()
+            }
+            BehaviourOutEvent::PeerDisconnected { peer } => {
+                f.debug_struct("PeerDisconnected").field("peer", peer).finish()
+            }
+            BehaviourOutEvent::InboundRateLimited { peer } => f
+                .debug_struct("InboundRateLimited")
+                .field("peer", peer)
+                .finish(),
+            BehaviourOutEvent::ConfirmationSent {
+                peer,
+                digest,
+                swap_id,
+            } => f
+                .debug_struct("ConfirmationSent")
+                .field("peer", peer)
+                .field("digest", &format_args!("{}", digest.short()))
+                .field("swap_id", swap_id)
+                .finish(),
+            BehaviourOutEvent::ConfirmationSendFailed { peer, digest } => {
+                peer_and_digest(f, "ConfirmationSendFailed", peer, digest)
+            }
+            BehaviourOutEvent::ReplyTimedOut { peer, digest } => {
+                peer_and_digest(f, "ReplyTimedOut", peer, digest)
+            }
+            BehaviourOutEvent::InboundFailed { peer } => f
+                .debug_struct("InboundFailed")
+                .field("peer", peer)
+                .finish(),
+        }
+    }
+}
+
+/// A concise line for operators, e.g. `received confirmation for 1220b94d
+/// from Qm...`.
+impl<TConfirmation> fmt::Display for BehaviourOutEvent<TConfirmation> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BehaviourOutEvent::ReceivedConfirmation { peer, digest, .. } => {
+                write!(f, "received confirmation for {} from {}", digest.short(), peer)
+            }
+            BehaviourOutEvent::ReceivedAnnouncement { peer, io, .. } => {
+                write!(f, "received announcement of {} from {}", io.swap_digest.short(), peer)
+            }
+            BehaviourOutEvent::DialFailed { peer, digest, .. } => {
+                write!(f, "failed to dial {} to announce {}", peer, digest.short())
+            }
+            BehaviourOutEvent::AnnounceFailed { peer, digest, .. } => {
+                write!(f, "announce of {} to {} failed", digest.short(), peer)
+            }
+            BehaviourOutEvent::AnnounceDropped { peer, digest } => write!(
+                f,
+                "announce of {} to {} dropped, too many are queued",
+                digest.short(),
+                peer
+            ),
+            BehaviourOutEvent::AnnounceExpired { peer, digest } => write!(
+                f,
+                "announce of {} to {} expired, the peer did not reconnect",
+                digest.short(),
+                peer
+            ),
+            BehaviourOutEvent::AnnounceRejected {
+                peer,
+                digest,
+                reason,
+            } => {
+                let reason = match reason {
+                    RejectReason::UnknownDigest => "unknown swap",
+                    RejectReason::Busy => "busy",
+                };
+                write!(f, "announce of {} rejected by {}: {}", digest.short(), peer, reason)
+            }
+            BehaviourOutEvent::AnnounceQueued { peer, digest } => write!(
+                f,
+                "announce of {} to {} queued until connected",
+                digest.short(),
+                peer
+            ),
+            BehaviourOutEvent::RaceLost { peer, digest } => write!(
+                f,
+                "announce of {} to {} cancelled, another peer confirmed first",
+                digest.short(),
+                peer
+            ),
+            BehaviourOutEvent::AnnounceInFlight { peer, digest } => write!(
+                f,
+                "announce of {} sent to {}, awaiting confirmation",
+                digest.short(),
+                peer
+            ),
+            BehaviourOutEvent::PeerConnected { peer } => write!(f, "connected to {}", peer),
+            BehaviourOutEvent::PeerDisconnected { peer } => write!(f, "disconnected from {}", peer),
+            BehaviourOutEvent::InboundRateLimited { peer } => {
+                write!(f, "rate limiting announcements from {}", peer)
+            }
+            BehaviourOutEvent::ConfirmationSent { peer, digest, .. } => {
+                write!(f, "sent confirmation for {} to {}", digest.short(), peer)
+            }
+            BehaviourOutEvent::ConfirmationSendFailed { peer, digest } => write!(
+                f,
+                "failed to send confirmation for {} to {}",
+                digest.short(),
+                peer
+            ),
+            BehaviourOutEvent::ReplyTimedOut { peer, digest } => write!(
+                f,
+                "did not confirm announcement of {} from {} in time",
+                digest.short(),
+                peer
+            ),
+            BehaviourOutEvent::InboundFailed { peer } => {
+                write!(f, "received an invalid announce from {}", peer)
+            }
+        }
+    }
+}
+
+/// Serializes a `PeerId` as its base58 string.
+#[cfg(feature = "serde")]
+mod serde_peer_id {
+    use libp2p::PeerId;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(peer: &PeerId, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&peer.to_base58())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PeerId, D::Error> {
+        let peer = String::deserialize(deserializer)?;
+        peer.parse().map_err(|_| D::Error::custom("invalid peer id"))
+    }
+}
+
+/// Serializes a reply substream as the digest it is for, there is no way to
+/// get the substream back.
+#[cfg(feature = "serde")]
+mod serde_reply {
+    use super::{NegotiatedSubstream, ReplySubstream, SwapDigest};
+    use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+    #[allow(clippy::borrowed_box)]
+    pub fn serialize<S: Serializer, TConfirmation>(
+        io: &Box<ReplySubstream<NegotiatedSubstream, TConfirmation>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        io.swap_digest.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>, TConfirmation>(
+        deserializer: D,
+    ) -> Result<Box<ReplySubstream<NegotiatedSubstream, TConfirmation>>, D::Error> {
+        let swap_digest = SwapDigest::deserialize(deserializer)?;
+        Err(D::Error::custom(format!(
+            "announcement of {} has no reply substream",
+            swap_digest
+        )))
+    }
+}
+
+/// Why an announce failed.
+#[derive(Clone, Copy, Debug)]
+enum Failure {
+    /// We could not connect to the peer.
+    Dial,
+    /// We connected, but the announce itself failed.
+    Announce,
+}
+
+/// The outcome of announcing a swap to one peer.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum AnnounceOutcome<TConfirmation = SwapId> {
+    /// The peer confirmed the swap.
+    Confirmed(Confirmed<TConfirmation>),
+    /// See `BehaviourOutEvent::DialFailed`.
+    DialFailed,
+    /// See `BehaviourOutEvent::AnnounceFailed`.
+    Failed,
+    /// See `BehaviourOutEvent::AnnounceDropped`.
+    Dropped,
+    /// See `BehaviourOutEvent::AnnounceRejected`.
+    Rejected(RejectReason),
+}
+
+impl<TConfirmation> AnnounceOutcome<TConfirmation> {
+    fn into_result(self) -> Result<Confirmed<TConfirmation>, AnnounceError> {
+        match self {
+            AnnounceOutcome::Confirmed(confirmed) => Ok(confirmed),
+            AnnounceOutcome::DialFailed => Err(AnnounceError::DialFailed),
+            AnnounceOutcome::Failed => Err(AnnounceError::Failed),
+            AnnounceOutcome::Dropped => Err(AnnounceError::Dropped),
+            AnnounceOutcome::Rejected(reason) => Err(AnnounceError::Rejected(reason)),
+        }
+    }
+}
+
+/// Why the announce behind an `AnnounceHandle` did not get confirmed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum AnnounceError {
+    #[error("could not connect to the peer")]
+    DialFailed,
+    #[error("the peer did not confirm the swap")]
+    Failed,
+    #[error("too many announces to the peer were queued")]
+    Dropped,
+    #[error("the announce was cancelled")]
+    Cancelled,
+    #[error("the peer rejected the swap: {0:?}")]
+    Rejected(RejectReason),
+}
+
+/// Returned by `Announce::start_announce_protocol`, resolves once the
+/// announce has been confirmed, has failed or has been cancelled.
+///
+/// Only makes progress while the swarm is polled.
+#[derive(Debug)]
+pub struct AnnounceHandle<TConfirmation = SwapId> {
+    swap_digest: SwapDigest,
+    outcome: mpsc::UnboundedReceiver<(PeerId, AnnounceOutcome<TConfirmation>)>,
+}
+
+impl<TConfirmation> AnnounceHandle<TConfirmation> {
+    /// The swap that was announced.
+    pub fn swap_digest(&self) -> &SwapDigest {
+        &self.swap_digest
+    }
+}
+
+impl<TConfirmation> Future for AnnounceHandle<TConfirmation> {
+    type Output = Result<Confirmed<TConfirmation>, AnnounceError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let outcome = match futures::ready!(self.outcome.poll_next_unpin(cx)) {
+            Some((_, outcome)) => outcome,
+            // The announce was dropped without an outcome.
+            None => return Poll::Ready(Err(AnnounceError::Cancelled)),
+        };
+
+        Poll::Ready(outcome.into_result())
+    }
+}
+
+/// Returned by `Announce::announce_to_many`, yields the outcome of the
+/// announce for each peer as it becomes known.
+///
+/// The stream ends once the announce has been resolved for every peer.
+/// Cancelled announces have no outcome. Outcomes are only produced while the
+/// swarm is polled.
+#[derive(Debug)]
+pub struct BroadcastHandle<TConfirmation = SwapId> {
+    swap_digest: SwapDigest,
+    outcomes: mpsc::UnboundedReceiver<(PeerId, AnnounceOutcome<TConfirmation>)>,
+}
+
+impl<TConfirmation> BroadcastHandle<TConfirmation> {
+    /// The swap that was announced.
+    pub fn swap_digest(&self) -> &SwapDigest {
+        &self.swap_digest
+    }
+}
+
+impl<TConfirmation> Stream for BroadcastHandle<TConfirmation> {
+    type Item = (PeerId, AnnounceOutcome<TConfirmation>);
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.outcomes.poll_next_unpin(cx)
+    }
+}
+
+/// Returned by `Announce::announce_race`, resolves with the first peer to
+/// confirm the swap.
+///
+/// If nobody confirms it the handle fails like the announce that failed
+/// last, or with `AnnounceError::Cancelled` if they were all cancelled. Only
+/// makes progress while the swarm is polled.
+#[derive(Debug)]
+pub struct AnnounceRaceHandle<TConfirmation = SwapId> {
+    broadcast: BroadcastHandle<TConfirmation>,
+    /// Why the last announce that did not win failed.
+    failure: Option<AnnounceError>,
+}
+
+impl<TConfirmation> AnnounceRaceHandle<TConfirmation> {
+    /// The swap that was announced.
+    pub fn swap_digest(&self) -> &SwapDigest {
+        self.broadcast.swap_digest()
+    }
+}
+
+impl<TConfirmation> Future for AnnounceRaceHandle<TConfirmation> {
+    type Output = Result<(PeerId, Confirmed<TConfirmation>), AnnounceError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        loop {
+            match futures::ready!(self.broadcast.poll_next_unpin(cx)) {
+                Some((peer, outcome)) => match outcome.into_result() {
+                    Ok(confirmed) => return Poll::Ready(Ok((peer, confirmed))),
+                    Err(error) => self.failure = Some(error),
+                },
+                None => return Poll::Ready(Err(self.failure.unwrap_or(AnnounceError::Cancelled))),
+            }
+        }
+    }
+}
+
+/// How long to wait before retry number `retries`, counting from zero: a
+/// random duration up to `base * 2^retries`, or up to `max` if that is less.
+fn jittered_backoff(rng: &mut impl Rng, base: Duration, max: Duration, retries: u32) -> Duration {
+    let ceiling = 2u32
+        .checked_pow(retries)
+        .and_then(|factor| base.checked_mul(factor))
+        .map_or(max, |backoff| backoff.min(max));
+    let ceiling_nanos = u64::try_from(ceiling.as_nanos()).unwrap_or(u64::MAX - 1);
+
+    Duration::from_nanos(rng.gen_range(0, ceiling_nanos + 1))
+}
+
+/// Turns the swarm into a stream of the events of its `Announce` behaviour,
+/// dropping its other events such as new listen addresses.
+///
+/// The swarm only makes progress while the stream is polled. To keep using
+/// the swarm, poll `&mut swarm` instead, which is the same stream.
+pub fn into_event_stream<TConfirmation: Confirmation>(
+    swarm: Swarm<Announce<TConfirmation>>,
+) -> impl Stream<Item = BehaviourOutEvent<TConfirmation>> + Unpin {
+    swarm
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::{core::upgrade::UpgradeError, identity, multihash::Sha2_256, swarm::ProtocolsHandlerUpgrErr};
+    use std::io;
+
+    fn swap_digest() -> SwapDigest {
+        SwapDigest::new(Sha2_256::digest(b"hello world"))
+    }
+
+    fn random_peer_id() -> PeerId {
+        identity::Keypair::generate_ed25519().public().into_peer_id()
+    }
+
+    #[test]
+    fn default_supported_protocols_are_v2_then_v1_framed_then_unframed() {
+        let announce = Announce::default();
+
+        assert_eq!(announce.supported_protocols(), vec![
+            "/comit/swap/announce/2.1.0".to_owned(),
+            "/comit/swap/announce/1.1.0".to_owned(),
+            "/comit/swap/announce/2.0.0".to_owned(),
+            "/comit/swap/announce/1.0.0".to_owned(),
+        ]);
+    }
+
+    #[test]
+    fn pipelining_adds_the_pipelined_protocol() {
+        let announce = Announce::new(
+            AnnounceConfig::default()
+                .versions(vec![ProtocolVersion::V1])
+                .pipeline_announces(true),
+        );
+
+        assert_eq!(announce.supported_protocols(), vec![
+            "/comit/swap/announce/1.1.0".to_owned(),
+            "/comit/swap/announce/pipelined/1.1.0".to_owned(),
+            "/comit/swap/announce/1.0.0".to_owned(),
+            "/comit/swap/announce/pipelined/1.0.0".to_owned(),
+        ]);
+    }
+
+    #[test]
+    fn backoff_is_jittered_up_to_the_doubled_base_and_capped() {
+        let base = Duration::from_millis(100);
+        let max = Duration::from_secs(5);
+        let backoffs = |seed| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            (0..64).map(|retries| jittered_backoff(&mut rng, base, max, retries)).collect::<Vec<_>>()
+        };
+
+        let seeded = backoffs(42);
+
+        for (retries, backoff) in seeded.iter().enumerate() {
+            let ceiling = if retries < 6 { base * 2u32.pow(retries as u32) } else { max };
+            assert!(*backoff <= ceiling, "retry {} waits {:?}", retries, backoff);
+        }
+        assert_eq!(seeded, backoffs(42));
+        // Retries that failed together are spread out rather than all waiting
+        // the longest.
+        assert!(seeded.iter().skip(6).any(|backoff| *backoff < max / 2));
+    }
+
+    #[test]
+    fn retry_of_an_announce_cancelled_during_its_backoff_is_not_sent() {
+        let config = AnnounceConfig::default()
+            .max_retries(1)
+            .base_backoff(Duration::from_millis(1));
+        let mut announce = Announce::new(config);
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+        let peer_id = random_peer_id();
+        let dial_info = DialInformation {
+            peer_id: peer_id.clone(),
+            address_hint: None,
+        };
+
+        announce.start_announce_protocol(swap_digest(), dial_info.clone()).unwrap();
+        announce.announce_failed(peer_id, swap_digest(), Failure::Dial);
+        assert!(announce.cancel_announce(&swap_digest()));
+        announce.start_announce_protocol(swap_digest(), dial_info).unwrap();
+        assert_eq!(announce.metrics().announces_sent(), 2);
+
+        std::thread::sleep(Duration::from_millis(5));
+        announce.send_due_retries(&mut cx);
+
+        assert_eq!(announce.metrics().announces_sent(), 2);
+        assert_eq!(announce.pending_announcements().count(), 1);
+    }
+
+    #[test]
+    fn clone_config_copies_only_the_configuration() {
+        let config = AnnounceConfig::default()
+            .timeout(Duration::from_secs(1))
+            .max_retries(3)
+            .protocol_name("/testnet/swap/announce".to_owned());
+        let mut announce = Announce::new(config.clone());
+
+        let dial_info = DialInformation {
+            peer_id: random_peer_id(),
+            address_hint: None,
+        };
+        announce.start_announce_protocol(swap_digest(), dial_info).unwrap();
+
+        let clone = announce.clone_config();
+
+        assert_eq!(clone.config(), &config);
+        assert_eq!(clone.pending_announcements().count(), 0);
+        assert_eq!(clone.metrics().announces_sent(), 0);
+    }
+
+    #[test]
+    fn dialable_address_hint_is_used() {
+        let mut announce = Announce::default();
+        let peer_id = random_peer_id();
+        let address: Multiaddr = "/ip4/127.0.0.1/tcp/1".parse().unwrap();
+
+        let dial_info = DialInformation {
+            peer_id: peer_id.clone(),
+            address_hint: Some(address.clone()),
+        };
+
+        assert!(announce.start_announce_protocol(swap_digest(), dial_info).is_ok());
+        assert_eq!(announce.addresses_of_peer(&peer_id), vec![address]);
+    }
+
+    #[test]
+    fn p2p_only_address_hint_is_rejected() {
+        let mut announce = Announce::default();
+        let peer_id = random_peer_id();
+        let address: Multiaddr = Protocol::P2p(peer_id.clone().into()).into();
+
+        let dial_info = DialInformation {
+            peer_id: peer_id.clone(),
+            address_hint: Some(address.clone()),
+        };
+
+        match announce.start_announce_protocol(swap_digest(), dial_info) {
+            Err(StartAnnounceError::InvalidAddressHint(InvalidAddressHint(rejected))) => {
+                assert_eq!(rejected, address)
+            }
+            Err(error) => panic!("unexpected error {:?}", error),
+            Ok(_) => panic!("a p2p only address hint was accepted"),
+        }
+        assert_eq!(announce.pending_announcements().count(), 0);
+        assert!(announce.addresses_of_peer(&peer_id).is_empty());
+    }
+
+    #[test]
+    fn announce_to_multiaddr_dials_the_peer_it_names() {
+        let mut announce = Announce::default();
+        let peer_id = random_peer_id();
+        let address: Multiaddr = "/ip4/127.0.0.1/tcp/4001".parse().unwrap();
+
+        let full_address = address.clone().with(Protocol::P2p(peer_id.clone().into()));
+
+        announce.announce_to_multiaddr(swap_digest(), full_address).unwrap();
+
+        assert_eq!(
+            announce.pending_announcements().collect::<Vec<_>>(),
+            vec![(&peer_id, &swap_digest())]
+        );
+        assert_eq!(announce.addresses_of_peer(&peer_id), vec![address]);
+    }
+
+    #[test]
+    fn announce_to_multiaddr_without_a_peer_id_is_refused() {
+        let mut announce = Announce::default();
+        let address: Multiaddr = "/ip4/127.0.0.1/tcp/4001".parse().unwrap();
+
+        assert_eq!(
+            announce.announce_to_multiaddr(swap_digest(), address.clone()).unwrap_err(),
+            StartAnnounceError::NoPeerId(address)
+        );
+        assert_eq!(announce.pending_announcements().count(), 0);
+    }
+
+    #[test]
+    fn peer_over_its_inbound_limit_is_rate_limited_until_the_second_is_over() {
+        let mut announce = Announce::new(AnnounceConfig::default().max_inbound_per_peer_per_second(1));
+        let peer_id = random_peer_id();
+        let other_peer_id = random_peer_id();
+
+        assert!(!announce.inbound_rate_limited(&peer_id));
+        assert!(!announce.inbound_rate_limited(&other_peer_id));
+        assert!(announce.rate_limited_peers().is_empty());
+
+        assert!(announce.inbound_rate_limited(&peer_id));
+        assert_eq!(announce.rate_limited_peers(), vec![peer_id]);
+
+        let second_over = Instant::now() + Duration::from_secs(1);
+        assert!(announce.rate_limited_peers_at(second_over).is_empty());
+    }
+
+    #[test]
+    fn removed_peer_has_no_announces_or_addresses_left() {
+        let mut announce = Announce::default();
+        let peer_id = random_peer_id();
+        let other_peer_id = random_peer_id();
+        let address: Multiaddr = "/ip4/127.0.0.1/tcp/4001".parse().unwrap();
+        let other_digest = SwapDigest::new(Sha2_256::digest(b"hello there"));
+
+        for digest in &[swap_digest(), other_digest] {
+            let dial_info = DialInformation {
+                peer_id: peer_id.clone(),
+                address_hint: Some(address.clone()),
+            };
+            announce.start_announce_protocol(digest.clone(), dial_info).unwrap();
+        }
+        let dial_info = DialInformation {
+            peer_id: other_peer_id.clone(),
+            address_hint: None,
+        };
+        announce.start_announce_protocol(swap_digest(), dial_info).unwrap();
+
+        assert_eq!(announce.remove_peer(&peer_id), 2);
+
+        assert_eq!(
+            announce.pending_announcements().collect::<Vec<_>>(),
+            vec![(&other_peer_id, &swap_digest())]
+        );
+        assert!(announce.addresses_of_peer(&peer_id).is_empty());
+        assert_eq!(announce.remove_peer(&peer_id), 0);
+    }
+
+    #[test]
+    fn announce_is_refused_when_the_queue_for_the_peer_is_full() {
+        let mut announce = Announce::new(AnnounceConfig::default().max_in_flight_per_peer(1));
+        let peer_id = random_peer_id();
+        let dial_info = DialInformation {
+            peer_id: peer_id.clone(),
+            address_hint: None,
+        };
+        let other_digest = SwapDigest::new(Sha2_256::digest(b"hello there"));
+
+        announce.start_announce_protocol(swap_digest(), dial_info.clone()).unwrap();
+        // Joining the announce under way does not take another slot.
+        announce.start_announce_protocol(swap_digest(), dial_info.clone()).unwrap();
+
+        assert_eq!(
+            announce.start_announce_protocol(other_digest, dial_info).unwrap_err(),
+            StartAnnounceError::QueueFull(peer_id)
+        );
+        assert_eq!(announce.pending_announcements().count(), 1);
+    }
+
+    #[test]
+    fn draining_events_leaves_the_other_actions_for_poll() {
+        let mut announce = Announce::default();
+        let peer_id = random_peer_id();
+
+        announce
+            .start_announce_protocol(swap_digest(), DialInformation {
+                peer_id: peer_id.clone(),
+                address_hint: None,
+            })
+            .unwrap();
+
+        assert!(matches!(
+            announce.drain_events().as_slice(),
+            [BehaviourOutEvent::AnnounceQueued { .. }]
+        ));
+        assert!(matches!(
+            announce.events.iter().collect::<Vec<_>>().as_slice(),
+            [NetworkBehaviourAction::DialPeer { peer_id: dialed, .. }] if dialed == &peer_id
+        ));
+    }
+
+    #[test]
+    fn announce_dials_the_peer_by_default() {
+        let mut announce = Announce::default();
+        let peer_id = random_peer_id();
+
+        announce
+            .start_announce_protocol(swap_digest(), DialInformation {
+                peer_id: peer_id.clone(),
+                address_hint: None,
+            })
+            .unwrap();
+
+        assert!(announce.events.iter().any(|action| matches!(
+            action,
+            NetworkBehaviourAction::DialPeer { peer_id: dialed, .. } if dialed == &peer_id
+        )));
+    }
+
+    #[test]
+    fn announce_without_dialing_needs_a_connection() {
+        let mut announce = Announce::new(AnnounceConfig::default().dial_peers(false));
+        let peer_id = random_peer_id();
+        let dial_info = DialInformation {
+            peer_id: peer_id.clone(),
+            address_hint: None,
+        };
+
+        assert_eq!(
+            announce.start_announce_protocol(swap_digest(), dial_info.clone()).unwrap_err(),
+            StartAnnounceError::NotConnected(peer_id.clone())
+        );
+        assert_eq!(announce.pending_announcements().count(), 0);
+
+        let connection = ConnectionId::new(0);
+        let endpoint = ConnectedPoint::Dialer {
+            address: "/ip4/127.0.0.1/tcp/1".parse().unwrap(),
+        };
+        announce.inject_connection_established(&peer_id, &connection, &endpoint);
+        announce.inject_connected(&peer_id);
+        announce.events.clear();
+
+        announce.start_announce_protocol(swap_digest(), dial_info).unwrap();
+        assert!(!announce
+            .events
+            .iter()
+            .any(|action| matches!(action, NetworkBehaviourAction::DialPeer { .. })));
+        assert_eq!(announce.pending_announcements().count(), 1);
+    }
+
+    #[test]
+    fn duplicate_address_hints_are_collapsed() {
+        let mut announce = Announce::default();
+        let peer_id = random_peer_id();
+        let address: Multiaddr = "/ip4/127.0.0.1/tcp/1".parse().unwrap();
+
+        for digest in [b"hello world", b"hello there"] {
+            let dial_info = DialInformation {
+                peer_id: peer_id.clone(),
+                address_hint: Some(address.clone()),
+            };
+            announce
+                .start_announce_protocol(SwapDigest::new(Sha2_256::digest(digest)), dial_info)
+                .unwrap();
+        }
+
+        assert_eq!(announce.addresses_of_peer(&peer_id), vec![address]);
+    }
+
+    #[test]
+    fn announce_fails_when_the_peer_disconnects() {
+        let mut announce = Announce::default();
+        let peer_id = random_peer_id();
+        let connection = ConnectionId::new(0);
+        let endpoint = ConnectedPoint::Dialer {
+            address: "/ip4/127.0.0.1/tcp/1".parse().unwrap(),
+        };
+
+        announce
+            .start_announce_protocol(
+                swap_digest(),
+                DialInformation {
+                    peer_id: peer_id.clone(),
+                    address_hint: None,
+                },
+            )
+            .unwrap();
+        announce.inject_connection_established(&peer_id, &connection, &endpoint);
+        announce.inject_connected(&peer_id);
+        announce.inject_connection_closed(&peer_id, &connection, &endpoint);
+        announce.inject_disconnected(&peer_id);
+
+        let events = announce.drain_events();
+        assert!(matches!(
+            events.as_slice(),
+            [
+                BehaviourOutEvent::AnnounceQueued { .. },
+                BehaviourOutEvent::PeerConnected { .. },
+                BehaviourOutEvent::PeerDisconnected { .. },
+                BehaviourOutEvent::AnnounceFailed { digest, .. },
+            ] if digest == &swap_digest()
+        ));
+        assert_eq!(announce.pending_announcements().count(), 0);
+    }
+
+    #[test]
+    fn announce_whose_substream_went_away_is_held_until_the_peer_reconnects() {
+        let mut announce = Announce::new(AnnounceConfig::default().resend_on_reconnect(Duration::from_secs(5)));
+        let peer_id = random_peer_id();
+        let connection = ConnectionId::new(0);
+        let endpoint = ConnectedPoint::Dialer {
+            address: "/ip4/127.0.0.1/tcp/1".parse().unwrap(),
+        };
+
+        announce
+            .start_announce_protocol(
+                swap_digest(),
+                DialInformation {
+                    peer_id: peer_id.clone(),
+                    address_hint: None,
+                },
+            )
+            .unwrap();
+        announce.inject_connection_established(&peer_id, &connection, &endpoint);
+        announce.inject_connected(&peer_id);
+        // The substream fails before the disconnect is seen.
+        announce.inject_event(
+            peer_id.clone(),
+            connection,
+            HandlerEvent::Error(Error::Upgrade {
+                swap_digest: swap_digest(),
+                error: ProtocolsHandlerUpgrErr::Upgrade(UpgradeError::Apply(
+                    protocol::Error::ClosedWithoutConfirmation,
+                )),
+            }),
+        );
+        announce.inject_connection_closed(&peer_id, &connection, &endpoint);
+        announce.inject_disconnected(&peer_id);
+        announce.inject_connection_established(&peer_id, &connection, &endpoint);
+        announce.inject_connected(&peer_id);
+
+        let events = announce.drain_events();
+        assert!(matches!(
+            events.as_slice(),
+            [
+                BehaviourOutEvent::AnnounceQueued { .. },
+                BehaviourOutEvent::PeerConnected { .. },
+                BehaviourOutEvent::PeerDisconnected { .. },
+                BehaviourOutEvent::PeerConnected { .. },
+            ]
+        ), "{:?}", events);
+        assert_eq!(announce.metrics().announces_sent(), 2);
+        assert_eq!(announce.pending_announcements().count(), 1);
+    }
+
+    #[test]
+    fn announce_retried_across_a_disconnect_is_sent_once_after_the_reconnect() {
+        let config = AnnounceConfig::default()
+            .resend_on_reconnect(Duration::from_secs(5))
+            .max_retries(1)
+            .base_backoff(Duration::from_millis(1));
+        let mut announce = Announce::new(config);
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+        let peer_id = random_peer_id();
+        let connection = ConnectionId::new(0);
+        let endpoint = ConnectedPoint::Dialer {
+            address: "/ip4/127.0.0.1/tcp/1".parse().unwrap(),
+        };
+
+        announce
+            .start_announce_protocol(
+                swap_digest(),
+                DialInformation {
+                    peer_id: peer_id.clone(),
+                    address_hint: None,
+                },
+            )
+            .unwrap();
+        announce.inject_connection_established(&peer_id, &connection, &endpoint);
+        announce.inject_connected(&peer_id);
+        announce.announce_failed(peer_id.clone(), swap_digest(), Failure::Announce);
+        announce.inject_connection_closed(&peer_id, &connection, &endpoint);
+        announce.inject_disconnected(&peer_id);
+        announce.events.clear();
+
+        // The backoff elapses while the peer is disconnected.
+        std::thread::sleep(Duration::from_millis(5));
+        announce.send_due_retries(&mut cx);
+        announce.inject_connection_established(&peer_id, &connection, &endpoint);
+        announce.inject_connected(&peer_id);
+
+        let announces_sent = announce
+            .events
+            .iter()
+            .map(|action| match action {
+                NetworkBehaviourAction::NotifyHandler {
+                    event: HandlerInEvent::Announce(_),
+                    ..
+                } => 1,
+                NetworkBehaviourAction::NotifyHandler {
+                    event: HandlerInEvent::AnnounceAll(configs),
+                    ..
+                } => configs.len(),
+                _ => 0,
+            })
+            .sum::<usize>();
+        assert_eq!(announces_sent, 1);
+        assert_eq!(announce.metrics().announces_sent(), 2);
+        assert_eq!(announce.pending_announcements().count(), 1);
+    }
+
+    #[test]
+    fn disconnect_does_not_use_up_a_retry_of_an_announce_in_its_backoff() {
+        let config = AnnounceConfig::default()
+            .max_retries(2)
+            .base_backoff(Duration::from_secs(60));
+        let mut announce = Announce::new(config);
+        let peer_id = random_peer_id();
+        let connection = ConnectionId::new(0);
+        let endpoint = ConnectedPoint::Dialer {
+            address: "/ip4/127.0.0.1/tcp/1".parse().unwrap(),
+        };
+
+        announce
+            .start_announce_protocol(
+                swap_digest(),
+                DialInformation {
+                    peer_id: peer_id.clone(),
+                    address_hint: None,
+                },
+            )
+            .unwrap();
+        announce.inject_connection_established(&peer_id, &connection, &endpoint);
+        announce.inject_connected(&peer_id);
+        announce.announce_failed(peer_id.clone(), swap_digest(), Failure::Announce);
+        let retry = announce.pending[&(peer_id.clone(), swap_digest())].retry;
+        announce.inject_connection_closed(&peer_id, &connection, &endpoint);
+        announce.inject_disconnected(&peer_id);
+
+        let pending = &announce.pending[&(peer_id, swap_digest())];
+        assert_eq!(pending.retries, 1);
+        assert_eq!(pending.retry, retry);
+    }
+
+    #[test]
+    fn unreachable_address_hints_are_forgotten() {
+        let mut announce = Announce::default();
+        let peer_id = random_peer_id();
+        let address: Multiaddr = "/ip4/127.0.0.1/tcp/1".parse().unwrap();
+
+        announce
+            .start_announce_protocol(
+                swap_digest(),
+                DialInformation {
+                    peer_id: peer_id.clone(),
+                    address_hint: Some(address.clone()),
+                },
+            )
+            .unwrap();
+        assert_eq!(announce.addresses_of_peer(&peer_id), vec![address.clone()]);
+
+        let error = io::Error::new(io::ErrorKind::ConnectionRefused, "refused");
+        announce.inject_addr_reach_failure(Some(&peer_id), &address, &error);
+
+        assert!(announce.addresses_of_peer(&peer_id).is_empty());
+    }
+
+    #[test]
+    fn least_recently_completed_announce_is_forgotten_first() {
+        let mut completed = RecentlyCompleted::new(2);
+        let (first, second, third) = (random_peer_id(), random_peer_id(), random_peer_id());
+
+        assert!(!completed.insert(first.clone(), swap_digest()));
+        assert!(!completed.insert(second.clone(), swap_digest()));
+        assert!(completed.insert(first.clone(), swap_digest()));
+        assert!(!completed.insert(third.clone(), swap_digest()));
+
+        assert!(completed.contains(&first, &swap_digest()));
+        assert!(!completed.contains(&second, &swap_digest()));
+        assert!(completed.contains(&third, &swap_digest()));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn received_confirmation_roundtrips_through_json() {
+        let peer = random_peer_id();
+        let swap_id = SwapId::random();
+        let event = BehaviourOutEvent::ReceivedConfirmation {
+            peer: peer.clone(),
+            digest: swap_digest(),
+            swap_id,
+            context: Some(7),
+        };
+
+        let json = serde_json::to_string(&event).unwrap();
+
+        match serde_json::from_str::<BehaviourOutEvent>(&json).unwrap() {
+            BehaviourOutEvent::ReceivedConfirmation {
+                peer: decoded_peer,
+                digest,
+                swap_id: decoded_swap_id,
+                context,
+            } => {
+                assert_eq!(decoded_peer, peer);
+                assert_eq!(digest, swap_digest());
+                assert_eq!(decoded_swap_id, swap_id);
+                assert_eq!(context, Some(7));
+            }
+            event => panic!("decoded into {:?}", event),
+        }
+    }
+
+    #[test]
+    fn exported_announces_are_requeued_on_import() {
+        let mut announce = Announce::default();
+        let peer_id = random_peer_id();
+        let other_digest = SwapDigest::new(Sha2_256::digest(b"other"));
+        let address: Multiaddr = "/ip4/127.0.0.1/tcp/9939".parse().unwrap();
+
+        announce
+            .start_announce_protocol_with_context(swap_digest(), DialInformation {
+                peer_id: peer_id.clone(),
+                address_hint: Some(address.clone()),
+            }, 7)
+            .unwrap();
+        announce
+            .start_announce_protocol(other_digest.clone(), DialInformation {
+                peer_id: peer_id.clone(),
+                address_hint: None,
+            })
+            .unwrap();
+
+        let state = announce.export_state();
+        let mut restarted = Announce::default();
+        let started = restarted.import_state(state.clone());
+
+        assert!(started.iter().all(Result::is_ok));
+        assert_eq!(restarted.in_flight_count(), 2);
+        let mut announces = restarted.export_state().announces;
+        announces.sort_by_key(|announce| announce.context);
+        assert_eq!(announces, vec![
+            PendingAnnounceState {
+                peer: peer_id.clone(),
+                digest: other_digest,
+                address_hint: None,
+                context: None,
+            },
+            PendingAnnounceState {
+                peer: peer_id.clone(),
+                digest: swap_digest(),
+                address_hint: Some(address.clone()),
+                context: Some(7),
+            },
+        ]);
+        assert_eq!(restarted.addresses_of_peer(&peer_id), vec![address]);
+    }
+
+    #[test]
+    fn events_are_displayed_as_concise_lines() {
+        let peer = random_peer_id();
+        let digest = swap_digest().short();
+        let line = |event: BehaviourOutEvent| event.to_string();
+
+        assert_eq!(
+            line(BehaviourOutEvent::ReceivedConfirmation {
+                peer: peer.clone(),
+                digest: swap_digest(),
+                swap_id: SwapId::random(),
+                context: None,
+            }),
+            format!("received confirmation for {} from {}", digest, peer)
+        );
+        assert_eq!(
+            line(BehaviourOutEvent::DialFailed {
+                peer: peer.clone(),
+                digest: swap_digest(),
+                context: None,
+            }),
+            format!("failed to dial {} to announce {}", peer, digest)
+        );
+        assert_eq!(
+            line(BehaviourOutEvent::AnnounceFailed {
+                peer: peer.clone(),
+                digest: swap_digest(),
+                context: Some(1),
+            }),
+            format!("announce of {} to {} failed", digest, peer)
+        );
+        assert_eq!(
+            line(BehaviourOutEvent::AnnounceDropped {
+                peer: peer.clone(),
+                digest: swap_digest(),
+            }),
+            format!("announce of {} to {} dropped, too many are queued", digest, peer)
+        );
+        assert_eq!(
+            line(BehaviourOutEvent::AnnounceExpired {
+                peer: peer.clone(),
+                digest: swap_digest(),
+            }),
+            format!("announce of {} to {} expired, the peer did not reconnect", digest, peer)
+        );
+        assert_eq!(
+            line(BehaviourOutEvent::AnnounceRejected {
+                peer: peer.clone(),
+                digest: swap_digest(),
+                reason: RejectReason::Busy,
+            }),
+            format!("announce of {} rejected by {}: busy", digest, peer)
+        );
+        assert_eq!(
+            line(BehaviourOutEvent::AnnounceQueued {
+                peer: peer.clone(),
+                digest: swap_digest(),
+            }),
+            format!("announce of {} to {} queued until connected", digest, peer)
+        );
+        assert_eq!(
+            line(BehaviourOutEvent::RaceLost {
+                peer: peer.clone(),
+                digest: swap_digest(),
+            }),
+            format!("announce of {} to {} cancelled, another peer confirmed first", digest, peer)
+        );
+        assert_eq!(
+            line(BehaviourOutEvent::AnnounceInFlight {
+                peer: peer.clone(),
+                digest: swap_digest(),
+            }),
+            format!("announce of {} sent to {}, awaiting confirmation", digest, peer)
+        );
+        assert_eq!(
+            line(BehaviourOutEvent::PeerConnected { peer: peer.clone() }),
+            format!("connected to {}", peer)
+        );
+        assert_eq!(
+            line(BehaviourOutEvent::PeerDisconnected { peer: peer.clone() }),
+            format!("disconnected from {}", peer)
+        );
+        assert_eq!(
+            line(BehaviourOutEvent::InboundRateLimited { peer: peer.clone() }),
+            format!("rate limiting announcements from {}", peer)
+        );
+        assert_eq!(
+            line(BehaviourOutEvent::ConfirmationSent {
+                peer: peer.clone(),
+                digest: swap_digest(),
+                swap_id: SwapId::random(),
+            }),
+            format!("sent confirmation for {} to {}", digest, peer)
+        );
+        assert_eq!(
+            line(BehaviourOutEvent::ConfirmationSendFailed {
+                peer: peer.clone(),
+                digest: swap_digest(),
+            }),
+            format!("failed to send confirmation for {} to {}", digest, peer)
+        );
+        assert_eq!(
+            line(BehaviourOutEvent::ReplyTimedOut {
+                peer: peer.clone(),
+                digest: swap_digest(),
+            }),
+            format!("did not confirm announcement of {} from {} in time", digest, peer)
+        );
+        assert_eq!(
+            line(BehaviourOutEvent::InboundFailed { peer: peer.clone() }),
+            format!("received an invalid announce from {}", peer)
+        );
+    }
+
+    #[test]
+    fn address_change_replaces_the_address_hint() {
+        let mut announce = Announce::default();
+        let peer_id = random_peer_id();
+        let connection = ConnectionId::new(0);
+        let old: Multiaddr = "/ip4/127.0.0.1/tcp/1".parse().unwrap();
+        let new: Multiaddr = "/ip4/127.0.0.2/tcp/2".parse().unwrap();
+
+        announce
+            .start_announce_protocol(swap_digest(), DialInformation {
+                peer_id: peer_id.clone(),
+                address_hint: Some(old.clone()),
+            })
+            .unwrap();
+        let old_endpoint = ConnectedPoint::Dialer { address: old };
+        announce.inject_connection_established(&peer_id, &connection, &old_endpoint);
+        announce.inject_address_change(&peer_id, &connection, &old_endpoint, &ConnectedPoint::Dialer {
+            address: new.clone(),
+        });
+
+        assert_eq!(announce.addresses_of_peer(&peer_id), vec![new.clone()]);
+        let pending = &announce.pending[&(peer_id, swap_digest())];
+        assert_eq!(pending.address_hint, Some(new));
+    }
+
+    #[test]
+    fn confirmation_is_only_attributed_to_the_dialed_peer() {
+        let mut announce = Announce::default();
+        let (bob, mallory) = (random_peer_id(), random_peer_id());
+        let connection = ConnectionId::new(0);
+        let confirmed = Confirmed::new(swap_digest(), SwapId::random(), ProtocolVersion::V1);
+
+        announce
+            .start_announce_protocol(swap_digest(), DialInformation {
+                peer_id: bob.clone(),
+                address_hint: None,
+            })
+            .unwrap();
+        announce.drain_events();
+
+        announce.inject_event(mallory, connection, HandlerEvent::ReceivedConfirmation(confirmed.clone()));
+        assert!(announce.drain_events().is_empty());
+        assert_eq!(announce.pending_announcements().count(), 1);
+
+        announce.inject_event(bob.clone(), connection, HandlerEvent::ReceivedConfirmation(confirmed));
+        match announce.drain_events().as_slice() {
+            [BehaviourOutEvent::ReceivedConfirmation { peer, .. }] => assert_eq!(peer, &bob),
+            events => panic!("unexpected events {:?}", events),
+        }
+    }
+
+    #[test]
+    fn identified_addresses_are_tried_after_the_address_hints() {
+        let mut announce = Announce::default();
+        let peer_id = random_peer_id();
+        let hint: Multiaddr = "/ip4/127.0.0.1/tcp/1".parse().unwrap();
+        let identified: Multiaddr = "/ip4/127.0.0.2/tcp/2".parse().unwrap();
+
+        announce
+            .start_announce_protocol(swap_digest(), DialInformation {
+                peer_id: peer_id.clone(),
+                address_hint: Some(hint.clone()),
+            })
+            .unwrap();
+        announce.inject_identified_addresses(peer_id.clone(), vec![
+            identified.clone(),
+            hint.clone(),
+            Protocol::P2p(peer_id.clone().into()).into(),
+        ]);
+
+        assert_eq!(announce.addresses_of_peer(&peer_id), vec![hint, identified]);
+    }
 }
@@ -0,0 +1,122 @@
+use futures::prelude::*;
+use std::{
+    collections::BTreeMap,
+    task::{Context, Poll},
+};
+use wasm_timer::{Delay, Instant};
+
+/// Deadlines of any number of keys, tracked with a single timer.
+///
+/// A `Delay` per key would register a timer for each of them and have every
+/// wakeup poll them all. Here only the earliest deadline has a timer, keys
+/// whose deadlines have passed are handed out in one go once it fires.
+///
+/// Keys cannot be removed, whoever polls the queue skips the expired keys
+/// it no longer cares about.
+#[derive(Debug)]
+pub(crate) struct DeadlineQueue<K> {
+    /// The keys by deadline, the sequence number keeps keys with the same
+    /// deadline apart and in the order they were inserted.
+    deadlines: BTreeMap<(Instant, u64), K>,
+    next_seq: u64,
+    /// Fires at the earliest deadline, which is stored alongside.
+    timer: Option<(Instant, Delay)>,
+}
+
+impl<K> DeadlineQueue<K> {
+    pub(crate) fn new() -> Self {
+        DeadlineQueue {
+            deadlines: BTreeMap::new(),
+            next_seq: 0,
+            timer: None,
+        }
+    }
+
+    /// Adds a key that expires at `deadline`.
+    pub(crate) fn insert(&mut self, key: K, deadline: Instant) {
+        self.deadlines.insert((deadline, self.next_seq), key);
+        self.next_seq += 1;
+    }
+
+    /// Returns a key whose deadline has passed, earliest first, or registers
+    /// for a wakeup at the next deadline.
+    pub(crate) fn poll_expired(&mut self, cx: &mut Context<'_>) -> Poll<K> {
+        let earliest = match self.deadlines.keys().next() {
+            Some(earliest) => *earliest,
+            None => {
+                self.timer = None;
+                return Poll::Pending;
+            }
+        };
+        let deadline = earliest.0;
+
+        if deadline > Instant::now() {
+            match &mut self.timer {
+                Some((at, _)) if *at == deadline => {}
+                Some((at, timer)) => {
+                    *at = deadline;
+                    timer.reset_at(deadline);
+                }
+                None => self.timer = Some((deadline, Delay::new_at(deadline))),
+            }
+
+            let (_, timer) = self.timer.as_mut().expect("the timer was just set");
+            if timer.poll_unpin(cx).is_pending() {
+                return Poll::Pending;
+            }
+        }
+
+        Poll::Ready(self.deadlines.remove(&earliest).expect("the key was just looked up"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::task::noop_waker_ref;
+    use std::{thread, time::Duration};
+
+    #[test]
+    fn earlier_deadline_inserted_later_expires_first() {
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let mut queue = DeadlineQueue::new();
+        let now = Instant::now();
+
+        queue.insert("late", now + Duration::from_millis(100));
+        assert!(queue.poll_expired(&mut cx).is_pending());
+        queue.insert("early", now + Duration::from_millis(10));
+
+        let early = async_std::task::block_on(future::poll_fn(|cx| queue.poll_expired(cx)));
+        assert_eq!(early, "early");
+        assert!(Instant::now() < now + Duration::from_millis(100));
+
+        let late = async_std::task::block_on(future::poll_fn(|cx| queue.poll_expired(cx)));
+        assert_eq!(late, "late");
+    }
+
+    #[test]
+    fn many_expired_deadlines_are_drained_in_one_pass() {
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let mut queue = DeadlineQueue::new();
+        let start = Instant::now() + Duration::from_millis(50);
+
+        // Shuffled, so the order they expire in comes from the deadlines.
+        for i in (0..10_000u64).rev().step_by(2).chain((0..10_000u64).step_by(2)) {
+            queue.insert(i, start + Duration::from_micros(i));
+        }
+        assert!(queue.poll_expired(&mut cx).is_pending());
+
+        thread::sleep(Duration::from_millis(100));
+
+        // Every poll hands out a key without looking at the later ones.
+        let mut polls = 0;
+        let mut expired = Vec::new();
+        while let Poll::Ready(key) = queue.poll_expired(&mut cx) {
+            polls += 1;
+            expired.push(key);
+        }
+
+        assert_eq!(polls, 10_000);
+        assert_eq!(expired, (0..10_000).collect::<Vec<_>>());
+    }
+}
@@ -1,124 +1,1252 @@
-use crate::announce::protocol::{
-    self, Confirmed, InboundConfig, OutboundConfig, ReplySubstream,
+use crate::announce::{
+    deadlines::DeadlineQueue,
+    protocol::{
+        self, Confirmation, ConfirmListen, Confirmed, ErrorCode, InboundConfig, OutboundConfig,
+        PipelinedConfig, ProtocolId, ProtocolVersion, PushConfirmation, RejectReason, ReplySubstream, SharedSubstream,
+        WireInspector,
+    },
+    SwapDigest,
+};
+use crate::swap_id::SwapId;
+use futures::{
+    channel::{mpsc, oneshot},
+    future::BoxFuture,
+    prelude::*,
+    stream::FuturesUnordered,
 };
 use libp2p::{
-    core::upgrade::{InboundUpgrade, OutboundUpgrade},
+    core::upgrade::{InboundUpgrade, NegotiationError, OutboundUpgrade, UpgradeError, UpgradeInfo},
     swarm::{
         KeepAlive, NegotiatedSubstream, ProtocolsHandler, ProtocolsHandlerEvent,
         ProtocolsHandlerUpgrErr, SubstreamProtocol,
     },
 };
 use std::{
-    collections::VecDeque,
+    collections::{HashMap, HashSet, VecDeque},
+    convert::Infallible,
+    fmt, io,
+    marker::PhantomData,
+    sync::Arc,
     task::{Context, Poll},
+    time::Duration,
+    vec,
 };
+use wasm_timer::Instant;
+
+/// How many announces a handler keeps queued or in flight by default before
+/// dropping new ones.
+pub const DEFAULT_MAX_IN_FLIGHT: usize = 32;
+
+/// The cap used to count only the announces waiting for a substream.
+#[deprecated(note = "the cap counts in-flight announces too, use `DEFAULT_MAX_IN_FLIGHT`")]
+pub const DEFAULT_MAX_DIAL_QUEUE: usize = DEFAULT_MAX_IN_FLIGHT;
+
+/// How long a connection without any announces on it is kept open by default.
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long negotiating the protocol of an outbound substream may take by
+/// default.
+pub const DEFAULT_NEGOTIATION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a connection is kept open once it has nothing left to do.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeepAlivePolicy {
+    /// For the given duration, the default with `DEFAULT_IDLE_TIMEOUT`.
+    UntilIdle(Duration),
+    /// Until the remote or the swarm closes it, the connection can be reused
+    /// for later announces.
+    Always,
+    /// Not at all, it is closed the moment its announces are done. A fresh
+    /// connection is still kept open for `DEFAULT_IDLE_TIMEOUT`, so the
+    /// announces it was opened for can arrive.
+    MinimalOnce,
+}
+
+impl Default for KeepAlivePolicy {
+    fn default() -> Self {
+        KeepAlivePolicy::UntilIdle(DEFAULT_IDLE_TIMEOUT)
+    }
+}
+
+impl KeepAlivePolicy {
+    /// The keep alive of a connection that has not been used yet.
+    fn initial(self) -> KeepAlive {
+        match self {
+            KeepAlivePolicy::UntilIdle(idle_timeout) => KeepAlive::Until(Instant::now() + idle_timeout),
+            KeepAlivePolicy::Always => KeepAlive::Yes,
+            KeepAlivePolicy::MinimalOnce => KeepAlive::Until(Instant::now() + DEFAULT_IDLE_TIMEOUT),
+        }
+    }
+
+    /// The keep alive of a connection that just ran out of things to do.
+    fn idle(self) -> KeepAlive {
+        match self {
+            KeepAlivePolicy::UntilIdle(idle_timeout) => KeepAlive::Until(Instant::now() + idle_timeout),
+            KeepAlivePolicy::Always => KeepAlive::Yes,
+            KeepAlivePolicy::MinimalOnce => KeepAlive::No,
+        }
+    }
+}
+
+/// Decides which inbound announces are accepted, the others are rejected
+/// with `RejectReason::UnknownDigest` by the handler.
+#[derive(Clone)]
+pub struct DigestFilter(Arc<dyn Fn(&SwapDigest) -> bool + Send + Sync>);
+
+impl DigestFilter {
+    pub fn new(filter: impl Fn(&SwapDigest) -> bool + Send + Sync + 'static) -> Self {
+        DigestFilter(Arc::new(filter))
+    }
+
+    pub fn accepts(&self, swap_digest: &SwapDigest) -> bool {
+        (self.0)(swap_digest)
+    }
+}
+
+impl fmt::Debug for DigestFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("DigestFilter")
+    }
+}
 
 /// Protocol handler for sending and receiving announce protocol messages.
-pub struct Handler {
-    /// Pending events to yield.
-    events: VecDeque<HandlerEvent>,
+///
+/// Swaps are confirmed with a `TConfirmation`, by default their `SwapId`.
+pub struct Handler<TConfirmation = SwapId> {
+    /// Pending events to yield, first in first out. Inbound announces are
+    /// reported in the order their substreams were negotiated and, on a
+    /// pipelined substream, in the order they were read.
+    events: VecDeque<HandlerEvent<TConfirmation>>,
     /// Queue of outbound substreams to open.
     dial_queue: VecDeque<OutboundConfig>,
+    /// Announces that arrive while this many are queued or in flight are
+    /// dropped.
+    max_in_flight: usize,
+    /// Announces whose outbound substreams have been requested but not yet
+    /// resolved, by the substream they are on. A swap cancelled and
+    /// announced again is in flight on both substreams until each resolves.
+    in_flight: HashSet<(u64, SwapDigest)>,
+    /// In flight announces whose outcome should be dropped once it arrives.
+    cancelled: HashSet<(u64, SwapDigest)>,
+    /// The number the next outbound substream is requested as.
+    next_substream: u64,
+    /// Told by the outbound upgrades which swaps they announce once their
+    /// substream has been negotiated.
+    opened: (mpsc::UnboundedSender<OutboundInfo>, mpsc::UnboundedReceiver<OutboundInfo>),
+    /// How long each outbound substream waits for its confirmation.
+    timeout: Duration,
+    /// How long negotiating the protocol of an outbound substream may take.
+    negotiation_timeout: Duration,
+    /// The announces on outbound substreams whose protocol is still being
+    /// negotiated, by substream.
+    negotiating: HashMap<u64, Vec<SwapDigest>>,
+    /// When the negotiations of those substreams time out.
+    negotiation_deadlines: DeadlineQueue<u64>,
+    /// Substreams whose negotiation timed out, their outcome is dropped.
+    abandoned: HashSet<u64>,
+    /// The largest confirmation frame accepted on outbound substreams.
+    max_confirmation_size: usize,
+    /// The protocol versions we speak, most preferred first.
+    versions: Vec<ProtocolVersion>,
+    /// What the names of the protocols we speak start with.
+    prefix: String,
+    /// Whether queued announces share a `ProtocolVersion::Pipelined`
+    /// substream.
+    pipelining: bool,
+    /// Whether the remote pushes its confirmations of our announces on
+    /// substreams of `protocol::CONFIRM_PROTOCOL`, rather than replying on
+    /// the announce substreams.
+    push_confirmations: bool,
+    /// Announces that have been sent and await their pushed confirmation,
+    /// by the substream they were sent on.
+    awaiting_push: HashSet<(u64, SwapDigest)>,
+    /// When the announces awaiting their pushed confirmation time out.
+    push_deadlines: DeadlineQueue<(u64, SwapDigest)>,
+    /// Confirmations of inbound announces to push to the remote.
+    push_queue: VecDeque<PushConfirmation<TConfirmation>>,
+    /// Digests of the confirmations being pushed. They are told apart from
+    /// our announces by digest, a swap is not announced both to and by the
+    /// same peer.
+    pushing: HashSet<SwapDigest>,
+    /// Inbound announces the application has not replied to yet, by the
+    /// number they were received as.
+    awaiting_reply: HashMap<u64, AwaitingReply>,
+    /// Resolve with the number of an inbound announce once its reply has
+    /// been sent, or its `ReplySubstream` dropped without one. Only those
+    /// that were woken are polled.
+    replies_sent: FuturesUnordered<ReplySent<TConfirmation>>,
+    /// When the inbound announces awaiting a reply time out.
+    reply_deadlines: DeadlineQueue<u64>,
+    /// The number the next inbound announce is received as.
+    next_reply: u64,
+    /// How long the application has to reply to an inbound announce.
+    reply_timeout: Duration,
+    /// How long the remote has to send its announces on an inbound
+    /// substream.
+    read_timeout: Duration,
+    /// Inbound substreams are refused until then.
+    refuse_inbound_until: Option<Instant>,
+    /// Whether inbound substreams are accepted at all.
+    listen: bool,
+    /// Is shown the frames sent and received on our substreams, if set.
+    inspector: Option<WireInspector>,
+    /// Inbound announces it does not accept are rejected, all are accepted
+    /// without one.
+    digest_filter: Option<DigestFilter>,
+    /// Rejections of inbound announces that are being sent, and substreams
+    /// dropped unanswered that are being closed.
+    rejections: FuturesUnordered<BoxFuture<'static, ()>>,
+    /// How long to keep the connection open once there is nothing to do.
+    keep_alive_policy: KeepAlivePolicy,
+    keep_alive: KeepAlive,
 }
 
 impl Default for Handler {
     fn default() -> Self {
+        Handler::with_timeout(protocol::DEFAULT_TIMEOUT)
+    }
+}
+
+impl<TConfirmation> Handler<TConfirmation> {
+    /// Creates a handler whose outbound announces fail with `Error::Timeout`
+    /// if no confirmation arrives within `timeout` of the substream being
+    /// negotiated.
+    pub fn with_timeout(timeout: Duration) -> Self {
         Handler {
             events: VecDeque::new(),
             dial_queue: VecDeque::new(),
+            max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+            in_flight: HashSet::new(),
+            cancelled: HashSet::new(),
+            next_substream: 0,
+            opened: mpsc::unbounded(),
+            timeout,
+            negotiation_timeout: DEFAULT_NEGOTIATION_TIMEOUT,
+            negotiating: HashMap::new(),
+            negotiation_deadlines: DeadlineQueue::new(),
+            abandoned: HashSet::new(),
+            max_confirmation_size: protocol::MAX_CONFIRMATION_SIZE,
+            versions: ProtocolVersion::ALL.to_vec(),
+            prefix: protocol::DEFAULT_PROTOCOL_PREFIX.to_owned(),
+            pipelining: false,
+            push_confirmations: false,
+            awaiting_push: HashSet::new(),
+            push_deadlines: DeadlineQueue::new(),
+            push_queue: VecDeque::new(),
+            pushing: HashSet::new(),
+            awaiting_reply: HashMap::new(),
+            replies_sent: FuturesUnordered::new(),
+            reply_deadlines: DeadlineQueue::new(),
+            next_reply: 0,
+            reply_timeout: protocol::DEFAULT_TIMEOUT,
+            read_timeout: protocol::DEFAULT_TIMEOUT,
+            refuse_inbound_until: None,
+            listen: true,
+            inspector: None,
+            digest_filter: None,
+            rejections: FuturesUnordered::new(),
+            keep_alive_policy: KeepAlivePolicy::default(),
+            keep_alive: KeepAlivePolicy::default().initial(),
+        }
+    }
+
+    /// Sets how many announces may be queued or in flight before further
+    /// announces are dropped with `Error::QueueFull`.
+    pub fn with_max_in_flight(mut self, max_in_flight: usize) -> Self {
+        self.max_in_flight = max_in_flight;
+        self
+    }
+
+    /// The cap used to count only the announces waiting for a substream.
+    #[deprecated(note = "the cap counts in-flight announces too, use `with_max_in_flight`")]
+    pub fn with_max_dial_queue(self, max_dial_queue: usize) -> Self {
+        self.with_max_in_flight(max_dial_queue)
+    }
+
+    /// Sets how long negotiating the protocol of an outbound substream may
+    /// take, from the substream being requested, before the announces on it
+    /// fail with `ProtocolsHandlerUpgrErr::Timeout`.
+    pub fn with_negotiation_timeout(mut self, negotiation_timeout: Duration) -> Self {
+        self.negotiation_timeout = negotiation_timeout;
+        self
+    }
+
+    /// Sets how large a confirmation frame may be before the announce fails
+    /// with `protocol::Error::FrameTooLarge`.
+    pub fn with_max_confirmation_size(mut self, max_confirmation_size: usize) -> Self {
+        self.max_confirmation_size = max_confirmation_size;
+        self
+    }
+
+    /// Sets the protocol versions to offer and accept, most preferred first.
+    pub fn with_versions(mut self, versions: Vec<ProtocolVersion>) -> Self {
+        self.versions = versions;
+        self
+    }
+
+    /// Sets what the protocol names start with, peers using a different
+    /// prefix do not understand each other.
+    pub fn with_protocol_prefix(mut self, prefix: String) -> Self {
+        self.prefix = prefix;
+        self
+    }
+
+    /// Sets whether the announces queued when a substream is opened are all
+    /// sent on it with `ProtocolVersion::Pipelined`, which the remote has to
+    /// have enabled too.
+    pub fn with_pipelining(mut self, pipelining: bool) -> Self {
+        self.pipelining = pipelining;
+        self
+    }
+
+    /// Sets whether the remote pushes its confirmations of our announces on
+    /// substreams of `protocol::CONFIRM_PROTOCOL`. The announces are sent
+    /// without waiting for a reply then, and not pipelined.
+    pub fn with_push_confirmations(mut self, push_confirmations: bool) -> Self {
+        self.push_confirmations = push_confirmations;
+        self
+    }
+
+    /// Sets how long the application has to reply to an inbound announce
+    /// before its `ReplySubstream` is dropped.
+    pub fn with_reply_timeout(mut self, reply_timeout: Duration) -> Self {
+        self.reply_timeout = reply_timeout;
+        self
+    }
+
+    /// Sets how long the remote has to send its announces on an inbound
+    /// substream before it fails with `protocol::Error::ReadTimeout`.
+    pub fn with_read_timeout(mut self, read_timeout: Duration) -> Self {
+        self.read_timeout = read_timeout;
+        self
+    }
+
+    /// Sets whether inbound substreams are accepted, a handler that does not
+    /// listen offers no protocol to the remote and only announces.
+    pub fn with_listen(mut self, listen: bool) -> Self {
+        self.listen = listen;
+        self
+    }
+
+    /// Sets what is shown the frames sent and received on the substreams of
+    /// the handler, none by default.
+    pub fn with_wire_inspector(mut self, inspector: Option<WireInspector>) -> Self {
+        self.inspector = inspector;
+        self
+    }
+
+    /// Sets the filter inbound announces have to pass, `None` accepts all of
+    /// them.
+    pub fn with_digest_filter(mut self, digest_filter: Option<DigestFilter>) -> Self {
+        self.digest_filter = digest_filter;
+        self
+    }
+
+    /// Sets how long the connection is kept open once there are no more
+    /// announces in flight on it.
+    pub fn with_idle_timeout(self, idle_timeout: Duration) -> Self {
+        self.with_keep_alive(KeepAlivePolicy::UntilIdle(idle_timeout))
+    }
+
+    /// Sets how long the connection is kept open once it has nothing left
+    /// to do.
+    pub fn with_keep_alive(mut self, policy: KeepAlivePolicy) -> Self {
+        self.keep_alive_policy = policy;
+        self.keep_alive = policy.initial();
+        self
+    }
+
+    /// The timeout of an outbound substream, which libp2p applies to the
+    /// negotiation and the upgrade together. The announces on it time out
+    /// sooner, `negotiation_timeout` after the substream is requested if it
+    /// is not negotiated by then, or `timeout` after it is. Only a substream
+    /// pushing a confirmation, which nothing else times out, runs into this
+    /// one.
+    fn outbound_timeout(&self) -> Duration {
+        self.negotiation_timeout + self.timeout
+    }
+
+    /// The info of a new outbound substream announcing the swaps.
+    fn next_outbound(&mut self, swap_digests: Vec<SwapDigest>) -> OutboundInfo {
+        let substream = self.next_substream;
+        self.next_substream += 1;
+
+        OutboundInfo {
+            substream,
+            swap_digests,
+        }
+    }
+
+    /// Marks the announce on the substream as resolved, returning whether
+    /// its outcome should be reported. It is not if the announce was
+    /// cancelled, or if it is not in flight at all, e.g. because its outcome
+    /// arrived already.
+    fn resolve(&mut self, substream: u64, swap_digest: &SwapDigest) -> bool {
+        // The substream may have been negotiated since we were last polled,
+        // which is reported before its outcome.
+        while let Ok(Some(info)) = self.opened.1.try_next() {
+            self.report_opened(info);
+        }
+
+        let key = (substream, swap_digest.clone());
+        let in_flight = self.in_flight.remove(&key);
+        let cancelled = self.cancelled.remove(&key);
+        if !in_flight {
+            tracing::warn!(%swap_digest, "dropping outcome of an announce that is not in flight");
+        }
+
+        in_flight && !cancelled
+    }
+
+    /// Starts the negotiation deadline of a requested announce substream.
+    fn start_negotiation(&mut self, info: &OutboundInfo) {
+        self.negotiating.insert(info.substream, info.swap_digests.clone());
+        self.negotiation_deadlines
+            .insert(info.substream, Instant::now() + self.negotiation_timeout);
+    }
+
+    /// Reports the announces still wanted on a negotiated substream as being
+    /// in flight.
+    fn report_opened(&mut self, info: OutboundInfo) {
+        self.negotiating.remove(&info.substream);
+        for swap_digest in info.swap_digests {
+            let key = (info.substream, swap_digest);
+            if self.in_flight.contains(&key) && !self.cancelled.contains(&key) {
+                self.events
+                    .push_back(HandlerEvent::OutboundSubstreamOpened { swap_digest: key.1 });
+            }
+        }
+    }
+
+    /// The substream of the announce a pushed confirmation of the swap
+    /// resolves, one whose outcome is still wanted if there is one.
+    fn pushed_for(&self, swap_digest: &SwapDigest) -> Option<u64> {
+        let substreams = self
+            .in_flight
+            .iter()
+            .filter(|(_, digest)| digest == swap_digest)
+            .map(|(substream, _)| *substream)
+            .collect::<Vec<_>>();
+
+        substreams
+            .iter()
+            .find(|substream| !self.cancelled.contains(&(**substream, swap_digest.clone())))
+            .or_else(|| substreams.first())
+            .copied()
+    }
+
+    /// Queues the announce, unless too many are queued or in flight already.
+    fn queue(&mut self, config: OutboundConfig) {
+        if self.dial_queue.len() + self.in_flight.len() >= self.max_in_flight {
+            self.events.push_back(HandlerEvent::Error(Error::QueueFull {
+                swap_digest: config.swap_digest,
+            }));
+            return;
+        }
+
+        self.dial_queue.push_back(config)
+    }
+
+    /// Keeps the connection alive while announces are queued, in flight or
+    /// waiting for our reply, and for as long as `keep_alive_policy` says
+    /// after that.
+    fn update_keep_alive(&mut self) {
+        let busy = !self.events.is_empty()
+            || !self.dial_queue.is_empty()
+            || !self.in_flight.is_empty()
+            || !self.push_queue.is_empty()
+            || !self.pushing.is_empty()
+            || !self.awaiting_reply.is_empty()
+            || !self.rejections.is_empty();
+
+        match (busy, self.keep_alive) {
+            (true, _) => self.keep_alive = KeepAlive::Yes,
+            (false, KeepAlive::Yes) => self.keep_alive = self.keep_alive_policy.idle(),
+            // Already idle, the deadline stays where it is.
+            (false, _) => {}
+        }
+    }
+
+    /// Reports the replies to inbound announces that have been sent, and
+    /// forgets those dropped unanswered.
+    fn poll_replies_sent(&mut self, cx: &mut Context<'_>) {
+        while let Poll::Ready(Some((id, sent))) = self.replies_sent.poll_next_unpin(cx) {
+            // Timed out already.
+            let reply = match self.awaiting_reply.remove(&id) {
+                Some(reply) => reply,
+                None => continue,
+            };
+
+            match sent {
+                Ok(sent) => self.events.push_back(reply.sent_event(sent)),
+                // Dropped without confirming. Unless other pipelined announces
+                // still await a reply on it, the substream is closed so the
+                // remote does not wait for its timeout.
+                Err(oneshot::Canceled) => {
+                    if Arc::strong_count(&reply.io) == 1 {
+                        if let Some(mut io) = reply.io.try_lock().and_then(|mut io| io.take()) {
+                            self.rejections.push(
+                                async move {
+                                    let _ = io.close().await;
+                                }
+                                .boxed(),
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Reports replies that have been sent and drops the substreams of those
+    /// that took too long.
+    fn expire_replies(&mut self, cx: &mut Context<'_>) {
+        self.poll_replies_sent(cx);
+
+        while let Poll::Ready(id) = self.reply_deadlines.poll_expired(cx) {
+            // Replied to in time.
+            let io = match self.awaiting_reply.get(&id) {
+                Some(reply) => reply.io.clone(),
+                None => continue,
+            };
+
+            // The reply is under way, its future holds the lock until it has
+            // been sent and `replies_sent` wakes us up once it has.
+            let mut io = match io.try_lock() {
+                Some(io) => io,
+                None => continue,
+            };
+            // A reply sent before we took the lock has been reported to
+            // `replies_sent` already, see `ReplySubstream::reply`.
+            self.poll_replies_sent(cx);
+            let reply = match self.awaiting_reply.remove(&id) {
+                Some(reply) => reply,
+                None => continue,
+            };
+
+            // Replies to pipelined announces share the substream, they all
+            // have the same deadline though.
+            io.take();
+            drop(io);
+            tracing::warn!(swap_digest = %reply.swap_digest, "no reply sent in time");
+            self.events.push_back(HandlerEvent::Error(Error::ReplyTimeout {
+                swap_digest: reply.swap_digest,
+            }));
+        }
+    }
+
+    /// Fails the announces on the substreams not negotiated in time.
+    fn expire_negotiations(&mut self, cx: &mut Context<'_>) {
+        while let Poll::Ready(substream) = self.negotiation_deadlines.poll_expired(cx) {
+            // Negotiated, or failed, in time.
+            let swap_digests = match self.negotiating.remove(&substream) {
+                Some(swap_digests) => swap_digests,
+                None => continue,
+            };
+
+            self.abandoned.insert(substream);
+            for swap_digest in swap_digests {
+                if self.resolve(substream, &swap_digest) {
+                    tracing::warn!(%swap_digest, "protocol negotiation timed out");
+                    self.events.push_back(HandlerEvent::Error(Error::Upgrade {
+                        swap_digest,
+                        error: ProtocolsHandlerUpgrErr::Timeout,
+                    }));
+                }
+            }
+        }
+    }
+
+    /// Fails the announces whose pushed confirmation did not arrive in time.
+    fn expire_pushes(&mut self, cx: &mut Context<'_>) {
+        while let Poll::Ready((substream, swap_digest)) = self.push_deadlines.poll_expired(cx) {
+            // Confirmed in time.
+            if !self.awaiting_push.remove(&(substream, swap_digest.clone())) {
+                continue;
+            }
+
+            if self.resolve(substream, &swap_digest) {
+                tracing::warn!(%swap_digest, "no confirmation pushed in time");
+                self.events.push_back(HandlerEvent::Error(Error::Timeout { swap_digest }));
+            }
         }
     }
 }
 
-/// Event produced by the `Handler`.
+/// An inbound announce waiting for the application to reply.
+struct AwaitingReply {
+    swap_digest: SwapDigest,
+    io: SharedSubstream<NegotiatedSubstream>,
+}
+
+/// Resolves with the number of an inbound announce and whether its reply was
+/// sent, see `Handler::replies_sent`.
+type ReplySent<TConfirmation> =
+    BoxFuture<'static, (u64, Result<Result<TConfirmation, io::ErrorKind>, oneshot::Canceled>)>;
+
+impl AwaitingReply {
+    /// The event reporting whether the reply could be sent.
+    fn sent_event<TConfirmation>(&self, sent: Result<TConfirmation, io::ErrorKind>) -> HandlerEvent<TConfirmation> {
+        let swap_digest = self.swap_digest.clone();
+
+        match sent {
+            Ok(swap_id) => HandlerEvent::ConfirmationSent {
+                swap_digest,
+                swap_id,
+            },
+            Err(kind) => HandlerEvent::Error(Error::ConfirmationSendFailed {
+                swap_digest,
+                error: kind.into(),
+            }),
+        }
+    }
+}
+
+/// Inbound upgrade that returns the errors of `InboundConfig` as its output.
+///
+/// libp2p drops the errors of inbound upgrades before they reach the
+/// `ProtocolsHandler`, this is how the handler still gets to report them.
+#[derive(Debug, Clone)]
+pub struct ReportInboundErrors<TConfirmation = SwapId>(InboundConfig, Option<ConfirmListen<TConfirmation>>);
+
+impl<TConfirmation> ReportInboundErrors<TConfirmation> {
+    pub fn new(config: InboundConfig) -> Self {
+        ReportInboundErrors(config, None)
+    }
+
+    /// Also accepts confirmations the remote pushes with `listen`.
+    pub fn with_pushed_confirmations(mut self, listen: ConfirmListen<TConfirmation>) -> Self {
+        self.1 = Some(listen);
+        self
+    }
+}
+
+impl<TConfirmation> UpgradeInfo for ReportInboundErrors<TConfirmation> {
+    type Info = ProtocolId;
+    type InfoIter = vec::IntoIter<Self::Info>;
+
+    fn protocol_info(&self) -> Self::InfoIter {
+        let mut ids = self.0.protocol_info().collect::<Vec<_>>();
+        if self.1.is_some() {
+            ids.push(ProtocolId::confirm());
+        }
+
+        ids.into_iter()
+    }
+}
+
+impl<C, TConfirmation> InboundUpgrade<C> for ReportInboundErrors<TConfirmation>
+where
+    C: AsyncRead + Unpin + Send + 'static,
+    TConfirmation: Confirmation,
+{
+    type Output = Result<Inbound<C, TConfirmation>, protocol::Error>;
+    type Error = Infallible;
+    type Future = BoxFuture<'static, Result<Self::Output, Self::Error>>;
+
+    fn upgrade_inbound(self, socket: C, info: Self::Info) -> Self::Future {
+        match self.1 {
+            Some(listen) if info.is_confirm() => listen
+                .upgrade_inbound(socket, info)
+                .map_ok(Inbound::Confirmation)
+                .map(Ok)
+                .boxed(),
+            _ => self.0.read_all(socket, info).map_ok(Inbound::Announces).map(Ok).boxed(),
+        }
+    }
+}
+
+/// What the remote sent on an inbound substream.
+pub enum Inbound<C, TConfirmation = SwapId> {
+    /// Announces awaiting our replies.
+    Announces(Vec<ReplySubstream<C, TConfirmation>>),
+    /// The confirmation of one of our announces, pushed by the remote.
+    Confirmation(Confirmed<TConfirmation>),
+}
+
+/// Outbound upgrade announcing either a single swap or several pipelined
+/// ones, which are confirmed with a `TConfirmation`, or pushing our
+/// confirmation of an announce of the remote.
+///
+/// The handler is told the announced digests as soon as the substream has
+/// been negotiated.
+#[derive(Debug, Clone)]
+pub enum OutboundAnnounce<TConfirmation = SwapId> {
+    Single(OutboundConfig, Opened, PhantomData<fn() -> TConfirmation>),
+    Pipelined(PipelinedConfig, Opened, PhantomData<fn() -> TConfirmation>),
+    /// Announces a single swap whose confirmation the remote pushes.
+    AnnounceOnly(OutboundConfig, Opened),
+    Push(PushConfirmation<TConfirmation>),
+}
+
+/// The outbound substream an upgrade is requested for, and the swaps it
+/// announces.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutboundInfo {
+    /// Tells the substreams of a handler apart, the same swap is on several
+    /// of them if it was cancelled and announced again.
+    pub substream: u64,
+    pub swap_digests: Vec<SwapDigest>,
+}
+
+/// Tells the handler that the substream of an `OutboundInfo` has been
+/// negotiated.
+#[derive(Debug, Clone)]
+pub struct Opened {
+    info: OutboundInfo,
+    sender: mpsc::UnboundedSender<OutboundInfo>,
+}
+
+impl Opened {
+    fn new(info: OutboundInfo, sender: mpsc::UnboundedSender<OutboundInfo>) -> Self {
+        Opened { info, sender }
+    }
+
+    fn notify(self) {
+        // The handler may be gone already, nobody cares then.
+        let _ = self.sender.unbounded_send(self.info);
+    }
+}
+
+/// What an `OutboundAnnounce` achieved.
 #[derive(Debug)]
-pub enum HandlerEvent {
+pub enum Outbound<TConfirmation = SwapId> {
+    /// The announced swaps were confirmed, in the order they were announced.
+    Confirmed(Vec<Confirmed<TConfirmation>>),
+    /// The swap was announced, its confirmation is pushed by the remote.
+    Announced(SwapDigest),
+    /// Our confirmation of the swap was pushed to the remote.
+    Pushed(SwapDigest, TConfirmation),
+}
+
+impl<TConfirmation> UpgradeInfo for OutboundAnnounce<TConfirmation> {
+    type Info = ProtocolId;
+    type InfoIter = vec::IntoIter<Self::Info>;
+
+    fn protocol_info(&self) -> Self::InfoIter {
+        match self {
+            OutboundAnnounce::Single(config, ..) => config.protocol_info(),
+            OutboundAnnounce::Pipelined(config, ..) => {
+                config.protocol_info().collect::<Vec<_>>().into_iter()
+            }
+            OutboundAnnounce::AnnounceOnly(config, _) => config.protocol_info(),
+            OutboundAnnounce::Push(push) => push.protocol_info().collect::<Vec<_>>().into_iter(),
+        }
+    }
+}
+
+impl<C, TConfirmation> OutboundUpgrade<C> for OutboundAnnounce<TConfirmation>
+where
+    C: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    TConfirmation: Confirmation,
+{
+    type Output = Outbound<TConfirmation>;
+    type Error = protocol::Error;
+    type Future = BoxFuture<'static, Result<Self::Output, Self::Error>>;
+
+    fn upgrade_outbound(self, socket: C, info: Self::Info) -> Self::Future {
+        match self {
+            OutboundAnnounce::Single(config, opened, _) => {
+                opened.notify();
+                config
+                    .announce(socket, info)
+                    .map_ok(|confirmed| Outbound::Confirmed(vec![confirmed]))
+                    .boxed()
+            }
+            OutboundAnnounce::Pipelined(config, opened, _) => {
+                opened.notify();
+                config.announce(socket, info).map_ok(Outbound::Confirmed).boxed()
+            }
+            OutboundAnnounce::AnnounceOnly(config, opened) => {
+                let swap_digest = config.swap_digest.clone();
+                opened.notify();
+                config
+                    .announce_only(socket, info)
+                    .map_ok(|()| Outbound::Announced(swap_digest))
+                    .boxed()
+            }
+            OutboundAnnounce::Push(push) => {
+                let swap_digest = push.swap_digest.clone();
+                let swap_id = push.swap_id.clone();
+                push.upgrade_outbound(socket, info)
+                    .map_ok(|()| Outbound::Pushed(swap_digest, swap_id))
+                    .boxed()
+            }
+        }
+    }
+}
+
+/// Event sent to the `Handler` by the behaviour.
+#[derive(Clone, Debug)]
+pub enum HandlerInEvent<TConfirmation = SwapId> {
+    /// Announce a swap on a new outbound substream.
+    Announce(OutboundConfig),
+    /// Announce several swaps at once, they share a substream if pipelining
+    /// is enabled.
+    AnnounceAll(Vec<OutboundConfig>),
+    /// Abandon the announce of the swap with this digest.
+    Cancel(SwapDigest),
+    /// Refuse inbound announce substreams until the given instant.
+    RefuseInboundUntil(Instant),
+    /// Reject inbound announces that do not pass the filter from now on.
+    SetDigestFilter(DigestFilter),
+    /// Push our confirmation of an announce the remote made on a substream
+    /// of `protocol::CONFIRM_PROTOCOL`.
+    PushConfirmation {
+        swap_digest: SwapDigest,
+        swap_id: TConfirmation,
+    },
+}
+
+/// Event produced by the `Handler`.
+pub enum HandlerEvent<TConfirmation = SwapId> {
     /// This event created when a confirmation message containing a `swap_id` is
     /// received in response to an announce message containing a
     /// `swap_digest`. The Event contains both the swap id and
     /// the swap digest.
-    ReceivedConfirmation(Confirmed),
+    ReceivedConfirmation(Confirmed<TConfirmation>),
 
     /// The event is created when a remote sends a `swap_digest`. The event
     /// contains a reply substream for the receiver to send back the
     /// `swap_id` that corresponds to the swap digest.
-    AwaitingConfirmation(Box<ReplySubstream<NegotiatedSubstream>>),
+    AwaitingConfirmation(Box<ReplySubstream<NegotiatedSubstream, TConfirmation>>),
+
+    /// The substream for an outbound announce has been negotiated, the
+    /// announce is sent and the confirmation awaited.
+    OutboundSubstreamOpened { swap_digest: SwapDigest },
+
+    /// The confirmation to an inbound announce has been sent.
+    ConfirmationSent {
+        swap_digest: SwapDigest,
+        swap_id: TConfirmation,
+    },
 
     /// Failed to announce swap to peer.
     Error(Error),
 }
 
-impl ProtocolsHandler for Handler {
-    type InEvent = OutboundConfig;
-    type OutEvent = HandlerEvent;
+/// Shows digests in their short form, the full ones clutter logs.
+impl<TConfirmation: fmt::Debug + Clone> fmt::Debug for HandlerEvent<TConfirmation> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandlerEvent::ReceivedConfirmation(confirmed) => f
+                .debug_struct("ReceivedConfirmation")
+                .field("swap_digest", &format_args!("{}", confirmed.swap_digest().short()))
+                .field("swap_id", &confirmed.swap_id())
+                .field("version", &confirmed.version())
+                .finish(),
+            HandlerEvent::AwaitingConfirmation(sender) => f
+                .debug_struct("AwaitingConfirmation")
+                .field("swap_digest", &format_args!("{}", sender.swap_digest.short()))
+                .field("version", &sender.version)
+                .finish(),
+            HandlerEvent::OutboundSubstreamOpened { swap_digest } => f
+                .debug_struct("OutboundSubstreamOpened")
+                .field("swap_digest", &format_args!("{}", swap_digest.short()))
+                .finish(),
+            HandlerEvent::ConfirmationSent {
+                swap_digest,
+                swap_id,
+            } => f
+                .debug_struct("ConfirmationSent")
+                .field("swap_digest", &format_args!("{}", swap_digest.short()))
+                .field("swap_id", swap_id)
+                .finish(),
+            HandlerEvent::Error(error) => f.debug_tuple("Error").field(error).finish(),
+        }
+    }
+}
+
+/// A concise line for operators, e.g. `received confirmation for 1220b94d`.
+impl<TConfirmation: Clone> fmt::Display for HandlerEvent<TConfirmation> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandlerEvent::ReceivedConfirmation(confirmed) => {
+                write!(f, "received confirmation for {}", confirmed.swap_digest().short())
+            }
+            HandlerEvent::AwaitingConfirmation(sender) => write!(
+                f,
+                "received announcement of {}, awaiting our confirmation",
+                sender.swap_digest.short()
+            ),
+            HandlerEvent::OutboundSubstreamOpened { swap_digest } => write!(
+                f,
+                "announce of {} sent, awaiting confirmation",
+                swap_digest.short()
+            ),
+            HandlerEvent::ConfirmationSent { swap_digest, .. } => {
+                write!(f, "sent confirmation for {}", swap_digest.short())
+            }
+            HandlerEvent::Error(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl<TConfirmation: Confirmation> ProtocolsHandler for Handler<TConfirmation> {
+    type InEvent = HandlerInEvent<TConfirmation>;
+    type OutEvent = HandlerEvent<TConfirmation>;
     type Error = Error;
-    type InboundProtocol = InboundConfig;
-    type OutboundProtocol = OutboundConfig;
-    type OutboundOpenInfo = ();
+    type InboundProtocol = ReportInboundErrors<TConfirmation>;
+    type OutboundProtocol = OutboundAnnounce<TConfirmation>;
+    type OutboundOpenInfo = OutboundInfo;
 
     fn listen_protocol(&self) -> SubstreamProtocol<Self::InboundProtocol> {
-        SubstreamProtocol::new(InboundConfig::default())
+        let mut versions = self.versions.clone();
+        if self.pipelining && !versions.contains(&ProtocolVersion::Pipelined) {
+            versions.push(ProtocolVersion::Pipelined);
+        }
+        // Offering no protocols makes the negotiation of inbound substreams
+        // fail.
+        if !self.listen || matches!(self.refuse_inbound_until, Some(until) if Instant::now() < until) {
+            versions.clear();
+        }
+
+        let mut upgrade = ReportInboundErrors::new(InboundConfig {
+            versions,
+            prefix: self.prefix.clone(),
+            read_timeout: self.read_timeout,
+            inspector: self.inspector.clone(),
+        });
+        // Pushed confirmations answer our own announces, they are accepted
+        // even while announces are not.
+        if self.push_confirmations {
+            let mut listen = ConfirmListen::default();
+            listen.max_confirmation_size = self.max_confirmation_size;
+            listen.read_timeout = self.read_timeout;
+            listen.inspector = self.inspector.clone();
+            upgrade = upgrade.with_pushed_confirmations(listen);
+        }
+
+        SubstreamProtocol::new(upgrade)
     }
 
     fn inject_fully_negotiated_inbound(
         &mut self,
         sender: <Self::InboundProtocol as InboundUpgrade<NegotiatedSubstream>>::Output,
     ) {
-        self.events
-            .push_back(HandlerEvent::AwaitingConfirmation(Box::new(sender)))
+        let senders = match sender {
+            Ok(Inbound::Announces(senders)) => senders,
+            Ok(Inbound::Confirmation(confirmed)) => {
+                let swap_digest = confirmed.swap_digest().clone();
+                let _span = tracing::debug_span!("outbound_announce", %swap_digest).entered();
+                // The remote does not know our substreams, the confirmation
+                // is for the announce of the swap that was not cancelled.
+                let substream = match self.pushed_for(&swap_digest) {
+                    Some(substream) => substream,
+                    None => {
+                        tracing::warn!("dropping outcome of an announce that is not in flight");
+                        return;
+                    }
+                };
+                self.awaiting_push.remove(&(substream, swap_digest.clone()));
+
+                if self.resolve(substream, &swap_digest) {
+                    tracing::debug!(swap_id = ?confirmed.swap_id(), "received pushed confirmation");
+                    self.events.push_back(HandlerEvent::ReceivedConfirmation(confirmed));
+                }
+                return;
+            }
+            Err(error) => {
+                tracing::warn!(%error, "inbound upgrade failed");
+                self.events
+                    .push_back(HandlerEvent::Error(Error::InboundUpgrade(error)));
+                return;
+            }
+        };
+
+        for mut sender in senders {
+            let _span = tracing::debug_span!("inbound_announce", swap_digest = %sender.swap_digest).entered();
+            if let Some(filter) = &self.digest_filter {
+                if !filter.accepts(&sender.swap_digest) {
+                    tracing::debug!("rejecting announce of unknown swap");
+                    self.rejections.push(
+                        sender
+                            .reject(RejectReason::UnknownDigest)
+                            .map(|result| {
+                                if let Err(error) = result {
+                                    tracing::debug!(%error, "failed to send rejection");
+                                }
+                            })
+                            .boxed(),
+                    );
+                    continue;
+                }
+            }
+
+            tracing::debug!(version = ?sender.version, "received announce, awaiting confirmation");
+
+            let id = self.next_reply;
+            self.next_reply += 1;
+            self.awaiting_reply.insert(id, AwaitingReply {
+                swap_digest: sender.swap_digest.clone(),
+                io: sender.shared_io(),
+            });
+            self.replies_sent
+                .push(sender.on_sent().map(move |sent| (id, sent)).boxed());
+            self.reply_deadlines.insert(id, Instant::now() + self.reply_timeout);
+
+            self.events
+                .push_back(HandlerEvent::AwaitingConfirmation(Box::new(sender)))
+        }
     }
 
     fn inject_fully_negotiated_outbound(
         &mut self,
-        confirmed: <Self::OutboundProtocol as OutboundUpgrade<NegotiatedSubstream>>::Output,
-        _info: Self::OutboundOpenInfo,
+        outbound: <Self::OutboundProtocol as OutboundUpgrade<NegotiatedSubstream>>::Output,
+        // The upgrade either confirms every announced swap or fails.
+        info: Self::OutboundOpenInfo,
     ) {
-        self.events
-            .push_back(HandlerEvent::ReceivedConfirmation(confirmed));
+        self.negotiating.remove(&info.substream);
+        if self.abandoned.remove(&info.substream) {
+            tracing::debug!("dropping outcome of a substream negotiated too late");
+            return;
+        }
+
+        let confirmations = match outbound {
+            Outbound::Confirmed(confirmations) => confirmations,
+            Outbound::Announced(swap_digest) => {
+                let key = (info.substream, swap_digest);
+                // Unless the pushed confirmation beat us to it.
+                if self.in_flight.contains(&key) {
+                    self.awaiting_push.insert(key.clone());
+                    self.push_deadlines.insert(key, Instant::now() + self.timeout);
+                }
+                return;
+            }
+            Outbound::Pushed(swap_digest, swap_id) => {
+                self.pushing.remove(&swap_digest);
+                tracing::debug!(%swap_digest, "pushed confirmation");
+                self.events.push_back(HandlerEvent::ConfirmationSent {
+                    swap_digest,
+                    swap_id,
+                });
+                return;
+            }
+        };
+
+        for confirmed in confirmations {
+            let _span = tracing::debug_span!("outbound_announce", swap_digest = %confirmed.swap_digest()).entered();
+
+            if !self.resolve(info.substream, confirmed.swap_digest()) {
+                tracing::debug!("dropping confirmation of cancelled announce");
+                continue;
+            }
+
+            tracing::debug!(swap_id = ?confirmed.swap_id(), "received confirmation");
+            self.events
+                .push_back(HandlerEvent::ReceivedConfirmation(confirmed));
+        }
     }
 
     fn inject_event(&mut self, event: Self::InEvent) {
-        self.dial_queue.push_back(event);
+        match event {
+            HandlerInEvent::Announce(config) => self.queue(config),
+            HandlerInEvent::AnnounceAll(configs) => {
+                for config in configs {
+                    self.queue(config)
+                }
+            }
+            HandlerInEvent::Cancel(swap_digest) => {
+                self.dial_queue
+                    .retain(|config| config.swap_digest != swap_digest);
+
+                // We cannot abort a substream that is already being upgraded,
+                // instead we drop whatever it produces.
+                let cancelled = self
+                    .in_flight
+                    .iter()
+                    .filter(|(_, digest)| *digest == swap_digest)
+                    .cloned()
+                    .collect::<Vec<_>>();
+                self.cancelled.extend(cancelled);
+            }
+            HandlerInEvent::RefuseInboundUntil(until) => self.refuse_inbound_until = Some(until),
+            HandlerInEvent::SetDigestFilter(filter) => self.digest_filter = Some(filter),
+            HandlerInEvent::PushConfirmation {
+                swap_digest,
+                swap_id,
+            } => {
+                let mut push = PushConfirmation::new(swap_digest, swap_id);
+                push.timeout = self.timeout;
+                push.inspector = self.inspector.clone();
+                self.push_queue.push_back(push);
+            }
+        }
     }
 
     fn inject_dial_upgrade_error(
         &mut self,
-        _info: Self::OutboundOpenInfo,
+        info: Self::OutboundOpenInfo,
         err: ProtocolsHandlerUpgrErr<
             <Self::OutboundProtocol as OutboundUpgrade<NegotiatedSubstream>>::Error,
         >,
     ) {
-        self.events
-            .push_back(HandlerEvent::Error(Error::Upgrade(err)));
+        self.negotiating.remove(&info.substream);
+        if self.abandoned.remove(&info.substream) {
+            tracing::debug!("dropping error of a substream not negotiated in time");
+            return;
+        }
+
+        let timed_out = matches!(
+            err,
+            ProtocolsHandlerUpgrErr::Upgrade(UpgradeError::Apply(protocol::Error::Timeout))
+        );
+        let lost_substream = lost_substream(&err);
+        // Only one of the announces on a pipelined substream gets the error
+        // itself, it cannot be cloned.
+        let mut err = Some(err);
+
+        for swap_digest in info.swap_digests {
+            if self.pushing.remove(&swap_digest) {
+                if let Some(error) = err.take() {
+                    tracing::warn!(%swap_digest, %error, "failed to push confirmation");
+                    self.events
+                        .push_back(HandlerEvent::Error(Error::PushFailed { swap_digest, error }));
+                }
+                continue;
+            }
+
+            let _span = tracing::debug_span!("outbound_announce", swap_digest = %swap_digest).entered();
+
+            if !self.resolve(info.substream, &swap_digest) {
+                tracing::debug!("dropping error of cancelled announce");
+                continue;
+            }
+
+            let error = if timed_out {
+                tracing::warn!("no confirmation received in time");
+                Error::Timeout { swap_digest }
+            } else if let Some(error) = err.take() {
+                match error {
+                    ProtocolsHandlerUpgrErr::Upgrade(UpgradeError::Apply(protocol::Error::Rejected(reason))) => {
+                        tracing::debug!(?reason, "announce rejected");
+                        Error::Rejected {
+                            swap_digest,
+                            reason,
+                        }
+                    }
+                    error => {
+                        tracing::warn!(%error, "outbound upgrade failed");
+                        Error::Upgrade { swap_digest, error }
+                    }
+                }
+            } else {
+                Error::PipelineFailed {
+                    swap_digest,
+                    lost_substream,
+                }
+            };
+
+            self.events.push_back(HandlerEvent::Error(error));
+        }
     }
 
     fn connection_keep_alive(&self) -> KeepAlive {
-        KeepAlive::Yes
+        self.keep_alive
     }
 
     #[allow(clippy::type_complexity)]
     fn poll(
         &mut self,
-        _: &mut Context<'_>,
+        cx: &mut Context<'_>,
     ) -> Poll<
         ProtocolsHandlerEvent<
             Self::OutboundProtocol,
             Self::OutboundOpenInfo,
-            HandlerEvent,
+            HandlerEvent<TConfirmation>,
             Self::Error,
         >,
     > {
+        self.expire_replies(cx);
+        self.expire_pushes(cx);
+        while let Poll::Ready(Some(())) = self.rejections.poll_next_unpin(cx) {}
+        while let Poll::Ready(Some(info)) = self.opened.1.poll_next_unpin(cx) {
+            self.report_opened(info);
+        }
+        self.expire_negotiations(cx);
+        self.update_keep_alive();
+
+        // Errors only concern a single announce, the connection stays usable
+        // for other announces so we never close it.
         if let Some(event) = self.events.pop_front() {
-            if let HandlerEvent::Error(err) = event {
-                return Poll::Ready(ProtocolsHandlerEvent::Close(err));
-            };
             return Poll::Ready(ProtocolsHandlerEvent::Custom(event));
         }
 
-        if let Some(upgrade) = self.dial_queue.pop_front() {
+        if let Some(push) = self.push_queue.pop_front() {
+            let swap_digest = push.swap_digest.clone();
+            self.pushing.insert(swap_digest.clone());
+
+            tracing::debug!(%swap_digest, "opening substream to push confirmation");
+
             return Poll::Ready(ProtocolsHandlerEvent::OutboundSubstreamRequest {
-                protocol: SubstreamProtocol::new(upgrade),
-                info: (),
+                protocol: SubstreamProtocol::new(OutboundAnnounce::Push(push))
+                    .with_timeout(self.outbound_timeout()),
+                info: self.next_outbound(vec![swap_digest]),
+            });
+        }
+
+        // Pipelined announces cannot carry a time-to-live, announces with one
+        // go out on a substream of their own.
+        let pipelinable = self
+            .dial_queue
+            .iter()
+            .take(protocol::MAX_PIPELINED)
+            .take_while(|config| config.ttl.is_none())
+            .count();
+        if self.pipelining && !self.push_confirmations && pipelinable > 0 {
+            let count = pipelinable;
+            let swap_digests = self
+                .dial_queue
+                .drain(..count)
+                .map(|config| config.swap_digest)
+                .collect::<Vec<_>>();
+            let info = self.next_outbound(swap_digests.clone());
+            self.in_flight
+                .extend(swap_digests.iter().map(|swap_digest| (info.substream, swap_digest.clone())));
+            self.start_negotiation(&info);
+
+            let mut upgrade = PipelinedConfig::new(swap_digests);
+            upgrade.timeout = self.timeout;
+            upgrade.prefix = self.prefix.clone();
+            upgrade.max_confirmation_size = self.max_confirmation_size;
+            upgrade.inspector = self.inspector.clone();
+
+            tracing::debug!(count, "opening pipelined outbound substream");
+
+            return Poll::Ready(ProtocolsHandlerEvent::OutboundSubstreamRequest {
+                protocol: SubstreamProtocol::new(OutboundAnnounce::Pipelined(
+                    upgrade,
+                    Opened::new(info.clone(), self.opened.0.clone()),
+                    PhantomData,
+                ))
+                .with_timeout(self.outbound_timeout()),
+                info,
+            });
+        }
+
+        if let Some(mut upgrade) = self.dial_queue.pop_front() {
+            upgrade.timeout = self.timeout;
+            upgrade.versions = self.versions.clone();
+            upgrade.prefix = self.prefix.clone();
+            upgrade.max_confirmation_size = self.max_confirmation_size;
+            upgrade.inspector = self.inspector.clone();
+            let swap_digest = upgrade.swap_digest.clone();
+            let info = self.next_outbound(vec![swap_digest.clone()]);
+            self.in_flight.insert((info.substream, swap_digest.clone()));
+            self.start_negotiation(&info);
+
+            tracing::debug!(%swap_digest, "opening outbound substream");
+
+            let opened = Opened::new(info.clone(), self.opened.0.clone());
+            let upgrade = if self.push_confirmations {
+                OutboundAnnounce::AnnounceOnly(upgrade, opened)
+            } else {
+                OutboundAnnounce::Single(upgrade, opened, PhantomData)
+            };
+
+            return Poll::Ready(ProtocolsHandlerEvent::OutboundSubstreamRequest {
+                protocol: SubstreamProtocol::new(upgrade).with_timeout(self.outbound_timeout()),
+                info,
             });
         }
 
@@ -126,8 +1254,389 @@ impl ProtocolsHandler for Handler {
     }
 }
 
+/// Whether an outbound upgrade failed because its substream went away, as it
+/// does when the connection closes.
+pub(crate) fn lost_substream(error: &ProtocolsHandlerUpgrErr<protocol::Error>) -> bool {
+    match error {
+        ProtocolsHandlerUpgrErr::Upgrade(UpgradeError::Apply(error)) => {
+            error.code() == ErrorCode::Io || matches!(error, protocol::Error::ClosedWithoutConfirmation)
+        }
+        ProtocolsHandlerUpgrErr::Upgrade(UpgradeError::Select(NegotiationError::ProtocolError(_))) => true,
+        _ => false,
+    }
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
-    #[error("outbound upgrade failed")]
-    Upgrade(#[from] ProtocolsHandlerUpgrErr<protocol::Error>),
+    #[error("outbound upgrade failed for swap {swap_digest}")]
+    Upgrade {
+        swap_digest: SwapDigest,
+        #[source]
+        error: ProtocolsHandlerUpgrErr<protocol::Error>,
+    },
+    #[error("swap {swap_digest} was pipelined on a substream whose upgrade failed")]
+    PipelineFailed {
+        swap_digest: SwapDigest,
+        /// Whether the upgrade failed because the substream went away, see
+        /// `lost_substream`.
+        lost_substream: bool,
+    },
+    #[error("swap {swap_digest} was rejected: {reason:?}")]
+    Rejected {
+        swap_digest: SwapDigest,
+        reason: RejectReason,
+    },
+    #[error("no confirmation received for swap {swap_digest}")]
+    Timeout { swap_digest: SwapDigest },
+    #[error("too many queued announces, dropped swap {swap_digest}")]
+    QueueFull { swap_digest: SwapDigest },
+    #[error("no reply sent in time for swap {swap_digest}")]
+    ReplyTimeout { swap_digest: SwapDigest },
+    #[error("failed to push the confirmation for swap {swap_digest}")]
+    PushFailed {
+        swap_digest: SwapDigest,
+        #[source]
+        error: ProtocolsHandlerUpgrErr<protocol::Error>,
+    },
+    #[error("failed to send the confirmation for swap {swap_digest}")]
+    ConfirmationSendFailed {
+        swap_digest: SwapDigest,
+        #[source]
+        error: io::Error,
+    },
+    #[error("failed to receive an announce")]
+    InboundUpgrade(#[source] protocol::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::multihash::Sha2_256;
+
+    fn swap_digest(i: usize) -> SwapDigest {
+        SwapDigest::new(Sha2_256::digest(&i.to_be_bytes()))
+    }
+
+    /// Polls the handler until it is pending, returning the outbound
+    /// substreams it requested.
+    fn requested_substreams(handler: &mut Handler) -> Vec<OutboundInfo> {
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+        let mut requested = Vec::new();
+        while let Poll::Ready(event) = handler.poll(&mut cx) {
+            if let ProtocolsHandlerEvent::OutboundSubstreamRequest { info, .. } = event {
+                requested.push(info);
+            }
+        }
+
+        requested
+    }
+
+    #[test]
+    fn confirmation_is_reported_for_the_announced_digest() {
+        let mut handler = Handler::default();
+        // As if the outbound substream for the announce had been requested.
+        handler.in_flight.insert((0, swap_digest(0)));
+        let info = OutboundInfo {
+            substream: 0,
+            swap_digests: vec![swap_digest(0)],
+        };
+
+        let confirmed = Confirmed::new(swap_digest(0), SwapId::default(), ProtocolVersion::V2);
+        handler.inject_fully_negotiated_outbound(Outbound::Confirmed(vec![confirmed.clone()]), info);
+
+        match handler.events.pop_front() {
+            Some(HandlerEvent::ReceivedConfirmation(received)) => {
+                assert_eq!(received, confirmed);
+                assert_eq!(received.swap_digest(), &swap_digest(0));
+            }
+            other => panic!("expected a confirmation, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn confirmation_arriving_after_the_cancel_is_dropped() {
+        let mut handler = Handler::default();
+        handler.inject_event(HandlerInEvent::Announce(OutboundConfig::new(swap_digest(0))));
+        let info = requested_substreams(&mut handler).remove(0);
+
+        handler.inject_event(HandlerInEvent::Cancel(swap_digest(0)));
+        let confirmed = Confirmed::new(swap_digest(0), SwapId::default(), ProtocolVersion::V2);
+        handler.inject_fully_negotiated_outbound(Outbound::Confirmed(vec![confirmed.clone()]), info.clone());
+        // The announce was resolved by the first, a second one is not in flight.
+        handler.inject_fully_negotiated_outbound(Outbound::Confirmed(vec![confirmed]), info);
+
+        assert!(handler.events.is_empty());
+        assert!(handler.in_flight.is_empty());
+        assert!(handler.cancelled.is_empty());
+    }
+
+    #[test]
+    fn swap_announced_again_after_the_cancel_is_confirmed() {
+        let mut handler = Handler::default();
+        handler.inject_event(HandlerInEvent::Announce(OutboundConfig::new(swap_digest(0))));
+        let cancelled = requested_substreams(&mut handler).remove(0);
+        handler.inject_event(HandlerInEvent::Cancel(swap_digest(0)));
+        handler.inject_event(HandlerInEvent::Announce(OutboundConfig::new(swap_digest(0))));
+        let announced_again = requested_substreams(&mut handler).remove(0);
+
+        // The cancelled substream resolving first leaves the other one be.
+        let confirmed = Confirmed::new(swap_digest(0), SwapId::default(), ProtocolVersion::V2);
+        handler.inject_fully_negotiated_outbound(Outbound::Confirmed(vec![confirmed.clone()]), cancelled);
+        assert!(handler.events.is_empty());
+        handler.inject_fully_negotiated_outbound(
+            Outbound::Confirmed(vec![confirmed.clone()]),
+            announced_again,
+        );
+
+        match handler.events.pop_front() {
+            Some(HandlerEvent::ReceivedConfirmation(received)) => assert_eq!(received, confirmed),
+            other => panic!("expected a confirmation, got {:?}", other),
+        }
+        assert!(handler.in_flight.is_empty());
+        assert!(handler.cancelled.is_empty());
+    }
+
+    #[test]
+    fn malformed_inbound_announce_is_reported() {
+        let frame = b"not a swap digest";
+        let mut bytes = vec![frame.len() as u8 + 1, protocol::FRAME_MAGIC];
+        bytes.extend_from_slice(frame);
+
+        let upgrade = ReportInboundErrors::<SwapId>::new(InboundConfig::default())
+            .upgrade_inbound(futures::io::Cursor::new(bytes), ProtocolVersion::V1.into());
+        let error = match async_std::task::block_on(upgrade) {
+            Ok(Err(error)) => error,
+            Ok(Ok(_)) => panic!("inbound upgrade accepted a malformed frame"),
+            Err(infallible) => match infallible {},
+        };
+
+        let mut handler = Handler::default();
+        handler.inject_fully_negotiated_inbound(Err(error));
+
+        match handler.events.pop_front() {
+            Some(HandlerEvent::Error(Error::InboundUpgrade(protocol::Error::Serde(_)))) => {}
+            other => panic!("expected an inbound upgrade error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn replies_not_sent_yet_are_not_polled_again() {
+        let mut handler = Handler::default();
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+        let polls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+
+        for id in 0..1_000 {
+            handler.awaiting_reply.insert(id, AwaitingReply {
+                swap_digest: swap_digest(id as usize),
+                io: Arc::new(futures::lock::Mutex::new(None)),
+            });
+            let polls = polls.clone();
+            handler.replies_sent.push(
+                future::poll_fn(move |_| {
+                    polls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                    Poll::Pending
+                })
+                .boxed(),
+            );
+        }
+        let (sent, on_sent) = oneshot::channel();
+        handler.awaiting_reply.insert(1_000, AwaitingReply {
+            swap_digest: swap_digest(1_000),
+            io: Arc::new(futures::lock::Mutex::new(None)),
+        });
+        handler.replies_sent.push(on_sent.map(|sent| (1_000, sent)).boxed());
+
+        for _ in 0..100 {
+            while handler.poll(&mut cx).is_ready() {}
+        }
+        sent.send(Ok(SwapId::default())).unwrap();
+        let event = match handler.poll(&mut cx) {
+            Poll::Ready(ProtocolsHandlerEvent::Custom(event)) => event,
+            _ => panic!("expected the sent reply to be reported"),
+        };
+
+        // Each was polled once when it was added, and never woken since.
+        assert_eq!(polls.load(std::sync::atomic::Ordering::SeqCst), 1_000);
+        assert!(matches!(event, HandlerEvent::ConfirmationSent { swap_digest: digest, .. } if digest == swap_digest(1_000)));
+        assert_eq!(handler.awaiting_reply.len(), 1_000);
+    }
+
+    #[test]
+    fn connection_goes_idle_after_completed_announce() {
+        let idle_timeout = Duration::from_millis(50);
+        let mut handler = Handler::default().with_idle_timeout(idle_timeout);
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+
+        handler.inject_event(HandlerInEvent::Announce(OutboundConfig::new(swap_digest(0))));
+        let info = match handler.poll(&mut cx) {
+            Poll::Ready(ProtocolsHandlerEvent::OutboundSubstreamRequest { info, .. }) => info,
+            _ => panic!("expected the substream of the announce to be requested"),
+        };
+        assert!(handler.poll(&mut cx).is_pending());
+        assert_eq!(handler.connection_keep_alive(), KeepAlive::Yes);
+
+        let confirmed = Confirmed::new(swap_digest(0), SwapId::default(), ProtocolVersion::V2);
+        handler.inject_fully_negotiated_outbound(Outbound::Confirmed(vec![confirmed]), info);
+        while handler.poll(&mut cx).is_ready() {}
+
+        let deadline = match handler.connection_keep_alive() {
+            KeepAlive::Until(deadline) => deadline,
+            other => panic!("expected the connection to go idle, got {:?}", other),
+        };
+        assert!(deadline <= Instant::now() + idle_timeout);
+
+        std::thread::sleep(idle_timeout);
+        assert!(deadline <= Instant::now());
+    }
+
+    #[test]
+    fn idle_connection_reports_the_keep_alive_of_its_policy() {
+        let idle_timeout = Duration::from_millis(50);
+        let policies = [
+            KeepAlivePolicy::UntilIdle(idle_timeout),
+            KeepAlivePolicy::Always,
+            KeepAlivePolicy::MinimalOnce,
+        ];
+
+        for policy in policies {
+            let mut handler = Handler::default().with_keep_alive(policy);
+            let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+
+            handler.inject_event(HandlerInEvent::Announce(OutboundConfig::new(swap_digest(0))));
+            let info = requested_substreams(&mut handler).remove(0);
+            let confirmed = Confirmed::new(swap_digest(0), SwapId::default(), ProtocolVersion::V2);
+            handler.inject_fully_negotiated_outbound(Outbound::Confirmed(vec![confirmed]), info);
+            while handler.poll(&mut cx).is_ready() {}
+
+            match (policy, handler.connection_keep_alive()) {
+                (KeepAlivePolicy::UntilIdle(_), KeepAlive::Until(deadline)) => {
+                    assert!(deadline <= Instant::now() + idle_timeout)
+                }
+                (KeepAlivePolicy::Always, KeepAlive::Yes) => {}
+                (KeepAlivePolicy::MinimalOnce, KeepAlive::No) => {}
+                (policy, keep_alive) => panic!("{:?} kept the connection alive {:?}", policy, keep_alive),
+            }
+        }
+    }
+
+    #[test]
+    fn announces_beyond_the_in_flight_limit_are_dropped() {
+        let mut handler = Handler::default().with_max_in_flight(2);
+
+        for i in 0..3 {
+            handler.inject_event(HandlerInEvent::Announce(OutboundConfig::new(swap_digest(i))));
+        }
+
+        assert_eq!(handler.dial_queue.len(), 2);
+        match handler.events.pop_front() {
+            Some(HandlerEvent::Error(Error::QueueFull { swap_digest: dropped })) => {
+                assert_eq!(dropped, swap_digest(2))
+            }
+            other => panic!("expected the newest announce to be dropped, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn upgrade_error_names_the_announce_it_was_for() {
+        let mut handler = Handler::default();
+
+        for i in 0..2 {
+            handler.inject_event(HandlerInEvent::Announce(OutboundConfig::new(swap_digest(i))));
+        }
+        let mut requested = requested_substreams(&mut handler);
+        assert_eq!(
+            requested.iter().map(|info| info.swap_digests.clone()).collect::<Vec<_>>(),
+            vec![vec![swap_digest(0)], vec![swap_digest(1)]]
+        );
+
+        let first = requested[0].substream;
+        let second = requested[1].substream;
+        handler.inject_dial_upgrade_error(requested.remove(1), ProtocolsHandlerUpgrErr::Timer);
+
+        match handler.events.pop_front() {
+            Some(HandlerEvent::Error(Error::Upgrade { swap_digest: failed, .. })) => {
+                assert_eq!(failed, swap_digest(1))
+            }
+            other => panic!("expected the second announce to fail, got {:?}", other),
+        }
+        assert!(handler.in_flight.contains(&(first, swap_digest(0))));
+        assert!(!handler.in_flight.contains(&(second, swap_digest(1))));
+    }
+
+    #[test]
+    fn upgrade_error_leads_to_the_io_error_through_its_sources() {
+        use libp2p::core::upgrade::ReadOneError;
+        use std::error::Error as _;
+
+        let mut handler = Handler::default();
+        handler.inject_event(HandlerInEvent::Announce(OutboundConfig::new(swap_digest(0))));
+        let info = requested_substreams(&mut handler).remove(0);
+
+        let io_error = io::Error::new(io::ErrorKind::ConnectionReset, "reset by the remote");
+        let error = protocol::Error::Read(ReadOneError::Io(io_error));
+        handler.inject_dial_upgrade_error(info, ProtocolsHandlerUpgrErr::Upgrade(UpgradeError::Apply(error)));
+        let error = match handler.events.pop_front() {
+            Some(HandlerEvent::Error(error)) => error,
+            other => panic!("expected the announce to fail, got {:?}", other),
+        };
+
+        // The upgrade error, the apply error, the protocol error and the
+        // read error each wrap the next one.
+        let mut chain = Vec::new();
+        let mut source = error.source();
+        while let Some(error) = source {
+            chain.push(error);
+            source = error.source();
+        }
+        assert_eq!(chain.len(), 5);
+        assert!(chain[2].downcast_ref::<protocol::Error>().is_some());
+
+        let root = chain.last().unwrap().downcast_ref::<io::Error>().unwrap();
+        assert_eq!(root.kind(), io::ErrorKind::ConnectionReset);
+        assert_eq!(root.to_string(), "reset by the remote");
+    }
+
+    #[test]
+    fn failed_confirmation_leads_to_the_io_error() {
+        use std::error::Error as _;
+
+        let error = Error::ConfirmationSendFailed {
+            swap_digest: swap_digest(0),
+            error: io::ErrorKind::BrokenPipe.into(),
+        };
+
+        let source = error.source().unwrap().downcast_ref::<io::Error>().unwrap();
+        assert_eq!(source.kind(), io::ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn events_are_displayed_as_concise_lines() {
+        let digest = swap_digest(0);
+        let short = digest.short();
+        let confirmed = Confirmed::new(digest.clone(), SwapId::default(), ProtocolVersion::V2);
+
+        assert_eq!(
+            HandlerEvent::ReceivedConfirmation(confirmed).to_string(),
+            format!("received confirmation for {}", short)
+        );
+        assert_eq!(
+            HandlerEvent::<SwapId>::OutboundSubstreamOpened {
+                swap_digest: digest.clone()
+            }
+            .to_string(),
+            format!("announce of {} sent, awaiting confirmation", short)
+        );
+        assert_eq!(
+            HandlerEvent::ConfirmationSent {
+                swap_digest: digest.clone(),
+                swap_id: SwapId::default(),
+            }
+            .to_string(),
+            format!("sent confirmation for {}", short)
+        );
+        assert_eq!(
+            HandlerEvent::<SwapId>::Error(Error::Timeout { swap_digest: digest.clone() }).to_string(),
+            format!("no confirmation received for swap {}", digest)
+        );
+    }
 }
@@ -0,0 +1,74 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counters describing the activity of an `Announce` behaviour.
+///
+/// These are plain numbers so they can be registered with whichever metrics
+/// exporter the application uses. A value obtained from `Announce::metrics`
+/// is a snapshot and does not change afterwards.
+#[derive(Debug, Default)]
+pub struct AnnounceMetrics {
+    announces_sent: AtomicU64,
+    confirmations_received: AtomicU64,
+    announcements_received: AtomicU64,
+    timeouts: AtomicU64,
+    upgrade_errors: AtomicU64,
+}
+
+impl AnnounceMetrics {
+    /// Announces handed to a connection, retries included.
+    pub fn announces_sent(&self) -> u64 {
+        self.announces_sent.load(Ordering::Relaxed)
+    }
+
+    /// Confirmations received for our own announces.
+    pub fn confirmations_received(&self) -> u64 {
+        self.confirmations_received.load(Ordering::Relaxed)
+    }
+
+    /// Announcements received from other peers.
+    pub fn announcements_received(&self) -> u64 {
+        self.announcements_received.load(Ordering::Relaxed)
+    }
+
+    /// Announces that were not confirmed in time.
+    pub fn timeouts(&self) -> u64 {
+        self.timeouts.load(Ordering::Relaxed)
+    }
+
+    /// Announces that failed to negotiate or exchange the protocol.
+    pub fn upgrade_errors(&self) -> u64 {
+        self.upgrade_errors.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn inc_announces_sent(&self) {
+        self.announces_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn inc_confirmations_received(&self) {
+        self.confirmations_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn inc_announcements_received(&self) {
+        self.announcements_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn inc_timeouts(&self) {
+        self.timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn inc_upgrade_errors(&self) {
+        self.upgrade_errors.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Clone for AnnounceMetrics {
+    fn clone(&self) -> Self {
+        AnnounceMetrics {
+            announces_sent: AtomicU64::new(self.announces_sent()),
+            confirmations_received: AtomicU64::new(self.confirmations_received()),
+            announcements_received: AtomicU64::new(self.announcements_received()),
+            timeouts: AtomicU64::new(self.timeouts()),
+            upgrade_errors: AtomicU64::new(self.upgrade_errors()),
+        }
+    }
+}
@@ -1,37 +1,481 @@
-use futures::prelude::*;
-use libp2p::core::upgrade::{self, InboundUpgrade, OutboundUpgrade, UpgradeInfo};
-use serde::Deserialize;
-use std::{io, iter, pin::Pin};
+use futures::{
+    channel::oneshot,
+    future::{self, Either},
+    lock::Mutex,
+    prelude::*,
+};
+use libp2p::{
+    core::upgrade::{self, InboundUpgrade, OutboundUpgrade, ProtocolName, UpgradeInfo},
+    multihash,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    fmt, io, iter,
+    marker::PhantomData,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+    vec,
+};
+use wasm_timer::Delay;
 
 use crate::announce::SwapDigest;
 use crate::swap_id::SwapId;
+use uuid::Uuid;
 
-const INFO: &str = "/comit/swap/announce/1.0.0";
+/// The prefix of the announce protocol names unless configured otherwise.
+pub const DEFAULT_PROTOCOL_PREFIX: &str = "/comit/swap/announce";
+
+/// The name of the protocol the peer an announce was made to pushes its
+/// confirmation with, on a substream it opens itself.
+pub const CONFIRM_PROTOCOL: &str = "/comit/swap/confirm/1.0.0";
+
+/// What a peer confirms an announced swap with, the `SwapId` it assigned to
+/// the swap unless the behaviour is built for something richer.
+///
+/// A type is made one with an empty `impl Confirmation for T {}`, unless its
+/// confirmations can be told apart by their size.
+pub trait Confirmation: Serialize + DeserializeOwned + Clone + fmt::Debug + Send + Sync + 'static {
+    /// Checks the size of a frame holding nothing but a confirmation, encoded
+    /// with `codec`, before it is decoded. Fails with the size the frame
+    /// should have had. Any size is fine by default.
+    fn validate_encoded_len(_codec: Codec, _len: usize) -> Result<(), usize> {
+        Ok(())
+    }
+}
+
+/// E.g. a signed blob.
+impl Confirmation for Vec<u8> {}
+
+impl Confirmation for String {}
+
+/// The frame holds nothing but the UUID, in any of the forms of it the codec
+/// decodes. JSON carries either the hyphenated or the simple string, CBOR
+/// only the bytes.
+impl Confirmation for SwapId {
+    fn validate_encoded_len(codec: Codec, len: usize) -> Result<(), usize> {
+        let nil = Uuid::nil();
+        let hyphenated = codec.encode(&SwapId(nil)).expect("a swap id always encodes");
+        let simple = codec
+            .encode(&nil.to_simple().to_string())
+            .expect("a string always encodes");
+        let sizes = iter::once(hyphenated.len())
+            .chain(codec.decode::<SwapId>(&simple).ok().map(|_| simple.len()))
+            .collect::<Vec<_>>();
+
+        if sizes.contains(&len) {
+            return Ok(());
+        }
+        // The size of the form the frame most likely is a broken version of.
+        let expected = sizes
+            .into_iter()
+            .min_by_key(|size| (*size as isize - len as isize).abs())
+            .expect("there is always the hyphenated form");
+
+        Err(expected)
+    }
+}
+
+/// A version of the announce protocol, the names given are those with the
+/// default prefix.
+///
+/// Every version is spoken under two names. The frames of the `x.1.0` one
+/// start with `FRAME_MAGIC`, those of the `x.0.0` one, which peers from
+/// before the magic byte speak, are the bare message. The `x.1.0` name is
+/// preferred.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum ProtocolVersion {
+    /// `/comit/swap/announce/1.1.0`, announces carry only the swap digest.
+    V1,
+    /// `/comit/swap/announce/2.1.0`, like `V1` but the confirmation repeats
+    /// the swap digest so it can be checked against the announce.
+    V2,
+    /// `/comit/swap/announce/cbor/1.1.0`, the messages of `V1` encoded with
+    /// CBOR instead of JSON. Not offered unless configured explicitly.
+    Cbor,
+    /// `/comit/swap/announce/pipelined/1.1.0`, several announces on one
+    /// substream, each carrying a request id that its confirmation repeats.
+    /// Only spoken when pipelining is enabled.
+    Pipelined,
+}
+
+impl ProtocolVersion {
+    /// The versions offered by default, most preferred first.
+    pub const ALL: &'static [ProtocolVersion] = &[ProtocolVersion::V2, ProtocolVersion::V1];
+
+    /// How messages are encoded on a substream of this version.
+    pub fn codec(&self) -> Codec {
+        match self {
+            ProtocolVersion::V1 | ProtocolVersion::V2 | ProtocolVersion::Pipelined => Codec::Json,
+            ProtocolVersion::Cbor => Codec::Cbor,
+        }
+    }
+
+    /// What follows the prefix in the protocol name of this version, with
+    /// framed or bare frames.
+    fn suffix(&self, framed: bool) -> &'static str {
+        match (self, framed) {
+            (ProtocolVersion::V1, true) => "/1.1.0",
+            (ProtocolVersion::V1, false) => "/1.0.0",
+            (ProtocolVersion::V2, true) => "/2.1.0",
+            (ProtocolVersion::V2, false) => "/2.0.0",
+            (ProtocolVersion::Cbor, true) => "/cbor/1.1.0",
+            (ProtocolVersion::Cbor, false) => "/cbor/1.0.0",
+            (ProtocolVersion::Pipelined, true) => "/pipelined/1.1.0",
+            (ProtocolVersion::Pipelined, false) => "/pipelined/1.0.0",
+        }
+    }
+}
+
+/// A protocol version together with the name it is negotiated under.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProtocolId {
+    pub version: ProtocolVersion,
+    /// Whether the frames start with `FRAME_MAGIC`, see `ProtocolVersion`.
+    pub framed: bool,
+    name: Vec<u8>,
+}
+
+impl ProtocolId {
+    /// The preferred name of the version, whose frames start with
+    /// `FRAME_MAGIC`.
+    pub fn new(prefix: &str, version: ProtocolVersion) -> Self {
+        ProtocolId::with_framing(prefix, version, true)
+    }
+
+    /// The name the version had before frames started with `FRAME_MAGIC`,
+    /// spoken with peers that predate it.
+    pub fn unframed(prefix: &str, version: ProtocolVersion) -> Self {
+        ProtocolId::with_framing(prefix, version, false)
+    }
+
+    fn with_framing(prefix: &str, version: ProtocolVersion, framed: bool) -> Self {
+        ProtocolId {
+            version,
+            framed,
+            name: format!("{}{}", prefix, version.suffix(framed)).into_bytes(),
+        }
+    }
+}
+
+impl ProtocolId {
+    /// The id of `CONFIRM_PROTOCOL`. A pushed confirmation is the message
+    /// of a `ProtocolVersion::V2` confirmation, which repeats the swap
+    /// digest, so that is its version.
+    pub fn confirm() -> Self {
+        ProtocolId {
+            version: ProtocolVersion::V2,
+            framed: true,
+            name: CONFIRM_PROTOCOL.as_bytes().to_vec(),
+        }
+    }
+
+    /// Whether this is the id of `CONFIRM_PROTOCOL`.
+    pub fn is_confirm(&self) -> bool {
+        self.name == CONFIRM_PROTOCOL.as_bytes()
+    }
+}
+
+impl From<ProtocolVersion> for ProtocolId {
+    fn from(version: ProtocolVersion) -> Self {
+        ProtocolId::new(DEFAULT_PROTOCOL_PREFIX, version)
+    }
+}
+
+impl ProtocolName for ProtocolId {
+    fn protocol_name(&self) -> &[u8] {
+        &self.name
+    }
+}
+
+/// An announce made with `ProtocolVersion::V2` that carries a time-to-live,
+/// without one the announce is the bare swap digest as with the other
+/// versions.
+#[derive(Debug, Serialize, Deserialize)]
+struct V2Announce {
+    swap_digest: SwapDigest,
+    ttl: Duration,
+}
+
+/// The reply to an announce made with `ProtocolVersion::V2`.
+#[derive(Debug, Serialize, Deserialize)]
+struct V2Confirmation<TConfirmation> {
+    swap_digest: SwapDigest,
+    swap_id: TConfirmation,
+}
+
+/// An announce made with `ProtocolVersion::Pipelined`.
+#[derive(Debug, Serialize, Deserialize)]
+struct PipelinedAnnounce {
+    request_id: u32,
+    swap_digest: SwapDigest,
+}
+
+/// The reply to a `PipelinedAnnounce`.
+#[derive(Debug, Serialize, Deserialize)]
+struct PipelinedConfirmation<TConfirmation> {
+    request_id: u32,
+    swap_digest: SwapDigest,
+    swap_id: TConfirmation,
+}
+
+/// Why an announce was rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RejectReason {
+    /// We know of no swap with this digest.
+    UnknownDigest,
+    /// We cannot take on the swap right now.
+    Busy,
+}
+
+/// Sent instead of a confirmation when the announce is rejected.
+#[derive(Debug, Serialize, Deserialize)]
+struct Rejection {
+    /// Only set on pipelined substreams.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    request_id: Option<u32>,
+    rejected: RejectReason,
+}
+
+/// Decodes a reply to an announce, which is either a `T` or a `Rejection`.
+fn decode_reply<T: DeserializeOwned>(codec: Codec, message: &[u8]) -> Result<T, Error> {
+    codec.decode::<T>(message).map_err(|error| rejected(codec, message).unwrap_or(error))
+}
+
+/// Like `decode_reply` for a reply holding nothing but the confirmation. One
+/// whose size `T` does not accept, e.g. because it was cut short, fails with
+/// `Error::InvalidConfirmationLength`.
+fn decode_confirmation<T: Confirmation>(codec: Codec, message: &[u8]) -> Result<T, Error> {
+    if let Err(expected) = T::validate_encoded_len(codec, message.len()) {
+        return Err(rejected(codec, message).unwrap_or(Error::InvalidConfirmationLength {
+            expected,
+            actual: message.len(),
+        }));
+    }
+
+    decode_reply(codec, message)
+}
+
+/// The reply as a rejection, if it is one.
+fn rejected(codec: Codec, message: &[u8]) -> Result<Error, Error> {
+    codec
+        .decode::<Rejection>(message)
+        .map(|rejection| Error::Rejected(rejection.rejected))
+}
+
+/// The encoding of the messages within a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Json,
+    Cbor,
+}
+
+impl Codec {
+    pub fn encode<T: Serialize>(self, message: &T) -> Result<Vec<u8>, Error> {
+        match self {
+            Codec::Json => Ok(serde_json::to_vec(message)?),
+            Codec::Cbor => Ok(serde_cbor::to_vec(message)?),
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T, Error> {
+        match self {
+            Codec::Json => {
+                let mut de = serde_json::Deserializer::from_slice(bytes);
+                let message = T::deserialize(&mut de)?;
+                de.end()?;
+                Ok(message)
+            }
+            Codec::Cbor => Ok(serde_cbor::from_slice(bytes)?),
+        }
+    }
+}
+
+/// Maximum size of a single frame, swap digests and swap ids are far smaller.
+pub const MAX_FRAME_SIZE: usize = 1024;
+
+/// Maximum size of a confirmation frame by default. Far above
+/// `MAX_FRAME_SIZE`, so a confirmation can carry e.g. a signed blob.
+pub const MAX_CONFIRMATION_SIZE: usize = 64 * 1024;
+
+/// The first byte of every non-empty frame on a framed protocol id. A frame
+/// starting with anything else was not written by this protocol, or by an
+/// incompatible version of it, and is not decoded at all.
+pub const FRAME_MAGIC: u8 = 0xa5;
+
+/// Maximum number of announces on a single pipelined substream.
+pub const MAX_PIPELINED: usize = 32;
+
+/// How long the outbound side waits for a confirmation by default.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
 
 /// Configuration for an upgrade to the `Announce` protocol on the outbound
 /// side.
 #[derive(Debug, Clone)]
 pub struct OutboundConfig {
     pub swap_digest: SwapDigest,
+    /// How long to wait for the confirmation once the substream has been
+    /// negotiated.
+    pub timeout: Duration,
+    /// The protocol versions to offer, most preferred first.
+    pub versions: Vec<ProtocolVersion>,
+    /// What the protocol names start with.
+    pub prefix: String,
+    /// How long the swap can wait for the remote to act on it. Only
+    /// `ProtocolVersion::V2` carries it, with other versions it is not sent.
+    pub ttl: Option<Duration>,
+    /// The largest confirmation frame accepted, in bytes.
+    pub max_confirmation_size: usize,
+    /// Is shown the frames sent and received, if set.
+    pub inspector: Option<WireInspector>,
 }
 
 impl OutboundConfig {
     pub fn new(swap_digest: SwapDigest) -> Self {
-        OutboundConfig { swap_digest }
+        OutboundConfig {
+            swap_digest,
+            timeout: DEFAULT_TIMEOUT,
+            versions: ProtocolVersion::ALL.to_vec(),
+            prefix: DEFAULT_PROTOCOL_PREFIX.to_owned(),
+            ttl: None,
+            max_confirmation_size: MAX_CONFIRMATION_SIZE,
+            inspector: None,
+        }
     }
 }
 
 impl UpgradeInfo for OutboundConfig {
-    type Info = &'static [u8];
-    type InfoIter = iter::Once<Self::Info>;
+    type Info = ProtocolId;
+    type InfoIter = vec::IntoIter<Self::Info>;
 
     fn protocol_info(&self) -> Self::InfoIter {
-        iter::once(INFO.as_bytes())
+        protocol_ids(&self.prefix, &self.versions)
     }
 }
 
+/// The ids of the versions, most preferred first. Every version is offered
+/// framed before any is offered unframed.
+fn protocol_ids(prefix: &str, versions: &[ProtocolVersion]) -> vec::IntoIter<ProtocolId> {
+    let framed = versions.iter().map(|version| ProtocolId::new(prefix, *version));
+    let unframed = versions.iter().map(|version| ProtocolId::unframed(prefix, *version));
+
+    framed.chain(unframed).collect::<Vec<_>>().into_iter()
+}
+
 type UpgradeFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
 
+/// The callback behind a `WireInspector`.
+pub type InspectFn = dyn Fn(Direction, &[u8]) + Send + Sync;
+
+/// Which way a frame shown to a `WireInspector` went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// Is shown every frame the upgrades send or receive, for debugging interop
+/// problems. Frames are given as they are on the wire without their length
+/// prefix, i.e. `FRAME_MAGIC` first unless they are empty or were sent on an
+/// unframed protocol id.
+#[derive(Clone)]
+pub struct WireInspector(Arc<InspectFn>);
+
+impl WireInspector {
+    pub fn new(inspector: Arc<InspectFn>) -> Self {
+        WireInspector(inspector)
+    }
+}
+
+impl fmt::Debug for WireInspector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("WireInspector")
+    }
+}
+
+/// Inspectors are equal if they share the same closure.
+impl PartialEq for WireInspector {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+/// Shows the frame to the inspector, if there is one.
+fn inspect(inspector: Option<&WireInspector>, direction: Direction, frame: &[u8]) {
+    if let Some(inspector) = inspector {
+        (inspector.0)(direction, frame)
+    }
+}
+
+/// Reads one length-prefixed frame, refusing frames whose declared length
+/// exceeds `max` before allocating a buffer for them.
+///
+/// If `framed`, the magic byte is checked and stripped, empty frames are
+/// returned as is.
+async fn read_frame(
+    socket: &mut (impl AsyncRead + Unpin),
+    max: usize,
+    framed: bool,
+    inspector: Option<&WireInspector>,
+) -> Result<Vec<u8>, Error> {
+    let mut frame = upgrade::read_one(socket, max)
+        .await
+        .map_err(|err| match err {
+            upgrade::ReadOneError::TooLarge { requested, .. } => Error::FrameTooLarge { size: requested },
+            err => Error::Read(err),
+        })?;
+    inspect(inspector, Direction::Received, &frame);
+    if !framed {
+        return Ok(frame);
+    }
+
+    match frame.first() {
+        None => {}
+        Some(&FRAME_MAGIC) => {
+            frame.remove(0);
+        }
+        Some(&magic) => return Err(Error::BadMagic(magic)),
+    }
+
+    Ok(frame)
+}
+
+/// Reads the frame replying to an announce. The remote closing the substream
+/// instead reads as an empty frame, or fails with a broken pipe or reset if
+/// the transport has no clean way to close.
+async fn read_reply(
+    socket: &mut (impl AsyncRead + Unpin),
+    max: usize,
+    framed: bool,
+    inspector: Option<&WireInspector>,
+) -> Result<Vec<u8>, Error> {
+    match read_frame(socket, max, framed, inspector).await {
+        Ok(frame) if frame.is_empty() => Err(Error::ClosedWithoutConfirmation),
+        Err(Error::Read(upgrade::ReadOneError::Io(err)))
+            if matches!(
+                err.kind(),
+                io::ErrorKind::UnexpectedEof | io::ErrorKind::BrokenPipe | io::ErrorKind::ConnectionReset
+            ) =>
+        {
+            Err(Error::ClosedWithoutConfirmation)
+        }
+        result => result,
+    }
+}
+
+/// Encodes the message as the contents of a frame, `FRAME_MAGIC` first if
+/// `framed`.
+fn encode_frame<T: Serialize>(codec: Codec, framed: bool, message: &T) -> Result<Vec<u8>, Error> {
+    let mut frame = if framed { vec![FRAME_MAGIC] } else { Vec::new() };
+    frame.extend(codec.encode(message)?);
+
+    Ok(frame)
+}
+
 impl<C> OutboundUpgrade<C> for OutboundConfig
 where
     C: AsyncRead + AsyncWrite + Unpin + Send + 'static,
@@ -40,46 +484,468 @@ where
     type Error = Error;
     type Future = UpgradeFuture<Result<Self::Output, Self::Error>>;
 
-    fn upgrade_outbound(self, mut socket: C, _info: Self::Info) -> Self::Future {
+    fn upgrade_outbound(self, socket: C, info: Self::Info) -> Self::Future {
+        self.announce(socket, info)
+    }
+}
+
+impl OutboundConfig {
+    /// Performs the upgrade with a peer confirming with a `TConfirmation`,
+    /// `upgrade_outbound` expects a `SwapId`.
+    pub fn announce<C, TConfirmation>(
+        self,
+        socket: C,
+        info: ProtocolId,
+    ) -> UpgradeFuture<Result<Confirmed<TConfirmation>, Error>>
+    where
+        C: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+        TConfirmation: Confirmation,
+    {
+        let version = info.version;
+
         Box::pin(async move {
-            let bytes = serde_json::to_vec(&self.swap_digest)?;
-            upgrade::write_one(&mut socket, &bytes).await?;
-            socket.close().await?;
+            let announces = vec![self.swap_digest.clone()];
+            let exchange = exchange(
+                socket,
+                version,
+                info.framed,
+                &announces,
+                self.ttl,
+                self.max_confirmation_size,
+                self.inspector.as_ref(),
+            );
+            let (swap_id, raw) = with_timeout(exchange, self.timeout, Error::Timeout).await?.remove(0);
 
-            let message = upgrade::read_one(&mut socket, 1024).await?;
-            let mut de = serde_json::Deserializer::from_slice(&message);
-            let swap_id = SwapId::deserialize(&mut de)?;
+            Ok(Confirmed::new(self.swap_digest, swap_id, version).with_raw(raw))
+        })
+    }
 
-            Ok(Confirmed {
-                swap_digest: self.swap_digest.clone(),
-                swap_id,
-            })
+    /// Sends the announce without waiting for a confirmation on the
+    /// substream, for a remote that pushes it with `PushConfirmation`.
+    pub fn announce_only<C>(self, mut socket: C, info: ProtocolId) -> UpgradeFuture<Result<(), Error>>
+    where
+        C: AsyncWrite + Unpin + Send + 'static,
+    {
+        Box::pin(async move {
+            let frame = announce_frame(info.version, info.framed, &self.swap_digest, self.ttl)?;
+            inspect(self.inspector.as_ref(), Direction::Sent, &frame);
+            let send = async {
+                upgrade::write_one(&mut socket, &frame).await?;
+                Ok(())
+            };
+
+            with_timeout(send, self.timeout, Error::Timeout).await
         })
     }
 }
 
-#[derive(Debug)]
-pub struct Confirmed {
+/// Configuration for an outbound upgrade announcing several swaps on one
+/// substream with `ProtocolVersion::Pipelined`.
+#[derive(Debug, Clone)]
+pub struct PipelinedConfig {
+    /// The swaps to announce, at most `MAX_PIPELINED`.
+    pub swap_digests: Vec<SwapDigest>,
+    /// How long to wait for all confirmations once the substream has been
+    /// negotiated.
+    pub timeout: Duration,
+    /// What the protocol names start with.
+    pub prefix: String,
+    /// The largest confirmation frame accepted, in bytes.
+    pub max_confirmation_size: usize,
+    /// Is shown the frames sent and received, if set.
+    pub inspector: Option<WireInspector>,
+}
+
+impl PipelinedConfig {
+    pub fn new(swap_digests: Vec<SwapDigest>) -> Self {
+        PipelinedConfig {
+            swap_digests,
+            timeout: DEFAULT_TIMEOUT,
+            prefix: DEFAULT_PROTOCOL_PREFIX.to_owned(),
+            max_confirmation_size: MAX_CONFIRMATION_SIZE,
+            inspector: None,
+        }
+    }
+}
+
+impl UpgradeInfo for PipelinedConfig {
+    type Info = ProtocolId;
+    type InfoIter = vec::IntoIter<Self::Info>;
+
+    fn protocol_info(&self) -> Self::InfoIter {
+        protocol_ids(&self.prefix, &[ProtocolVersion::Pipelined])
+    }
+}
+
+impl<C> OutboundUpgrade<C> for PipelinedConfig
+where
+    C: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    /// One confirmation per announced swap, in the order they were announced.
+    type Output = Vec<Confirmed>;
+    type Error = Error;
+    type Future = UpgradeFuture<Result<Self::Output, Self::Error>>;
+
+    fn upgrade_outbound(self, socket: C, info: Self::Info) -> Self::Future {
+        self.announce(socket, info)
+    }
+}
+
+impl PipelinedConfig {
+    /// Performs the upgrade with a peer confirming with a `TConfirmation`,
+    /// `upgrade_outbound` expects a `SwapId`.
+    pub fn announce<C, TConfirmation>(
+        self,
+        socket: C,
+        info: ProtocolId,
+    ) -> UpgradeFuture<Result<Vec<Confirmed<TConfirmation>>, Error>>
+    where
+        C: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+        TConfirmation: Confirmation,
+    {
+        let version = info.version;
+
+        Box::pin(async move {
+            let exchange = exchange(
+                socket,
+                version,
+                info.framed,
+                &self.swap_digests,
+                None,
+                self.max_confirmation_size,
+                self.inspector.as_ref(),
+            );
+            let swap_ids = with_timeout(exchange, self.timeout, Error::Timeout).await?;
+
+            Ok(self
+                .swap_digests
+                .into_iter()
+                .zip(swap_ids)
+                .map(|(swap_digest, (swap_id, raw))| Confirmed::new(swap_digest, swap_id, version).with_raw(raw))
+                .collect())
+        })
+    }
+}
+
+/// Fails with `error` unless `exchange` completes within `timeout`.
+async fn with_timeout<T>(
+    exchange: impl Future<Output = Result<T, Error>>,
+    timeout: Duration,
+    error: Error,
+) -> Result<T, Error> {
+    futures::pin_mut!(exchange);
+
+    match future::select(exchange, Delay::new(timeout)).await {
+        Either::Left((result, _)) => result,
+        Either::Right(_) => Err(error),
+    }
+}
+
+/// Announces the swaps and returns their swap ids in the same order, each with
+/// the confirmation frame it was read from. Only `ProtocolVersion::Pipelined`
+/// can announce more than one swap, and only `ProtocolVersion::V2` sends the
+/// `ttl`.
+async fn exchange<C, TConfirmation>(
+    mut socket: C,
+    version: ProtocolVersion,
+    framed: bool,
+    announces: &[SwapDigest],
+    ttl: Option<Duration>,
+    max_confirmation_size: usize,
+    inspector: Option<&WireInspector>,
+) -> Result<Vec<(TConfirmation, Vec<u8>)>, Error>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    TConfirmation: Confirmation,
+{
+    let codec = version.codec();
+
+    if version != ProtocolVersion::Pipelined {
+        let announced = &announces[0];
+        let frame = announce_frame(version, framed, announced, ttl)?;
+        inspect(inspector, Direction::Sent, &frame);
+        upgrade::write_one(&mut socket, &frame).await?;
+        socket.close().await?;
+
+        let message = read_reply(&mut socket, max_confirmation_size, framed, inspector).await?;
+        let swap_id = match version {
+            ProtocolVersion::V2 => {
+                let confirmation = decode_reply::<V2Confirmation<TConfirmation>>(codec, &message)?;
+                if &confirmation.swap_digest != announced {
+                    return Err(Error::DigestMismatch {
+                        expected: announced.clone(),
+                        actual: confirmation.swap_digest,
+                    });
+                }
+                confirmation.swap_id
+            }
+            _ => decode_confirmation::<TConfirmation>(codec, &message)?,
+        };
+
+        return Ok(vec![(swap_id, message)]);
+    }
+
+    for (request_id, swap_digest) in announces.iter().enumerate() {
+        let announce = PipelinedAnnounce {
+            request_id: request_id as u32,
+            swap_digest: swap_digest.clone(),
+        };
+        let frame = encode_frame(codec, framed, &announce)?;
+        inspect(inspector, Direction::Sent, &frame);
+        upgrade::write_with_len_prefix(&mut socket, &frame).await?;
+    }
+    // An empty frame ends the announces.
+    inspect(inspector, Direction::Sent, &[]);
+    upgrade::write_with_len_prefix(&mut socket, &[]).await?;
+    socket.close().await?;
+
+    // The confirmations may arrive in any order, the request ids put them
+    // back into the order of the announces.
+    let mut swap_ids = vec![None; announces.len()];
+    for _ in announces {
+        let message = read_reply(&mut socket, max_confirmation_size, framed, inspector).await?;
+        let confirmation = decode_reply::<PipelinedConfirmation<TConfirmation>>(codec, &message)?;
+        let request_id = confirmation.request_id;
+
+        let slot = match swap_ids.get_mut(request_id as usize) {
+            Some(slot @ None) => slot,
+            _ => return Err(Error::UnexpectedRequestId(request_id)),
+        };
+        let announced = &announces[request_id as usize];
+        if &confirmation.swap_digest != announced {
+            return Err(Error::DigestMismatch {
+                expected: announced.clone(),
+                actual: confirmation.swap_digest,
+            });
+        }
+        *slot = Some((confirmation.swap_id, message));
+    }
+
+    Ok(swap_ids.into_iter().flatten().collect())
+}
+
+/// The frame announcing a single swap, only `ProtocolVersion::V2` sends the
+/// `ttl`.
+fn announce_frame(
+    version: ProtocolVersion,
+    framed: bool,
+    swap_digest: &SwapDigest,
+    ttl: Option<Duration>,
+) -> Result<Vec<u8>, Error> {
+    let codec = version.codec();
+
+    match ttl {
+        Some(ttl) if version == ProtocolVersion::V2 => encode_frame(codec, framed, &V2Announce {
+            swap_digest: swap_digest.clone(),
+            ttl,
+        }),
+        _ => encode_frame(codec, framed, swap_digest),
+    }
+}
+
+/// Outbound upgrade pushing our confirmation of an announce the remote made,
+/// on a substream of `CONFIRM_PROTOCOL` rather than the announce substream.
+#[derive(Debug, Clone)]
+pub struct PushConfirmation<TConfirmation = SwapId> {
     pub swap_digest: SwapDigest,
-    pub swap_id: SwapId,
+    pub swap_id: TConfirmation,
+    /// How long sending the confirmation may take once the substream has
+    /// been negotiated.
+    pub timeout: Duration,
+    /// Is shown the frame sent, if set.
+    pub inspector: Option<WireInspector>,
+}
+
+impl<TConfirmation> PushConfirmation<TConfirmation> {
+    pub fn new(swap_digest: SwapDigest, swap_id: TConfirmation) -> Self {
+        PushConfirmation {
+            swap_digest,
+            swap_id,
+            timeout: DEFAULT_TIMEOUT,
+            inspector: None,
+        }
+    }
+}
+
+impl<TConfirmation> UpgradeInfo for PushConfirmation<TConfirmation> {
+    type Info = ProtocolId;
+    type InfoIter = iter::Once<Self::Info>;
+
+    fn protocol_info(&self) -> Self::InfoIter {
+        iter::once(ProtocolId::confirm())
+    }
+}
+
+impl<C, TConfirmation> OutboundUpgrade<C> for PushConfirmation<TConfirmation>
+where
+    C: AsyncWrite + Unpin + Send + 'static,
+    TConfirmation: Confirmation,
+{
+    type Output = ();
+    type Error = Error;
+    type Future = UpgradeFuture<Result<Self::Output, Self::Error>>;
+
+    fn upgrade_outbound(self, mut socket: C, _: Self::Info) -> Self::Future {
+        Box::pin(async move {
+            let frame = encode_frame(Codec::Json, true, &V2Confirmation {
+                swap_digest: self.swap_digest,
+                swap_id: self.swap_id,
+            })?;
+            inspect(self.inspector.as_ref(), Direction::Sent, &frame);
+            let send = async {
+                upgrade::write_one(&mut socket, &frame).await?;
+                Ok(())
+            };
+
+            with_timeout(send, self.timeout, Error::Timeout).await
+        })
+    }
+}
+
+/// Inbound upgrade reading a confirmation pushed with `PushConfirmation`.
+///
+/// The substream does not say which announce it confirms, the swap digest
+/// in the confirmation does.
+#[derive(Debug, Clone)]
+pub struct ConfirmListen<TConfirmation = SwapId> {
+    /// The largest confirmation frame accepted, in bytes.
+    pub max_confirmation_size: usize,
+    /// How long the remote has to send the confirmation once the substream
+    /// has been negotiated.
+    pub read_timeout: Duration,
+    /// Is shown the frame received, if set.
+    pub inspector: Option<WireInspector>,
+    confirmation: PhantomData<fn() -> TConfirmation>,
+}
+
+impl<TConfirmation> Default for ConfirmListen<TConfirmation> {
+    fn default() -> Self {
+        ConfirmListen {
+            max_confirmation_size: MAX_CONFIRMATION_SIZE,
+            read_timeout: DEFAULT_TIMEOUT,
+            inspector: None,
+            confirmation: PhantomData,
+        }
+    }
+}
+
+impl<TConfirmation> UpgradeInfo for ConfirmListen<TConfirmation> {
+    type Info = ProtocolId;
+    type InfoIter = iter::Once<Self::Info>;
+
+    fn protocol_info(&self) -> Self::InfoIter {
+        iter::once(ProtocolId::confirm())
+    }
+}
+
+impl<C, TConfirmation> InboundUpgrade<C> for ConfirmListen<TConfirmation>
+where
+    C: AsyncRead + Unpin + Send + 'static,
+    TConfirmation: Confirmation,
+{
+    type Output = Confirmed<TConfirmation>;
+    type Error = Error;
+    type Future = UpgradeFuture<Result<Self::Output, Self::Error>>;
+
+    fn upgrade_inbound(self, mut socket: C, info: Self::Info) -> Self::Future {
+        Box::pin(async move {
+            let read = read_reply(&mut socket, self.max_confirmation_size, info.framed, self.inspector.as_ref());
+            let message = with_timeout(read, self.read_timeout, Error::ReadTimeout).await?;
+            let confirmation = decode_reply::<V2Confirmation<TConfirmation>>(Codec::Json, &message)?;
+
+            Ok(Confirmed::new(confirmation.swap_digest, confirmation.swap_id, info.version).with_raw(message))
+        })
+    }
+}
+
+/// The outcome of a confirmed announce.
+///
+/// The upgrade only sees the substream, not the connection it belongs to, so
+/// the confirming peer is not known here. The behaviour adds it when
+/// reporting the confirmation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Confirmed<TConfirmation = SwapId> {
+    swap_digest: SwapDigest,
+    swap_id: TConfirmation,
+    version: ProtocolVersion,
+    #[cfg_attr(feature = "serde", serde(default))]
+    raw: Vec<u8>,
+}
+
+impl<TConfirmation: Clone> Confirmed<TConfirmation> {
+    pub fn new(swap_digest: SwapDigest, swap_id: TConfirmation, version: ProtocolVersion) -> Self {
+        Confirmed {
+            swap_digest,
+            swap_id,
+            version,
+            raw: Vec::new(),
+        }
+    }
+
+    /// Sets the confirmation frame the confirmation was read from.
+    pub fn with_raw(mut self, raw: Vec<u8>) -> Self {
+        self.raw = raw;
+        self
+    }
+
+    /// The digest of the announced swap.
+    pub fn swap_digest(&self) -> &SwapDigest {
+        &self.swap_digest
+    }
+
+    /// The id the remote assigned to the swap, or whatever else it confirmed
+    /// the swap with.
+    pub fn swap_id(&self) -> TConfirmation {
+        self.swap_id.clone()
+    }
+
+    /// The protocol version the announce was made with.
+    pub fn version(&self) -> ProtocolVersion {
+        self.version
+    }
+
+    /// The confirmation frame as received, without its magic byte and
+    /// encoded with the codec of `version`. It holds the whole reply, e.g.
+    /// with `ProtocolVersion::V2` the swap digest as well. Empty unless the
+    /// confirmation was received.
+    pub fn raw(&self) -> &[u8] {
+        &self.raw
+    }
 }
 
 /// Configuration for an upgrade to the `Announce` protocol on the inbound side.
-#[derive(Debug, Clone, Copy)]
-pub struct InboundConfig {}
+#[derive(Debug, Clone)]
+pub struct InboundConfig {
+    /// The protocol versions to accept, most preferred first.
+    pub versions: Vec<ProtocolVersion>,
+    /// What the protocol names start with.
+    pub prefix: String,
+    /// How long the announcing side has to send its announces once the
+    /// substream has been negotiated, so a peer stalling halfway through a
+    /// frame cannot hold on to the substream.
+    pub read_timeout: Duration,
+    /// Is shown the frames received and the replies sent, if set.
+    pub inspector: Option<WireInspector>,
+}
 
 impl Default for InboundConfig {
     fn default() -> Self {
-        InboundConfig {}
+        InboundConfig {
+            versions: ProtocolVersion::ALL.to_vec(),
+            prefix: DEFAULT_PROTOCOL_PREFIX.to_owned(),
+            read_timeout: DEFAULT_TIMEOUT,
+            inspector: None,
+        }
     }
 }
 
 impl UpgradeInfo for InboundConfig {
-    type Info = &'static [u8];
-    type InfoIter = iter::Once<Self::Info>;
+    type Info = ProtocolId;
+    type InfoIter = vec::IntoIter<Self::Info>;
 
     fn protocol_info(&self) -> Self::InfoIter {
-        iter::once(INFO.as_bytes())
+        protocol_ids(&self.prefix, &self.versions)
     }
 }
 
@@ -91,43 +957,278 @@ where
     type Error = Error;
     type Future = UpgradeFuture<Result<Self::Output, Self::Error>>;
 
-    fn upgrade_inbound(self, mut socket: C, _info: Self::Info) -> Self::Future {
+    fn upgrade_inbound(self, socket: C, info: Self::Info) -> Self::Future {
+        let read = self.read_all(socket, info);
+
         Box::pin(async move {
-            let message = upgrade::read_one(&mut socket, 1024).await?;
-            let mut de = serde_json::Deserializer::from_slice(&message);
-            let swap_digest = SwapDigest::deserialize(&mut de)?;
-            Ok(ReplySubstream {
-                io: socket,
-                swap_digest,
-            })
+            let mut replies = read.await?;
+            if replies.len() != 1 {
+                return Err(Error::UnexpectedAnnounceCount(replies.len()));
+            }
+
+            Ok(replies.remove(0))
+        })
+    }
+}
+
+impl InboundConfig {
+    /// Like `upgrade_inbound` but returns a `ReplySubstream` for every
+    /// announce on the substream, a pipelined substream can carry several.
+    ///
+    /// The replies are confirmed with a `TConfirmation`, `upgrade_inbound`
+    /// expects a `SwapId`.
+    pub fn read_all<C, TConfirmation>(
+        self,
+        mut socket: C,
+        info: ProtocolId,
+    ) -> UpgradeFuture<Result<Vec<ReplySubstream<C, TConfirmation>>, Error>>
+    where
+        C: AsyncRead + Unpin + Send + 'static,
+        TConfirmation: Confirmation,
+    {
+        let version = info.version;
+
+        Box::pin(async move {
+            let announces = with_timeout(
+                read_announces(&mut socket, version, info.framed, self.inspector.as_ref()),
+                self.read_timeout,
+                Error::ReadTimeout,
+            )
+            .await?;
+
+            // Swap digests are always SHA2-256, anything else is not a swap we
+            // could know about.
+            for (_, swap_digest, _) in &announces {
+                if swap_digest.algorithm() != multihash::Code::Sha2_256 {
+                    return Err(Error::UnsupportedDigestAlgorithm(swap_digest.algorithm()));
+                }
+            }
+
+            let io = Arc::new(Mutex::new(Some(socket)));
+            let remaining = Arc::new(AtomicUsize::new(announces.len()));
+
+            Ok(announces
+                .into_iter()
+                .map(|(request_id, swap_digest, ttl)| ReplySubstream {
+                    io: io.clone(),
+                    remaining: remaining.clone(),
+                    request_id,
+                    sent: None,
+                    swap_digest,
+                    version,
+                    framed: info.framed,
+                    ttl,
+                    inspector: self.inspector.clone(),
+                    confirmation: PhantomData,
+                    used: false,
+                })
+                .collect())
         })
     }
 }
 
+/// Reads the announces on the substream with their request ids, which are
+/// all 0 unless the substream is pipelined, and time-to-live, which only
+/// `ProtocolVersion::V2` announces carry.
+async fn read_announces(
+    socket: &mut (impl AsyncRead + Unpin),
+    version: ProtocolVersion,
+    framed: bool,
+    inspector: Option<&WireInspector>,
+) -> Result<Vec<(u32, SwapDigest, Option<Duration>)>, Error> {
+    let codec = version.codec();
+    let mut announces = Vec::new();
+
+    if version != ProtocolVersion::Pipelined {
+        let message = read_frame(socket, MAX_FRAME_SIZE, framed, inspector).await?;
+        let announce = match codec.decode::<SwapDigest>(&message) {
+            Ok(swap_digest) => (0, swap_digest, None),
+            Err(_) if version == ProtocolVersion::V2 => {
+                let announce = codec.decode::<V2Announce>(&message)?;
+                (0, announce.swap_digest, Some(announce.ttl))
+            }
+            Err(error) => return Err(error),
+        };
+        announces.push(announce);
+        return Ok(announces);
+    }
+
+    loop {
+        let message = read_frame(socket, MAX_FRAME_SIZE, framed, inspector).await?;
+        if message.is_empty() {
+            return Ok(announces);
+        }
+        if announces.len() == MAX_PIPELINED {
+            return Err(Error::TooManyAnnounces);
+        }
+        let announce = codec.decode::<PipelinedAnnounce>(&message)?;
+        announces.push((announce.request_id, announce.swap_digest, None));
+    }
+}
+
 /// The substream on which a reply is expected to be sent.
+///
+/// It is `Send + 'static`, so it can be moved out of the event and answered
+/// from another task once e.g. a database lookup is done. The future of
+/// `confirm` or `reject` does its work on its own, the swarm does not need
+/// to be involved, but a reply after the handler's reply timeout fails with
+/// `Error::ReplyTimeout`.
 #[derive(Debug)]
-pub struct ReplySubstream<T> {
-    pub io: T,
+pub struct ReplySubstream<T, TConfirmation = SwapId> {
+    /// Shared with the handler, which takes the substream away if the reply
+    /// is not sent in time.
+    io: SharedSubstream<T>,
+    /// How many of the announces on the substream still await a reply, the
+    /// last reply closes it.
+    remaining: Arc<AtomicUsize>,
+    request_id: u32,
+    /// Told whether the confirmation went out, `None` if nobody is listening.
+    sent: Option<oneshot::Sender<Result<TConfirmation, io::ErrorKind>>>,
     pub swap_digest: SwapDigest,
+    /// The protocol version the announce was made with.
+    pub version: ProtocolVersion,
+    /// Whether the replies start with `FRAME_MAGIC`, as the announces did.
+    framed: bool,
+    /// How long the announcing side can wait for the swap to be acted on,
+    /// only ever set if the announce was made with `ProtocolVersion::V2`.
+    pub ttl: Option<Duration>,
+    /// Is shown the reply, if set.
+    inspector: Option<WireInspector>,
+    /// The announce is confirmed with a `TConfirmation`.
+    confirmation: PhantomData<fn(TConfirmation)>,
+    /// Whether the announce was confirmed or rejected, dropping the
+    /// substream without either is logged as a likely bug.
+    used: bool,
+}
+
+pub(crate) type SharedSubstream<T> = Arc<Mutex<Option<T>>>;
+
+impl<T, TConfirmation> ReplySubstream<T, TConfirmation> {
+    pub(crate) fn shared_io(&self) -> SharedSubstream<T> {
+        self.io.clone()
+    }
+
+    /// Returns a receiver that learns whether the confirmation was sent, or
+    /// why writing it failed. It is cancelled if the substream is dropped
+    /// without confirming.
+    pub(crate) fn on_sent(&mut self) -> oneshot::Receiver<Result<TConfirmation, io::ErrorKind>> {
+        let (sender, receiver) = oneshot::channel();
+        self.sent = Some(sender);
+        receiver
+    }
+
+    /// Drops the substream without answering, on purpose. The handler closes
+    /// it, Alice fails with `Error::ClosedWithoutConfirmation`.
+    pub(crate) fn drop_unanswered(mut self) {
+        self.used = true;
+    }
+}
+
+impl<T, TConfirmation> Drop for ReplySubstream<T, TConfirmation> {
+    fn drop(&mut self) {
+        if !self.used {
+            tracing::warn!(
+                swap_digest = %self.swap_digest,
+                "reply substream dropped without confirming or rejecting the announce"
+            );
+        }
+    }
 }
 
-impl<T> ReplySubstream<T>
+impl<T, TConfirmation> ReplySubstream<T, TConfirmation>
 where
     T: AsyncWrite + Unpin,
+    TConfirmation: Confirmation,
 {
-    /// Sends back the requested information on the substream i.e., the
-    /// `swap_id`.
+    /// Confirms the announced swap by sending back its `swap_id`.
     ///
-    /// Consumes the substream, returning a reply future that resolves
-    /// when the reply has been sent on the underlying connection.
-    pub async fn send(mut self, swap_id: SwapId) -> Result<(), Error> {
-        let bytes = serde_json::to_vec(&swap_id)?;
-        upgrade::write_one(&mut self.io, &bytes).await?;
+    /// Consumes the substream, returning a future that resolves once the
+    /// reply has been flushed and the substream closed, which is what lets
+    /// the announcing side observe the confirmation. Fails with
+    /// `Error::ReplyTimeout` if the substream has already been dropped
+    /// because of the reply timeout.
+    pub async fn confirm(mut self, swap_id: TConfirmation) -> Result<(), Error> {
+        self.used = true;
+        let sent = self.sent.take();
+        let bytes = self.confirmation(swap_id.clone())?;
 
-        Ok(())
+        self.reply(&bytes, move |written| {
+            if let Some(sent) = sent {
+                let _ = sent.send(written.map(|()| swap_id));
+            }
+        })
+        .await
+    }
+
+    /// Rejects the announced swap, the announcing side fails with
+    /// `Error::Rejected` rather than waiting for its timeout.
+    ///
+    /// Like `confirm` the future resolves once the rejection has been sent.
+    pub async fn reject(mut self, reason: RejectReason) -> Result<(), Error> {
+        self.used = true;
+        // Not a confirmation, so the handler is not told it was sent.
+        let sent = self.sent.take();
+        let rejection = Rejection {
+            request_id: Some(self.request_id).filter(|_| self.version == ProtocolVersion::Pipelined),
+            rejected: reason,
+        };
+        let bytes = encode_frame(self.version.codec(), self.framed, &rejection)?;
+
+        self.reply(&bytes, move |_| drop(sent)).await
+    }
+
+    /// Writes the reply, `report` is told whether that worked before the
+    /// substream is unlocked.
+    async fn reply(
+        &self,
+        bytes: &[u8],
+        report: impl FnOnce(Result<(), io::ErrorKind>),
+    ) -> Result<(), Error> {
+        // Replies to pipelined announces share the substream, holding the lock
+        // keeps their frames apart.
+        let mut io = self.io.lock().await;
+        let socket = io.as_mut().ok_or(Error::ReplyTimeout)?;
+
+        let last = self.remaining.fetch_sub(1, Ordering::SeqCst) == 1;
+        inspect(self.inspector.as_ref(), Direction::Sent, bytes);
+        let result = write_reply(socket, bytes, last).await;
+        if last || result.is_err() {
+            io.take();
+        }
+        report(result.as_ref().map(|_| ()).map_err(io::Error::kind));
+
+        Ok(result?)
+    }
+
+    fn confirmation(&self, swap_id: TConfirmation) -> Result<Vec<u8>, Error> {
+        let (codec, framed) = (self.version.codec(), self.framed);
+        let bytes = match self.version {
+            ProtocolVersion::V1 | ProtocolVersion::Cbor => encode_frame(codec, framed, &swap_id)?,
+            ProtocolVersion::V2 => encode_frame(codec, framed, &V2Confirmation {
+                swap_digest: self.swap_digest.clone(),
+                swap_id,
+            })?,
+            ProtocolVersion::Pipelined => encode_frame(codec, framed, &PipelinedConfirmation {
+                request_id: self.request_id,
+                swap_digest: self.swap_digest.clone(),
+                swap_id,
+            })?,
+        };
+
+        Ok(bytes)
     }
 }
 
+async fn write_reply(io: &mut (impl AsyncWrite + Unpin), bytes: &[u8], last: bool) -> io::Result<()> {
+    // `write_one` would close the substream after the first reply.
+    upgrade::write_with_len_prefix(&mut *io, bytes).await?;
+    if last {
+        io.close().await?;
+    }
+
+    Ok(())
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("failed to read a message from the socket")]
@@ -136,4 +1237,733 @@ pub enum Error {
     Write(#[from] io::Error),
     #[error("failed to serialize/deserialize the message")]
     Serde(#[from] serde_json::Error),
+    #[error("failed to serialize/deserialize the CBOR message")]
+    Cbor(#[from] serde_cbor::Error),
+    #[error("frame of {size} bytes exceeds the maximum frame size")]
+    FrameTooLarge { size: usize },
+    #[error("frame starts with {0:#04x} instead of the magic byte")]
+    BadMagic(u8),
+    #[error("the announce was rejected: {0:?}")]
+    Rejected(RejectReason),
+    #[error("timed out waiting for the confirmation")]
+    Timeout,
+    #[error("timed out reading the announce")]
+    ReadTimeout,
+    #[error("the reply substream was dropped because the reply took too long")]
+    ReplyTimeout,
+    #[error("confirmation is for swap {actual} but swap {expected} was announced")]
+    DigestMismatch {
+        expected: SwapDigest,
+        actual: SwapDigest,
+    },
+    #[error("expected a single announce on the substream but got {0}")]
+    UnexpectedAnnounceCount(usize),
+    #[error("more than {} announces on one substream", MAX_PIPELINED)]
+    TooManyAnnounces,
+    #[error("confirmation for request {0} which was not announced or is already confirmed")]
+    UnexpectedRequestId(u32),
+    #[error("swap digest uses unsupported algorithm {0:?}")]
+    UnsupportedDigestAlgorithm(multihash::Code),
+    #[error("confirmation of {actual} bytes where {expected} were expected")]
+    InvalidConfirmationLength { expected: usize, actual: usize },
+    #[error("the substream was closed without confirming or rejecting the announce")]
+    ClosedWithoutConfirmation,
+}
+
+impl Error {
+    /// What kind of failure this is, for deciding e.g. whether to retry
+    /// without matching on the variants or their messages.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Error::Read(upgrade::ReadOneError::Io(_)) | Error::Write(_) => ErrorCode::Io,
+            Error::Read(upgrade::ReadOneError::TooLarge { .. }) | Error::FrameTooLarge { .. } => {
+                ErrorCode::FrameTooLarge
+            }
+            Error::Serde(_) | Error::Cbor(_) | Error::BadMagic(_) => ErrorCode::Decode,
+            Error::Rejected(_) => ErrorCode::Rejected,
+            Error::Timeout | Error::ReadTimeout | Error::ReplyTimeout => ErrorCode::Timeout,
+            Error::DigestMismatch { .. }
+            | Error::UnexpectedAnnounceCount(_)
+            | Error::TooManyAnnounces
+            | Error::UnexpectedRequestId(_)
+            | Error::InvalidConfirmationLength { .. }
+            | Error::ClosedWithoutConfirmation => ErrorCode::ProtocolViolation,
+            Error::UnsupportedDigestAlgorithm(_) => ErrorCode::UnsupportedAlgorithm,
+        }
+    }
+}
+
+/// The kind of an `Error`. New variants of `Error` map to one of these, so
+/// the codes stay the same as the errors evolve.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ErrorCode {
+    /// Reading from or writing to the substream failed.
+    Io,
+    /// A message could not be encoded or decoded.
+    Decode,
+    /// A frame was larger than allowed.
+    FrameTooLarge,
+    /// The announced swap digest uses a hash function we do not support.
+    UnsupportedAlgorithm,
+    /// The remote or the application took too long.
+    Timeout,
+    /// The remote rejected the announce.
+    Rejected,
+    /// The remote sent something the protocol does not allow, e.g. a
+    /// confirmation for a swap that was not announced.
+    ProtocolViolation,
+}
+
+/// Helpers for exercising the upgrades without a swarm, also available to
+/// other crates with the `testing` feature.
+#[cfg(any(test, feature = "testing"))]
+pub mod test_util {
+    use super::{Error, InboundConfig, ProtocolVersion, ReplySubstream};
+    use futures::{executor::block_on, future, io::Cursor, prelude::*};
+    use libp2p::{
+        core::{
+            transport::{ListenerEvent, MemoryTransport},
+            upgrade::InboundUpgrade,
+        },
+        Transport,
+    };
+
+    /// What `run_inbound` reads an announce into.
+    pub type InboundOutput = ReplySubstream<Cursor<Vec<u8>>>;
+
+    /// Runs the inbound upgrade of `ProtocolVersion::V1` on a stream holding
+    /// `bytes`, as if a peer had sent them, e.g. to check how crafted input
+    /// is handled. Replies are written to the stream and lost.
+    pub fn run_inbound(bytes: &[u8]) -> Result<InboundOutput, Error> {
+        let socket = Cursor::new(bytes.to_vec());
+
+        block_on(InboundConfig::default().upgrade_inbound(socket, ProtocolVersion::V1.into()))
+    }
+
+    /// Opens an in-memory connection and returns both ends of it.
+    pub async fn connected_pair() -> (
+        impl AsyncRead + AsyncWrite + Unpin + Send + 'static,
+        impl AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    ) {
+        let mut listener = MemoryTransport
+            .listen_on("/memory/0".parse().unwrap())
+            .unwrap();
+
+        let addr = match listener.next().await {
+            Some(Ok(ListenerEvent::NewAddress(addr))) => addr,
+            _ => panic!("memory listener did not report its address"),
+        };
+
+        let dialer = MemoryTransport.dial(addr).unwrap();
+        let listener = async move {
+            loop {
+                if let Some(Ok(ListenerEvent::Upgrade { upgrade, .. })) = listener.next().await {
+                    return upgrade.await.unwrap();
+                }
+            }
+        };
+
+        let (dialer, listener) = future::join(dialer, listener).await;
+        (dialer.unwrap(), listener)
+    }
+}
+
+/// Canonical bytes of an announce and its confirmation with
+/// `ProtocolVersion::V1`, for other implementations to check theirs against.
+///
+/// The frames are given as they are on the wire under
+/// `/comit/swap/announce/1.1.0`: the unsigned varint length, then
+/// `FRAME_MAGIC` and the JSON encoded message. Under the unframed
+/// `/comit/swap/announce/1.0.0` the magic byte is left out.
+pub mod test_vectors {
+    use super::ProtocolVersion;
+
+    /// The version the frames are encoded with.
+    pub const VERSION: ProtocolVersion = ProtocolVersion::V1;
+
+    /// The swap digest announced is the SHA2-256 of this.
+    pub const SWAP_DIGEST_PREIMAGE: &[u8] = b"hello world";
+
+    /// The hex encoded multihash of the swap digest.
+    pub const SWAP_DIGEST: &str = "1220b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+
+    /// The swap id it is confirmed with.
+    pub const SWAP_ID: &str = "0f8fad5b-d9cb-469f-a165-70867728950e";
+
+    /// The announce of `SWAP_DIGEST`.
+    pub const ANNOUNCE_FRAME: &[u8] =
+        b"\x47\xa5\"1220b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9\"";
+
+    /// The confirmation of the announce with `SWAP_ID`.
+    pub const CONFIRM_FRAME: &[u8] = b"\x27\xa5\"0f8fad5b-d9cb-469f-a165-70867728950e\"";
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        test_util::{connected_pair, run_inbound},
+        *,
+    };
+    use libp2p::multihash::{Blake2b256, Sha2_256};
+
+    /// Collects what is logged for a test to look at.
+    #[derive(Clone, Default)]
+    struct CapturedLogs(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl io::Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn dropping_an_unanswered_reply_substream_is_logged() {
+        let reply = async_std::task::block_on(async {
+            let (alice, bob) = connected_pair().await;
+
+            let outbound = OutboundConfig::new(SwapDigest::new(Sha2_256::digest(b"hello world")))
+                .upgrade_outbound(alice, ProtocolVersion::V1.into());
+            let inbound = InboundConfig::default().upgrade_inbound(bob, ProtocolVersion::V1.into());
+
+            match future::select(outbound.boxed(), inbound).await {
+                future::Either::Right((reply, _)) => reply.unwrap(),
+                future::Either::Left((outbound, _)) => panic!("outbound finished first: {:?}", outbound),
+            }
+        });
+
+        let logs = CapturedLogs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .finish();
+        tracing::subscriber::with_default(subscriber, || drop(reply));
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        assert!(logs.contains("WARN"), "{}", logs);
+        assert!(logs.contains("reply substream dropped without confirming"), "{}", logs);
+    }
+
+    #[test]
+    fn each_error_maps_to_its_code() {
+        let digest = SwapDigest::new(Sha2_256::digest(b"hello world"));
+        let io_error = || io::Error::from(io::ErrorKind::BrokenPipe);
+        let errors = vec![
+            (Error::Read(upgrade::ReadOneError::Io(io_error())), ErrorCode::Io),
+            (Error::Write(io_error()), ErrorCode::Io),
+            (
+                Error::Read(upgrade::ReadOneError::TooLarge { requested: 2, max: 1 }),
+                ErrorCode::FrameTooLarge,
+            ),
+            (Error::FrameTooLarge { size: 2 }, ErrorCode::FrameTooLarge),
+            (Error::Serde(serde_json::from_slice::<u8>(b"").unwrap_err()), ErrorCode::Decode),
+            (Error::Cbor(serde_cbor::from_slice::<u8>(b"").unwrap_err()), ErrorCode::Decode),
+            (Error::BadMagic(0), ErrorCode::Decode),
+            (Error::Rejected(RejectReason::Busy), ErrorCode::Rejected),
+            (Error::Timeout, ErrorCode::Timeout),
+            (Error::ReadTimeout, ErrorCode::Timeout),
+            (Error::ReplyTimeout, ErrorCode::Timeout),
+            (
+                Error::DigestMismatch {
+                    expected: digest.clone(),
+                    actual: digest,
+                },
+                ErrorCode::ProtocolViolation,
+            ),
+            (Error::UnexpectedAnnounceCount(2), ErrorCode::ProtocolViolation),
+            (Error::TooManyAnnounces, ErrorCode::ProtocolViolation),
+            (Error::UnexpectedRequestId(1), ErrorCode::ProtocolViolation),
+            (
+                Error::InvalidConfirmationLength { expected: 2, actual: 1 },
+                ErrorCode::ProtocolViolation,
+            ),
+            (Error::ClosedWithoutConfirmation, ErrorCode::ProtocolViolation),
+            (
+                Error::UnsupportedDigestAlgorithm(multihash::Code::Blake2b256),
+                ErrorCode::UnsupportedAlgorithm,
+            ),
+        ];
+
+        for (error, code) in errors {
+            assert_eq!(error.code(), code, "{:?}", error);
+        }
+    }
+
+    #[test]
+    fn pushed_confirmation_is_read_with_the_digest_it_confirms() {
+        let swap_digest = SwapDigest::new(Sha2_256::digest(b"hello world"));
+        let swap_id = SwapId::default();
+
+        let confirmed = async_std::task::block_on(async {
+            let (bob, alice) = connected_pair().await;
+
+            let push = PushConfirmation::new(swap_digest.clone(), swap_id)
+                .upgrade_outbound(bob, ProtocolId::confirm());
+            let listen = ConfirmListen::<SwapId>::default().upgrade_inbound(alice, ProtocolId::confirm());
+
+            let (pushed, confirmed) = future::join(push, listen).await;
+            pushed.unwrap();
+            confirmed.unwrap()
+        });
+
+        assert_eq!(confirmed.swap_digest(), &swap_digest);
+        assert_eq!(confirmed.swap_id(), swap_id);
+        assert_eq!(ProtocolId::confirm().protocol_name(), CONFIRM_PROTOCOL.as_bytes());
+    }
+
+    #[test]
+    fn outbound_upgrade_times_out_without_confirmation() {
+        async_std::task::block_on(async {
+            let (alice, bob) = connected_pair().await;
+
+            let mut config = OutboundConfig::new(SwapDigest::new(Sha2_256::digest(b"hello world")));
+            config.timeout = Duration::from_millis(50);
+
+            let outbound = config.upgrade_outbound(alice, ProtocolVersion::V1.into());
+            let inbound = InboundConfig::default().upgrade_inbound(bob, ProtocolVersion::V1.into());
+
+            // Bob reads the announcement but never confirms it.
+            let (outbound, reply) = future::join(outbound, inbound).await;
+            let _reply = reply.unwrap();
+
+            match outbound {
+                Err(Error::Timeout) => {}
+                other => panic!("expected a timeout, got {:?}", other),
+            }
+        })
+    }
+
+    #[test]
+    fn inbound_upgrade_rejects_non_sha2_256_digest() {
+        async_std::task::block_on(async {
+            let (mut alice, bob) = connected_pair().await;
+
+            let digest = SwapDigest::new(Blake2b256::digest(b"hello world"));
+            let bytes = encode_frame(Codec::Json, true, &digest).unwrap();
+            upgrade::write_one(&mut alice, &bytes).await.unwrap();
+
+            let result = InboundConfig::default()
+                .upgrade_inbound(bob, ProtocolVersion::V1.into())
+                .await;
+
+            match result {
+                Err(Error::UnsupportedDigestAlgorithm(multihash::Code::Blake2b256)) => {}
+                Err(e) => panic!("expected an unsupported algorithm error, got {:?}", e),
+                Ok(_) => panic!("inbound upgrade accepted a Blake2b digest"),
+            }
+        })
+    }
+
+    #[test]
+    fn confirm_delivers_swap_id_to_outbound_side() {
+        async_std::task::block_on(async {
+            let (alice, bob) = connected_pair().await;
+
+            let swap_digest = SwapDigest::new(Sha2_256::digest(b"hello world"));
+            let swap_id = SwapId::default();
+
+            let outbound = OutboundConfig::new(swap_digest.clone()).upgrade_outbound(alice, ProtocolVersion::V1.into());
+            let inbound = async {
+                let reply = InboundConfig::default()
+                    .upgrade_inbound(bob, ProtocolVersion::V1.into())
+                    .await
+                    .unwrap();
+                reply.confirm(swap_id).await
+            };
+
+            let (confirmed, sent) = future::join(outbound, inbound).await;
+            sent.unwrap();
+            let confirmed = confirmed.unwrap();
+
+            assert_eq!(confirmed.swap_digest(), &swap_digest);
+            assert_eq!(confirmed.swap_id(), swap_id);
+        })
+    }
+
+    #[test]
+    fn confirmation_of_a_different_digest_is_rejected() {
+        async_std::task::block_on(async {
+            let (alice, bob) = connected_pair().await;
+
+            let announced = SwapDigest::new(Sha2_256::digest(b"hello world"));
+            let other = SwapDigest::new(Sha2_256::digest(b"goodbye world"));
+
+            let outbound = OutboundConfig::new(announced.clone()).upgrade_outbound(alice, ProtocolVersion::V2.into());
+            let inbound = async {
+                let mut reply = InboundConfig::default()
+                    .upgrade_inbound(bob, ProtocolVersion::V2.into())
+                    .await
+                    .unwrap();
+                reply.swap_digest = other.clone();
+                reply.confirm(SwapId::random()).await
+            };
+
+            let (confirmed, sent) = future::join(outbound, inbound).await;
+            sent.unwrap();
+
+            match confirmed {
+                Err(Error::DigestMismatch { expected, actual }) => {
+                    assert_eq!(expected, announced);
+                    assert_eq!(actual, other);
+                }
+                other => panic!("expected a digest mismatch, got {:?}", other),
+            }
+        })
+    }
+
+    #[test]
+    fn empty_inbound_stream_is_not_an_announce() {
+        // Reads as an empty frame, which holds no swap digest.
+        let error = run_inbound(&[]).unwrap_err();
+
+        assert!(matches!(error, Error::Serde(_)), "{:?}", error);
+    }
+
+    #[test]
+    fn truncated_length_prefix_fails_to_read() {
+        // The high bit says more bytes of the varint follow, none do.
+        let error = run_inbound(&[0x80]).unwrap_err();
+
+        assert!(matches!(error, Error::Read(_)), "{:?}", error);
+    }
+
+    #[test]
+    fn valid_inbound_frame_is_read() {
+        let swap_digest = SwapDigest::new(Sha2_256::digest(b"hello world"));
+        let frame = encode_frame(Codec::Json, true, &swap_digest).unwrap();
+        let mut bytes = Vec::new();
+        bytes.push(frame.len() as u8);
+        bytes.extend(frame);
+
+        let reply = run_inbound(&bytes).unwrap();
+
+        assert_eq!(reply.swap_digest, swap_digest);
+        reply.drop_unanswered();
+    }
+
+    #[test]
+    fn substream_closed_without_confirmation_fails_the_announce() {
+        async_std::task::block_on(async {
+            let (alice, mut bob) = connected_pair().await;
+            let swap_digest = SwapDigest::new(Sha2_256::digest(b"hello world"));
+
+            let outbound = OutboundConfig::new(swap_digest).upgrade_outbound(alice, ProtocolVersion::V1.into());
+            let inbound = async {
+                upgrade::read_one(&mut bob, MAX_FRAME_SIZE).await.unwrap();
+                drop(bob);
+            };
+
+            let (confirmed, ()) = future::join(outbound, inbound).await;
+
+            assert!(
+                matches!(confirmed, Err(Error::ClosedWithoutConfirmation)),
+                "{:?}",
+                confirmed
+            );
+        })
+    }
+
+    #[test]
+    fn large_confirmation_roundtrips_intact() {
+        async_std::task::block_on(async {
+            let (alice, bob) = connected_pair().await;
+            let swap_digest = SwapDigest::new(Sha2_256::digest(b"hello world"));
+            // E.g. a signed blob, far larger than an announce may be.
+            let blob = (0..4 * MAX_FRAME_SIZE).map(|i| i as u8).collect::<Vec<u8>>();
+
+            let outbound = OutboundConfig::new(swap_digest).announce::<_, Vec<u8>>(alice, ProtocolVersion::V2.into());
+            let inbound = async {
+                let mut replies = InboundConfig::default()
+                    .read_all::<_, Vec<u8>>(bob, ProtocolVersion::V2.into())
+                    .await
+                    .unwrap();
+                replies.remove(0).confirm(blob.clone()).await.unwrap();
+            };
+
+            let (confirmed, ()) = future::join(outbound, inbound).await;
+            let confirmed = confirmed.unwrap();
+
+            assert_eq!(confirmed.swap_id(), blob);
+            assert!(confirmed.raw().len() > MAX_FRAME_SIZE);
+            let raw = ProtocolVersion::V2
+                .codec()
+                .decode::<V2Confirmation<Vec<u8>>>(confirmed.raw())
+                .unwrap();
+            assert_eq!(raw.swap_id, blob);
+        })
+    }
+
+    #[test]
+    fn test_vectors_are_what_the_inputs_encode_to() {
+        use test_vectors::*;
+
+        let swap_digest = SwapDigest::new(Sha2_256::digest(SWAP_DIGEST_PREIMAGE));
+        assert_eq!(swap_digest.to_string(), SWAP_DIGEST);
+        let swap_id = SWAP_ID.parse::<SwapId>().unwrap();
+
+        let frame = |message: Vec<u8>| {
+            async_std::task::block_on(async {
+                let mut bytes = futures::io::Cursor::new(Vec::new());
+                upgrade::write_with_len_prefix(&mut bytes, message).await.unwrap();
+                bytes.into_inner()
+            })
+        };
+        assert_eq!(frame(encode_frame(VERSION.codec(), true, &swap_digest).unwrap()), ANNOUNCE_FRAME);
+        assert_eq!(frame(encode_frame(VERSION.codec(), true, &swap_id).unwrap()), CONFIRM_FRAME);
+
+        // And the frames read back as what they were made of.
+        assert_eq!(run_inbound(ANNOUNCE_FRAME).unwrap().swap_digest, swap_digest);
+        assert_eq!(decode_confirmation::<SwapId>(VERSION.codec(), &CONFIRM_FRAME[2..]).unwrap(), swap_id);
+    }
+
+    #[test]
+    fn short_confirmation_is_rejected() {
+        async_std::task::block_on(async {
+            let (alice, mut bob) = connected_pair().await;
+            let swap_digest = SwapDigest::new(Sha2_256::digest(b"hello world"));
+
+            let outbound = OutboundConfig::new(swap_digest).upgrade_outbound(alice, ProtocolVersion::V1.into());
+            let inbound = async {
+                upgrade::read_one(&mut bob, MAX_FRAME_SIZE).await.unwrap();
+
+                // A swap id cut short.
+                let mut frame = vec![FRAME_MAGIC];
+                frame.extend(&serde_json::to_vec(&SwapId::random()).unwrap()[..37]);
+                upgrade::write_one(&mut bob, frame).await.unwrap();
+            };
+
+            let (confirmed, ()) = future::join(outbound, inbound).await;
+
+            match confirmed {
+                Err(Error::InvalidConfirmationLength { expected, actual }) => {
+                    assert_eq!(expected, 38);
+                    assert_eq!(actual, 37);
+                }
+                other => panic!("expected an invalid confirmation length, got {:?}", other),
+            }
+        })
+    }
+
+    #[test]
+    fn swap_id_in_any_form_of_uuid_size_is_accepted() {
+        let swap_id = SwapId::random();
+        let simple = serde_json::to_vec(&swap_id.0.to_simple().to_string()).unwrap();
+        let hyphenated = serde_json::to_vec(&swap_id).unwrap();
+        let cbor = Codec::Cbor.encode(&swap_id).unwrap();
+
+        assert_eq!(decode_confirmation::<SwapId>(Codec::Json, &simple).unwrap(), swap_id);
+        assert_eq!(decode_confirmation::<SwapId>(Codec::Json, &hyphenated).unwrap(), swap_id);
+        assert_eq!(decode_confirmation::<SwapId>(Codec::Cbor, &cbor).unwrap(), swap_id);
+
+        // A frame cut short is reported against the form it is closest to,
+        // CBOR has no simple form.
+        assert_eq!(SwapId::validate_encoded_len(Codec::Json, simple.len() - 1), Err(simple.len()));
+        assert_eq!(SwapId::validate_encoded_len(Codec::Json, hyphenated.len() - 1), Err(hyphenated.len()));
+        assert_eq!(SwapId::validate_encoded_len(Codec::Cbor, simple.len()), Err(cbor.len()));
+
+        // The simple form with four bytes after it is as long as the
+        // hyphenated one, but has more than the UUID on it.
+        let mut trailing = simple;
+        trailing.extend(b"1234");
+        assert!(matches!(
+            decode_confirmation::<SwapId>(Codec::Json, &trailing),
+            Err(Error::Serde(_))
+        ));
+    }
+
+    #[test]
+    fn cbor_announce_is_confirmed() {
+        async_std::task::block_on(async {
+            let (alice, bob) = connected_pair().await;
+
+            let swap_digest = SwapDigest::new(Sha2_256::digest(b"hello world"));
+            let swap_id = SwapId::default();
+
+            let outbound = OutboundConfig::new(swap_digest.clone()).upgrade_outbound(alice, ProtocolVersion::Cbor.into());
+            let inbound = async {
+                let reply = InboundConfig::default()
+                    .upgrade_inbound(bob, ProtocolVersion::Cbor.into())
+                    .await
+                    .unwrap();
+                assert_eq!(reply.swap_digest, swap_digest);
+                reply.confirm(swap_id).await
+            };
+
+            let (confirmed, sent) = future::join(outbound, inbound).await;
+            sent.unwrap();
+            let confirmed = confirmed.unwrap();
+
+            assert_eq!(confirmed.swap_digest(), &swap_digest);
+            assert_eq!(confirmed.swap_id(), swap_id);
+            assert_eq!(confirmed.version(), ProtocolVersion::Cbor);
+        })
+    }
+
+    #[test]
+    fn messages_roundtrip_through_both_codecs() {
+        let swap_digest = SwapDigest::new(Sha2_256::digest(b"hello world"));
+        let swap_id = SwapId::default();
+
+        for codec in [Codec::Json, Codec::Cbor] {
+            let bytes = codec.encode(&swap_digest).unwrap();
+            assert_eq!(codec.decode::<SwapDigest>(&bytes).unwrap(), swap_digest);
+
+            let bytes = codec.encode(&swap_id).unwrap();
+            assert_eq!(codec.decode::<SwapId>(&bytes).unwrap(), swap_id);
+        }
+
+        // CBOR carries the digest as raw bytes rather than as a hex string.
+        let json = Codec::Json.encode(&swap_digest).unwrap();
+        let cbor = Codec::Cbor.encode(&swap_digest).unwrap();
+        assert!(cbor.len() < json.len());
+    }
+
+    #[test]
+    fn inbound_upgrade_rejects_oversized_frame() {
+        async_std::task::block_on(async {
+            let (mut alice, bob) = connected_pair().await;
+
+            // Only the length prefix is sent, reading the frame itself would
+            // never complete.
+            upgrade::write_varint(&mut alice, 1_000_000).await.unwrap();
+
+            let result = InboundConfig::default()
+                .upgrade_inbound(bob, ProtocolVersion::V1.into())
+                .await;
+
+            match result {
+                Err(Error::FrameTooLarge { size: 1_000_000 }) => {}
+                Err(e) => panic!("expected a frame too large error, got {:?}", e),
+                Ok(_) => panic!("inbound upgrade accepted an oversized frame"),
+            }
+        })
+    }
+
+    async fn negotiate(outbound: OutboundConfig, inbound: InboundConfig) -> (ProtocolVersion, ProtocolVersion) {
+        let (alice, bob) = connected_pair().await;
+
+        let outbound = upgrade::apply_outbound(alice, outbound, upgrade::Version::V1);
+        let inbound = async {
+            let reply = upgrade::apply_inbound(bob, inbound).await.unwrap();
+            let version = reply.version;
+            reply.confirm(SwapId::default()).await.unwrap();
+            version
+        };
+
+        let (confirmed, inbound_version) = future::join(outbound, inbound).await;
+        (confirmed.unwrap().version(), inbound_version)
+    }
+
+    #[test]
+    fn negotiates_v1_with_v1_only_peer() {
+        async_std::task::block_on(async {
+            let outbound = OutboundConfig::new(SwapDigest::new(Sha2_256::digest(b"hello world")));
+            let inbound = InboundConfig {
+                versions: vec![ProtocolVersion::V1],
+                ..InboundConfig::default()
+            };
+
+            let versions = negotiate(outbound, inbound).await;
+
+            assert_eq!(versions, (ProtocolVersion::V1, ProtocolVersion::V1));
+        })
+    }
+
+    #[test]
+    fn negotiates_v2_with_v2_capable_peer() {
+        async_std::task::block_on(async {
+            let outbound = OutboundConfig::new(SwapDigest::new(Sha2_256::digest(b"hello world")));
+
+            let versions = negotiate(outbound, InboundConfig::default()).await;
+
+            assert_eq!(versions, (ProtocolVersion::V2, ProtocolVersion::V2));
+        })
+    }
+
+    #[test]
+    fn frame_with_the_magic_byte_is_decoded() {
+        let swap_digest = SwapDigest::new(Sha2_256::digest(b"hello world"));
+        let frame = encode_frame(Codec::Json, true, &swap_digest).unwrap();
+        assert_eq!(frame[0], FRAME_MAGIC);
+
+        let mut bytes = vec![frame.len() as u8];
+        bytes.extend(frame);
+
+        let reply = async_std::task::block_on(
+            InboundConfig::default()
+                .upgrade_inbound(futures::io::Cursor::new(bytes), ProtocolVersion::V1.into()),
+        )
+        .unwrap();
+        assert_eq!(reply.swap_digest, swap_digest);
+    }
+
+    #[test]
+    fn frame_with_a_corrupted_magic_byte_is_rejected() {
+        let swap_digest = SwapDigest::new(Sha2_256::digest(b"hello world"));
+        let mut frame = encode_frame(Codec::Json, true, &swap_digest).unwrap();
+        frame[0] ^= 0xff;
+
+        let mut bytes = vec![frame.len() as u8];
+        bytes.extend(frame);
+
+        let result = async_std::task::block_on(
+            InboundConfig::default()
+                .upgrade_inbound(futures::io::Cursor::new(bytes), ProtocolVersion::V1.into()),
+        );
+        match result {
+            Err(Error::BadMagic(magic)) => assert_eq!(magic, FRAME_MAGIC ^ 0xff),
+            Err(e) => panic!("expected a bad magic error, got {:?}", e),
+            Ok(_) => panic!("inbound upgrade accepted a corrupted frame"),
+        }
+    }
+
+    #[test]
+    fn frame_without_the_magic_byte_is_decoded_and_replied_to_on_the_unframed_id() {
+        async_std::task::block_on(async {
+            let (mut alice, bob) = connected_pair().await;
+            let swap_digest = SwapDigest::new(Sha2_256::digest(b"hello world"));
+            let swap_id = SwapId::default();
+
+            // What a peer from before the magic byte sends, e.g. `"1220…"`.
+            let announce = Codec::Json.encode(&swap_digest).unwrap();
+            upgrade::write_one(&mut alice, &announce).await.unwrap();
+
+            let info = ProtocolId::unframed(DEFAULT_PROTOCOL_PREFIX, ProtocolVersion::V1);
+            let reply = InboundConfig::default().upgrade_inbound(bob, info).await.unwrap();
+            assert_eq!(reply.swap_digest, swap_digest);
+            reply.confirm(swap_id).await.unwrap();
+
+            let confirmation = upgrade::read_one(&mut alice, MAX_FRAME_SIZE).await.unwrap();
+            assert_eq!(confirmation, Codec::Json.encode(&swap_id).unwrap());
+        })
+    }
+
+    #[test]
+    fn inbound_upgrade_times_out_on_a_stalled_frame() {
+        async_std::task::block_on(async {
+            let (mut alice, bob) = connected_pair().await;
+
+            // Promise a frame, then never send it.
+            alice.write_all(&[32]).await.unwrap();
+            alice.flush().await.unwrap();
+
+            let inbound = InboundConfig {
+                read_timeout: Duration::from_millis(50),
+                ..InboundConfig::default()
+            };
+            let result = inbound.upgrade_inbound(bob, ProtocolVersion::V1.into()).await;
+
+            match result {
+                Err(Error::ReadTimeout) => {}
+                Err(e) => panic!("expected a read timeout, got {:?}", e),
+                Ok(_) => panic!("inbound upgrade accepted a stalled frame"),
+            }
+            drop(alice);
+        })
+    }
 }
@@ -1,2 +1,4 @@
 pub mod announce;
-pub mod swap_id;
\ No newline at end of file
+pub mod swap_id;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
@@ -2,12 +2,27 @@ use serde::{Deserialize, Serialize};
 use std::{fmt, str::FromStr};
 use uuid::Uuid;
 
+/// Identifier of a swap, as returned in the confirmation of an announce.
+///
+/// Text formats such as JSON carry it as the hyphenated UUID string, binary
+/// formats such as CBOR as the 16 bytes of the UUID.
 #[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub struct SwapId(pub Uuid);
 
+impl SwapId {
+    pub fn new(uuid: Uuid) -> Self {
+        SwapId(uuid)
+    }
+
+    /// A new, random (v4) swap id.
+    pub fn random() -> Self {
+        SwapId(Uuid::new_v4())
+    }
+}
+
 impl Default for SwapId {
     fn default() -> Self {
-        SwapId(Uuid::new_v4())
+        SwapId::random()
     }
 }
 
@@ -72,3 +87,33 @@ impl fmt::Display for NodeLocalSwapId {
         self.0.fmt(f)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn swap_id_roundtrips_through_serde() {
+        let swap_id = SwapId::random();
+
+        let json = serde_json::to_string(&swap_id).unwrap();
+        assert_eq!(json, format!("\"{}\"", swap_id));
+
+        let deserialized = serde_json::from_str::<SwapId>(&json).unwrap();
+        assert_eq!(deserialized, swap_id);
+        assert_eq!(swap_id.to_string().parse::<SwapId>().unwrap(), swap_id);
+    }
+
+    #[test]
+    fn swap_id_roundtrips_through_cbor_as_bytes() {
+        let swap_id = SwapId::random();
+
+        let cbor = serde_cbor::to_vec(&swap_id).unwrap();
+        // A byte string of length 16, then the UUID.
+        assert_eq!(cbor[0], 0x50);
+        assert_eq!(&cbor[1..], swap_id.0.as_bytes());
+
+        let deserialized = serde_cbor::from_slice::<SwapId>(&cbor).unwrap();
+        assert_eq!(deserialized, swap_id);
+    }
+}
@@ -0,0 +1,134 @@
+//! Helpers for spinning up announce nodes in tests.
+
+use crate::announce::behaviour::{Announce, AnnounceConfig};
+use libp2p::{
+    core::{
+        muxing::{StreamMuxer, StreamMuxerBox},
+        transport::boxed::Boxed,
+        upgrade,
+    },
+    identity,
+    mplex::MplexConfig,
+    noise::{Keypair, NoiseConfig, X25519},
+    secio::SecioConfig,
+    swarm::Swarm,
+    tcp::TcpConfig,
+    websocket::WsConfig,
+    yamux,
+    PeerId, Transport,
+};
+use std::{error, io};
+
+/// The transports built here, whatever they are made of.
+pub type BoxedTransport = Boxed<(PeerId, StreamMuxerBox), io::Error>;
+
+/// The TCP transport, authenticated with secio and multiplexed with mplex,
+/// that the nodes built here use. Returns it with the id of a freshly
+/// generated identity.
+pub fn transport() -> (PeerId, BoxedTransport) {
+    let id_keys = identity::Keypair::generate_ed25519();
+    let peer_id = id_keys.public().into_peer_id();
+    let transport = TcpConfig::new()
+        .nodelay(true)
+        .upgrade(upgrade::Version::V1)
+        .authenticate(SecioConfig::new(id_keys))
+        .multiplex(MplexConfig::new());
+    (peer_id, boxed(transport))
+}
+
+/// Like `transport`, but WebSocket over TCP, the transport browsers can
+/// speak. Nodes listen and dial on `/ip4/.../tcp/.../ws` addresses.
+pub fn ws_transport() -> (PeerId, BoxedTransport) {
+    let id_keys = identity::Keypair::generate_ed25519();
+    let peer_id = id_keys.public().into_peer_id();
+    let transport = WsConfig::new(TcpConfig::new().nodelay(true))
+        .upgrade(upgrade::Version::V1)
+        .authenticate(SecioConfig::new(id_keys))
+        .multiplex(MplexConfig::new());
+    (peer_id, boxed(transport))
+}
+
+/// Like `transport`, but authenticated with the XX handshake of noise
+/// instead of the deprecated secio.
+pub fn noise_transport() -> (PeerId, BoxedTransport) {
+    let id_keys = identity::Keypair::generate_ed25519();
+    let peer_id = id_keys.public().into_peer_id();
+    let dh_keys = Keypair::<X25519>::new()
+        .into_authentic(&id_keys)
+        .expect("an ed25519 identity can sign noise keys");
+    let transport = TcpConfig::new()
+        .nodelay(true)
+        .upgrade(upgrade::Version::V1)
+        .authenticate(NoiseConfig::xx(dh_keys).into_authenticated())
+        .multiplex(MplexConfig::new());
+    (peer_id, boxed(transport))
+}
+
+/// Like `transport`, but multiplexed with yamux instead of mplex.
+pub fn yamux_transport() -> (PeerId, BoxedTransport) {
+    let id_keys = identity::Keypair::generate_ed25519();
+    let peer_id = id_keys.public().into_peer_id();
+    let transport = TcpConfig::new()
+        .nodelay(true)
+        .upgrade(upgrade::Version::V1)
+        .authenticate(SecioConfig::new(id_keys))
+        .multiplex(yamux::Config::default());
+    (peer_id, boxed(transport))
+}
+
+/// Builds a swarm running `Announce` with the default configuration.
+pub fn build_announce_swarm() -> (PeerId, Swarm<Announce>) {
+    build_announce_swarm_with(AnnounceConfig::default())
+}
+
+/// Builds a swarm running `Announce` with the given configuration.
+pub fn build_announce_swarm_with(config: AnnounceConfig) -> (PeerId, Swarm<Announce>) {
+    build_swarm_with(transport(), config)
+}
+
+/// Builds a swarm running `Announce` with the default configuration over
+/// `ws_transport`.
+pub fn build_ws_announce_swarm() -> (PeerId, Swarm<Announce>) {
+    build_swarm_with(ws_transport(), AnnounceConfig::default())
+}
+
+/// Builds a swarm running `Announce` with the default configuration over
+/// `noise_transport`.
+pub fn build_announce_swarm_noise() -> (PeerId, Swarm<Announce>) {
+    build_swarm_with(noise_transport(), AnnounceConfig::default())
+}
+
+/// Builds a swarm running `Announce` with the default configuration over
+/// `yamux_transport`.
+pub fn build_announce_swarm_yamux() -> (PeerId, Swarm<Announce>) {
+    build_swarm_with(yamux_transport(), AnnounceConfig::default())
+}
+
+/// Builds a swarm running `Announce` with the given configuration over one
+/// of the transports built here, e.g. `build_swarm_with(noise_transport(),
+/// config)`.
+pub fn build_swarm_with(
+    (peer_id, transport): (PeerId, BoxedTransport),
+    config: AnnounceConfig,
+) -> (PeerId, Swarm<Announce>) {
+    let swarm = Swarm::new(transport, Announce::new(config), peer_id.clone());
+    (peer_id, swarm)
+}
+
+/// Boxes an upgraded transport, so the transports built here share a type.
+fn boxed<T, M>(transport: T) -> BoxedTransport
+where
+    T: Transport<Output = (PeerId, M)> + Clone + Send + Sync + 'static,
+    T::Error: error::Error + Send + Sync + 'static,
+    T::Listener: Send + 'static,
+    T::ListenerUpgrade: Send + 'static,
+    T::Dial: Send + 'static,
+    M: StreamMuxer + Send + Sync + 'static,
+    M::Substream: Send + 'static,
+    M::OutboundSubstream: Send + 'static,
+{
+    transport
+        .map(|(peer_id, muxer), _| (peer_id, StreamMuxerBox::new(muxer)))
+        .map_err(io::Error::other)
+        .boxed()
+}